@@ -1,10 +1,19 @@
-use crate::config::{EditorConfig, ModelConfig, Provider};
-use reqwest::blocking::Client;
+use crate::config::{AiConfig, EditorConfig, ModelConfig, Provider};
+use crate::logfile;
+use reqwest::blocking::{Client, RequestBuilder, Response};
 use serde::{Deserialize, Serialize};
 use std::env;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::time::{Duration, SystemTime};
+use std::io::{BufRead, BufReader, Write};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Messages sent from the AI worker thread back to the editor.
+pub enum AiEvent {
+    /// A piece of the response as it streams in.
+    Chunk(String),
+    /// The complete response, or the error that ended the request.
+    Done(Result<String, String>),
+}
 
 #[derive(Serialize)]
 struct AnythingLLMRequest {
@@ -14,7 +23,16 @@ struct AnythingLLMRequest {
 
 #[derive(Deserialize, Serialize)]
 struct AnythingLLMResponse {
-    textResponse: String,
+    #[serde(rename = "textResponse")]
+    text_response: String,
+}
+
+#[derive(Serialize)]
+struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
 }
 
 #[derive(Serialize)]
@@ -22,6 +40,8 @@ struct OllamaRequest {
     model: String,
     prompt: String,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<OllamaOptions>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -39,6 +59,11 @@ struct OpenAIMessage {
 struct OpenAIRequest {
     model: String,
     messages: Vec<OpenAIMessage>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -51,20 +76,73 @@ struct OpenAIResponse {
     choices: Vec<OpenAIChoice>,
 }
 
-pub fn send_prompt(
-    config: &EditorConfig,
-    user_prompt: &str,
-    text: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let system_prompt = "Modify the following text according to the user's request. Return only the modified text, no explanations or additional content.";
-    send_prompt_with_system(config, Some(system_prompt), user_prompt, text)
+#[derive(Deserialize)]
+struct OpenAIDelta {
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OpenAIStreamChoice {
+    delta: OpenAIDelta,
 }
 
+#[derive(Deserialize)]
+struct OpenAIStreamChunk {
+    choices: Vec<OpenAIStreamChoice>,
+}
+
+/// Per-model `max_tokens`, falling back to the `[ai]` default.
+fn max_tokens(ai: &AiConfig, model: &ModelConfig) -> Option<usize> {
+    model.max_tokens.or(ai.max_tokens_default)
+}
+
+/// Per-model `temperature`, falling back to the `[ai]` default.
+fn temperature(ai: &AiConfig, model: &ModelConfig) -> Option<f32> {
+    model.temperature.or(ai.temperature_default)
+}
+
+/// Sends the request produced by `build`, retrying connection failures,
+/// timeouts and 5xx responses with exponential backoff. Other errors fail
+/// straight away.
+fn send_with_retry(
+    ai: &AiConfig,
+    build: impl Fn() -> RequestBuilder,
+) -> Result<Response, Box<dyn std::error::Error>> {
+    let max_retries = ai.max_retries.unwrap_or(2);
+    let mut delay = Duration::from_millis(500);
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let error = match build().send() {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) if response.status().is_server_error() => {
+                format!("API error: {}", response.status())
+            }
+            Ok(response) => return Err(format!("API error: {}", response.status()).into()),
+            Err(e) if e.is_connect() || e.is_timeout() => e.to_string(),
+            Err(e) => return Err(e.into()),
+        };
+        if attempts > max_retries {
+            return Err(format!("{} (after {} attempts)", error, attempts).into());
+        }
+        thread::sleep(delay);
+        delay *= 2;
+    }
+}
+
+/// Whether responses from `model` should be streamed (on unless disabled).
+fn stream(model: &ModelConfig) -> bool {
+    model.stream.unwrap_or(true)
+}
+
+/// Sends a prompt to the active model and returns the full response.
+/// Providers that stream report each piece to `on_chunk` as it arrives.
 pub fn send_prompt_with_system(
     config: &EditorConfig,
     system_prompt: Option<&str>,
     user_prompt: &str,
     text: &str,
+    on_chunk: &mut dyn FnMut(&str),
 ) -> Result<String, Box<dyn std::error::Error>> {
     let ai = config.ai.as_ref().ok_or("No AI config")?;
     let default_id = ai.default_model.as_ref().ok_or("No default model")?;
@@ -74,10 +152,10 @@ pub fn send_prompt_with_system(
         Provider::AnythingLLM => {
             send_prompt_to_anythingllm(config, model, system_prompt, user_prompt, text)
         }
-        Provider::Ollama => send_prompt_to_ollama(config, model, system_prompt, user_prompt, text),
-        Provider::OpenAI => send_prompt_to_openai(config, model, system_prompt, user_prompt, text),
-        Provider::OpenAICompatible => send_prompt_to_openai(config, model, system_prompt, user_prompt, text),
-        Provider::LmStudio => send_prompt_to_openai(config, model, system_prompt, user_prompt, text),
+        Provider::Ollama => send_prompt_to_ollama(config, model, system_prompt, user_prompt, text, on_chunk),
+        Provider::OpenAI => send_prompt_to_openai(config, model, system_prompt, user_prompt, text, on_chunk),
+        Provider::OpenAICompatible => send_prompt_to_openai(config, model, system_prompt, user_prompt, text, on_chunk),
+        Provider::LmStudio => send_prompt_to_openai(config, model, system_prompt, user_prompt, text, on_chunk),
         Provider::Gemini => send_prompt_to_gemini(config, model, system_prompt, user_prompt, text),
     }
 }
@@ -102,11 +180,9 @@ fn send_prompt_to_anythingllm(
         mode: "chat".to_string(),
     };
 
-    let request_json = serde_json::to_string(&request)?;
-
     let timeout = model.timeout_ms
         .or(ai.timeout_ms_default)
-        .map(|ms| Duration::from_millis(ms))
+        .map(Duration::from_millis)
         .unwrap_or(Duration::from_secs(30));
 
     let client = Client::builder()
@@ -126,22 +202,13 @@ fn send_prompt_to_anythingllm(
     headers.insert("Authorization", auth_value.parse()?);
     headers.insert("Content-Type", "application/json".parse()?);
 
-    let response = client
-        .post(&model.endpoint)
-        .headers(headers)
-        .json(&request)
-        .send()?;
-
-    if !response.status().is_success() {
-        return Err(format!("API error: {}", response.status()).into());
-    }
+    let started = Instant::now();
+    let response = send_with_retry(ai, || client.post(&model.endpoint).headers(headers.clone()).json(&request))?;
 
     let anything_response: AnythingLLMResponse = response.json()?;
-    let response_json = serde_json::to_string(&anything_response)?;
-
-    log_interaction(&request_json, &response_json)?;
+    log_interaction(ai, model, &request, &anything_response, started.elapsed())?;
 
-    Ok(anything_response.textResponse)
+    Ok(anything_response.text_response)
 }
 
 fn send_prompt_to_ollama(
@@ -150,6 +217,7 @@ fn send_prompt_to_ollama(
     system_prompt: Option<&str>,
     user_prompt: &str,
     text: &str,
+    on_chunk: &mut dyn FnMut(&str),
 ) -> Result<String, Box<dyn std::error::Error>> {
     let ai = config.ai.as_ref().ok_or("No AI config")?;
     let system_msg = system_prompt.unwrap_or("Modify the following text according to the user's request. Return only the modified text, no explanations or additional content.");
@@ -159,36 +227,50 @@ fn send_prompt_to_ollama(
         format!("{}\n\n{}", system_msg, user_prompt)
     };
 
+    let num_predict = max_tokens(ai, model);
+    let temperature = temperature(ai, model);
+    let options = if num_predict.is_some() || temperature.is_some() {
+        Some(OllamaOptions { num_predict, temperature })
+    } else {
+        None
+    };
+
     let request = OllamaRequest {
         model: model.model.clone(),
         prompt: full_message,
-        stream: false,
+        stream: stream(model),
+        options,
     };
 
-    let request_json = serde_json::to_string(&request)?;
-
     let timeout = model.timeout_ms
         .or(ai.timeout_ms_default)
-        .map(|ms| Duration::from_millis(ms))
+        .map(Duration::from_millis)
         .unwrap_or(Duration::from_secs(30));
 
     let client = Client::builder()
         .timeout(timeout)
         .build()?;
 
-    let response = client
-        .post(&model.endpoint)
-        .json(&request)
-        .send()?;
-
-    if !response.status().is_success() {
-        return Err(format!("API error: {}", response.status()).into());
-    }
-
-    let ollama_response: OllamaResponse = response.json()?;
-    let response_json = serde_json::to_string(&ollama_response)?;
-
-    log_interaction(&request_json, &response_json)?;
+    let started = Instant::now();
+    let response = send_with_retry(ai, || client.post(&model.endpoint).json(&request))?;
+
+    let ollama_response = if request.stream {
+        // One JSON object per line, each carrying the next piece of text
+        let mut full = String::new();
+        for line in BufReader::new(response).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let chunk: OllamaResponse = serde_json::from_str(&line)?;
+            on_chunk(&chunk.response);
+            full.push_str(&chunk.response);
+        }
+        OllamaResponse { response: full }
+    } else {
+        response.json()?
+    };
+    log_interaction(ai, model, &request, &ollama_response, started.elapsed())?;
 
     Ok(ollama_response.response)
 }
@@ -199,6 +281,7 @@ fn send_prompt_to_openai(
     system_prompt: Option<&str>,
     user_prompt: &str,
     text: &str,
+    on_chunk: &mut dyn FnMut(&str),
 ) -> Result<String, Box<dyn std::error::Error>> {
     let ai = config.ai.as_ref().ok_or("No AI config")?;
     let system_msg = system_prompt.unwrap_or("Modify the following text according to the user's request. Return only the modified text, no explanations or additional content.");
@@ -222,13 +305,14 @@ fn send_prompt_to_openai(
     let request = OpenAIRequest {
         model: model.model.clone(),
         messages,
+        stream: stream(model),
+        max_tokens: max_tokens(ai, model),
+        temperature: temperature(ai, model),
     };
 
-    let request_json = serde_json::to_string(&request)?;
-
     let timeout = model.timeout_ms
         .or(ai.timeout_ms_default)
-        .map(|ms| Duration::from_millis(ms))
+        .map(Duration::from_millis)
         .unwrap_or(Duration::from_secs(30));
 
     let client = Client::builder()
@@ -248,20 +332,39 @@ fn send_prompt_to_openai(
     headers.insert("Authorization", auth_value.parse()?);
     headers.insert("Content-Type", "application/json".parse()?);
 
-    let response = client
-        .post(&model.endpoint)
-        .headers(headers)
-        .json(&request)
-        .send()?;
-
-    if !response.status().is_success() {
-        return Err(format!("API error: {}", response.status()).into());
-    }
-
-    let openai_response: OpenAIResponse = response.json()?;
-    let response_json = serde_json::to_string(&openai_response)?;
-
-    log_interaction(&request_json, &response_json)?;
+    let started = Instant::now();
+    let response = send_with_retry(ai, || client.post(&model.endpoint).headers(headers.clone()).json(&request))?;
+
+    let openai_response = if request.stream {
+        // Server-sent events: `data: {...}` lines ending with `data: [DONE]`
+        let mut full = String::new();
+        for line in BufReader::new(response).lines() {
+            let line = line?;
+            let data = match line.strip_prefix("data:") {
+                Some(data) => data.trim(),
+                None => continue,
+            };
+            if data == "[DONE]" {
+                break;
+            }
+            let chunk: OpenAIStreamChunk = serde_json::from_str(data)?;
+            if let Some(content) = chunk.choices.first().and_then(|c| c.delta.content.as_deref()) {
+                on_chunk(content);
+                full.push_str(content);
+            }
+        }
+        OpenAIResponse {
+            choices: vec![OpenAIChoice {
+                message: OpenAIMessage {
+                    role: "assistant".to_string(),
+                    content: full,
+                },
+            }],
+        }
+    } else {
+        response.json()?
+    };
+    log_interaction(ai, model, &request, &openai_response, started.elapsed())?;
 
     if let Some(choice) = openai_response.choices.first() {
         Ok(choice.message.content.clone())
@@ -271,33 +374,43 @@ fn send_prompt_to_openai(
 }
 
 fn send_prompt_to_gemini(
-    config: &EditorConfig,
-    model: &ModelConfig,
-    system_prompt: Option<&str>,
-    user_prompt: &str,
-    text: &str,
+    _config: &EditorConfig,
+    _model: &ModelConfig,
+    _system_prompt: Option<&str>,
+    _user_prompt: &str,
+    _text: &str,
 ) -> Result<String, Box<dyn std::error::Error>> {
     todo!()
 }
 
-fn log_interaction(request_json: &str, response_json: &str) -> Result<(), Box<dyn std::error::Error>> {
+/// Appends one JSON object per interaction to the AI log.
+fn log_interaction(
+    ai: &AiConfig,
+    model: &ModelConfig,
+    request: &impl Serialize,
+    response: &impl Serialize,
+    duration: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
     let timestamp = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_secs();
-    let log_entry = format!(
-        "Timestamp: {}\nRequest JSON:\n{}\nResponse JSON:\n{}\n---\n",
-        timestamp,
-        request_json,
-        response_json
-    );
-
-    std::fs::create_dir_all("log")?;
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("log/ai.log")?;
-
-    file.write_all(log_entry.as_bytes())?;
+    let log_entry = serde_json::json!({
+        "timestamp": timestamp,
+        "provider": model.provider,
+        "model": model.id,
+        "request": request,
+        "response": response,
+        "duration_ms": duration.as_millis() as u64,
+    });
+
+    let path = ai
+        .log_path
+        .as_deref()
+        .map(logfile::resolve)
+        .unwrap_or_else(|| logfile::default_path("ai.log"));
+    let mut file = logfile::open(&path, ai.log_max_bytes.unwrap_or(logfile::DEFAULT_MAX_BYTES))?;
+
+    writeln!(file, "{}", log_entry)?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,2478 @@
+use crate::ai::{self, AiEvent};
+use crate::config::{self, EditorConfig};
+use crate::editor::{file_mtime, AiScope, AiStatus, Document, Editor, Focus, PromptAction, SearchScope, SelectionMode, SplitPane, ViewAlign};
+use crate::format;
+use crate::swap;
+use crate::symbols;
+use crate::syntax::{self, HighlightCache};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+/// What the event loop should do after a command line entry has run.
+#[derive(Debug, PartialEq)]
+pub enum CommandOutcome {
+    /// Nothing further to do.
+    Continue,
+    /// Show an informational message on the command line.
+    Message(String),
+    /// Ask a yes/no question; the action runs when the user answers 'y'.
+    Confirm(String, PromptAction),
+    /// Leave the editor.
+    Quit,
+}
+
+/// Copies `path` to `path~` ahead of overwriting it. A file that does not
+/// exist yet has nothing to back up; a failed copy is reported, not fatal.
+fn backup_file(path: &str) -> Option<String> {
+    if !Path::new(path).is_file() {
+        return None;
+    }
+    let backup = format!("{}~", path);
+    fs::copy(path, &backup).err().map(|e| format!("Backup to {} failed: {}", backup, e))
+}
+
+/// Reads `path` as text with its line endings normalised to `\n`. A file
+/// with a NUL byte or that is not UTF-8 is an error rather than text, so it
+/// never turns into an empty buffer that could be saved over it.
+pub fn read_text_file(path: &str) -> std::io::Result<String> {
+    let binary = || std::io::Error::new(std::io::ErrorKind::InvalidData, "binary file, not text");
+    let bytes = fs::read(path)?;
+    if bytes.contains(&0) {
+        return Err(binary());
+    }
+    let contents = String::from_utf8(bytes).map_err(|_| binary())?;
+    Ok(contents.replace("\r\n", "\n").replace('\r', "\n"))
+}
+
+/// The text of `buffer` as it goes on disk.
+fn file_contents(buffer: &[String], final_newline: bool) -> String {
+    let mut content = buffer.join("\n");
+    if final_newline {
+        content.push('\n');
+    }
+    content
+}
+
+/// Writes the buffer to its file, backing the old version up first when
+/// `make_backup` is on. Returns the backup failure, if there was one.
+pub fn save_file(editor: &mut Editor) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    if let Some(path) = editor.filename.clone() {
+        let warning = if editor.make_backup { backup_file(&path) } else { None };
+        std::fs::write(&path, file_contents(&editor.buffer, editor.final_newline))?;
+        editor.mark_as_saved(); // Mark as saved to clear modified flag
+        editor.disk_mtime = file_mtime(&path);
+        swap::remove(&path);
+        Ok(warning)
+    } else {
+        Err("No filename specified".into())
+    }
+}
+
+/// Reads `NAME.prompt` from the first of `dirs` that has it. The name may
+/// reach into subdirectories, as in `code/refactor`.
+pub fn load_prompt_file(prompt_name: &str, dirs: &[PathBuf]) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let file_name = format!("{}.prompt", prompt_name);
+    let candidates: Vec<PathBuf> = dirs.iter().map(|dir| dir.join(&file_name)).collect();
+    let Some(prompt_path) = candidates.iter().find(|path| path.is_file()) else {
+        let tried: Vec<String> = candidates.iter().map(|p| p.display().to_string()).collect();
+        return Err(format!("Prompt '{}' not found (looked for {})", prompt_name, tried.join(", ")).into());
+    };
+    let content = fs::read_to_string(prompt_path)?;
+
+    // Parse the prompt file to extract system and user sections
+    let mut system_prompt = String::new();
+    let mut user_prompt = String::new();
+    let mut current_section = String::new();
+
+    for line in content.lines() {
+        if line.trim() == "[system]" {
+            current_section = "system".to_string();
+            continue;
+        } else if line.trim() == "[user]" {
+            current_section = "user".to_string();
+            continue;
+        }
+
+        match current_section.as_str() {
+            "system" => {
+                if !system_prompt.is_empty() {
+                    system_prompt.push('\n');
+                }
+                system_prompt.push_str(line);
+            }
+            "user" => {
+                if !user_prompt.is_empty() {
+                    user_prompt.push('\n');
+                }
+                user_prompt.push_str(line);
+            }
+            _ => {}
+        }
+    }
+
+    if system_prompt.is_empty() {
+        return Err("No [system] section found in prompt file".into());
+    }
+
+    Ok((system_prompt, user_prompt))
+}
+
+/// Restores the document that was active before a read-only view (help, AI
+/// response) replaced it.
+pub fn restore_original(editor: &mut Editor) {
+    if let Some(buf) = editor.original_buffer.take() {
+        editor.buffer = buf;
+    }
+    editor.filename = editor.original_filename.take();
+    editor.cursor_y = editor.original_cursor_y;
+    editor.cursor_x = editor.original_cursor_x;
+    editor.scroll_y = editor.original_scroll_y;
+    editor.scroll_x = editor.original_scroll_x;
+    editor.modified = editor.original_modified;
+    editor.read_only = false;
+    editor.view_targets = None;
+    editor.unfold_all();
+    editor.focus = Focus::Editor;
+}
+
+/// Replaces the buffer with a read-only view of `lines`, remembering the
+/// document so `q` can bring it back. `targets` maps each row of the view
+/// to a line of the document for Enter to jump to.
+pub fn open_view(editor: &mut Editor, mut lines: Vec<String>, targets: Option<Vec<usize>>) {
+    editor.original_buffer = Some(editor.buffer.clone());
+    editor.original_filename = editor.filename.clone();
+    editor.original_cursor_y = editor.cursor_y;
+    editor.original_cursor_x = editor.cursor_x;
+    editor.original_scroll_y = editor.scroll_y;
+    editor.original_scroll_x = editor.scroll_x;
+    editor.original_modified = editor.modified;
+    editor.extra_cursors.clear();
+
+    if lines.is_empty() {
+        lines.push(String::new());
+    }
+    editor.buffer = lines;
+    editor.view_targets = targets;
+    editor.unfold_all();
+    editor.cursor_y = 0;
+    editor.cursor_x = 0;
+    editor.scroll_y = 0;
+    editor.scroll_x = 0;
+    editor.modified = false;
+    editor.read_only = true;
+    editor.focus = Focus::Editor;
+}
+
+/// If the cursor is on a row of a list view, returns to the document at the
+/// line that row refers to.
+pub fn follow_view_target(editor: &mut Editor) -> bool {
+    let target = match &editor.view_targets {
+        Some(targets) => match targets.get(editor.cursor_y) {
+            Some(&line) => line,
+            None => return false,
+        },
+        None => return false,
+    };
+    restore_original(editor);
+    editor.cursor_y = target.min(editor.buffer.len() - 1);
+    editor.cursor_x = 0;
+    editor.scroll();
+    true
+}
+
+/// How many closed documents `reopen` can bring back.
+const CLOSED_DOCUMENTS_MAX: usize = 10;
+
+/// Opens `doc` in a new tab right after the current one.
+fn open_tab(editor: &mut Editor, config: &EditorConfig, doc: Document) {
+    let previous = editor.swap_document(doc);
+    editor.tabs[editor.active_tab] = Some(previous);
+    editor.active_tab += 1;
+    editor.tabs.insert(editor.active_tab, None);
+    if let Some(pane) = editor.split.as_mut().filter(|pane| pane.tab >= editor.active_tab) {
+        pane.tab += 1;
+    }
+    editor.tab_width = config.tab_width_for(&editor.syntax_name);
+    editor.focus = Focus::Editor;
+}
+
+/// Makes tab `index` the active one.
+pub fn switch_tab(editor: &mut Editor, config: &EditorConfig, index: usize) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Return to the document first ('q').".to_string());
+    }
+    if index == editor.active_tab {
+        return CommandOutcome::Continue;
+    }
+    let doc = match editor.tabs.get_mut(index).and_then(Option::take) {
+        Some(doc) => doc,
+        None => return CommandOutcome::Message(format!("No buffer {}.", index + 1)),
+    };
+    let previous = editor.swap_document(doc);
+    editor.tabs[editor.active_tab] = Some(previous);
+    editor.active_tab = index;
+    editor.tab_width = config.tab_width_for(&editor.syntax_name);
+    editor.focus = Focus::Editor;
+    CommandOutcome::Continue
+}
+
+/// Cycles `step` tabs forward (or back, when negative), wrapping around.
+pub fn cycle_tab(editor: &mut Editor, config: &EditorConfig, step: isize) -> CommandOutcome {
+    let count = editor.tabs.len() as isize;
+    let index = (editor.active_tab as isize + step).rem_euclid(count) as usize;
+    switch_tab(editor, config, index)
+}
+
+/// Closes the active tab, unsaved changes and all, keeping it for `reopen`.
+/// The tab to its right (or the new last tab) becomes active.
+pub fn close_tab(editor: &mut Editor, config: &EditorConfig) -> CommandOutcome {
+    if editor.tabs.len() < 2 {
+        return CommandOutcome::Message("Only one buffer open.".to_string());
+    }
+    editor.tabs.remove(editor.active_tab);
+    match &mut editor.split {
+        Some(pane) if pane.tab == editor.active_tab => editor.split = None,
+        Some(pane) if pane.tab > editor.active_tab => pane.tab -= 1,
+        _ => {}
+    }
+    let index = editor.active_tab.min(editor.tabs.len() - 1);
+    let doc = editor.tabs[index].take().unwrap_or_else(|| Document::new("", None, "Plain Text".to_string()));
+    editor.active_tab = index;
+    let closed = editor.swap_document(doc);
+    editor.tab_width = config.tab_width_for(&editor.syntax_name);
+    editor.focus = Focus::Editor;
+    let name = closed.filename.clone().unwrap_or_else(|| "[New File]".to_string());
+    editor.closed_documents.push(closed);
+    if editor.closed_documents.len() > CLOSED_DOCUMENTS_MAX {
+        editor.closed_documents.remove(0);
+    }
+    CommandOutcome::Message(format!("Closed {}", name))
+}
+
+/// Splits the editor area in two, the new half showing tab `arg` (1-based)
+/// or, without one, another view of the current buffer.
+fn split_view(editor: &mut Editor, arg: &str) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Return to the document first ('q').".to_string());
+    }
+    let tab = if arg.is_empty() {
+        editor.active_tab
+    } else {
+        match arg.parse::<usize>() {
+            Ok(n) if (1..=editor.tabs.len()).contains(&n) => n - 1,
+            _ => return CommandOutcome::Message(format!("No buffer {}.", arg)),
+        }
+    };
+    let (cursor_x, cursor_y, scroll_x, scroll_y) = match &editor.tabs[tab] {
+        Some(doc) => (doc.cursor_x, doc.cursor_y, doc.scroll_x, doc.scroll_y),
+        None => (editor.cursor_x, editor.cursor_y, editor.scroll_x, editor.scroll_y),
+    };
+    editor.split = Some(SplitPane {
+        tab,
+        cursor_x,
+        cursor_y,
+        scroll_x,
+        scroll_y,
+        on_left: false,
+        highlight_cache: HighlightCache::default(),
+    });
+    CommandOutcome::Continue
+}
+
+/// Moves the focus to the other half of a split view. Each half keeps its
+/// own cursor and scroll position.
+pub fn switch_pane(editor: &mut Editor, config: &EditorConfig) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Return to the document first ('q').".to_string());
+    }
+    let mut pane = match editor.split.take() {
+        Some(pane) => pane,
+        None => return CommandOutcome::Message("No split view.".to_string()),
+    };
+    let here = (editor.cursor_x, editor.cursor_y, editor.scroll_x, editor.scroll_y);
+    let tab = std::mem::replace(&mut pane.tab, editor.active_tab);
+    if tab != editor.active_tab {
+        switch_tab(editor, config, tab);
+        std::mem::swap(&mut pane.highlight_cache, &mut editor.highlight_cache);
+    }
+    editor.cursor_x = pane.cursor_x;
+    editor.cursor_y = pane.cursor_y.min(editor.buffer.len() - 1);
+    editor.scroll_x = pane.scroll_x;
+    editor.scroll_y = pane.scroll_y;
+    (pane.cursor_x, pane.cursor_y, pane.scroll_x, pane.scroll_y) = here;
+    pane.on_left = !pane.on_left;
+    editor.split = Some(pane);
+    CommandOutcome::Continue
+}
+
+/// Writes a recovery file for every named document with unsaved changes.
+pub fn write_swap_files(editor: &Editor) {
+    // A view covers the document, which cannot have changed since
+    if editor.modified && !editor.read_only {
+        if let Some(path) = &editor.filename {
+            let _ = swap::write(path, &editor.buffer);
+        }
+    }
+    for doc in editor.tabs.iter().flatten().filter(|doc| doc.modified) {
+        if let Some(path) = &doc.filename {
+            let _ = swap::write(path, &doc.buffer);
+        }
+    }
+}
+
+/// Removes the recovery files of every open or closed document, on exit.
+pub fn remove_swap_files(editor: &Editor) {
+    let documents = editor.tabs.iter().flatten().chain(&editor.closed_documents);
+    for path in documents.filter_map(|doc| doc.filename.as_ref()).chain(editor.document_filename()) {
+        swap::remove(path);
+    }
+}
+
+/// Replaces the buffer with the text saved in its recovery file; undo
+/// goes back to the file as loaded.
+pub fn recover_swap(editor: &mut Editor) -> CommandOutcome {
+    let Some(path) = editor.filename.clone() else {
+        return CommandOutcome::Continue;
+    };
+    match swap::read(&path) {
+        Ok(lines) => {
+            let end = editor.buffer.len() - 1;
+            editor.replace_lines(0, end, lines);
+            CommandOutcome::Message(format!("Recovered unsaved changes from {}.", swap::swap_path(&path)))
+        }
+        Err(e) => CommandOutcome::Message(format!("Recovery failed: {}", e)),
+    }
+}
+
+/// Asks whether to recover the unsaved changes in recovery file `swap`.
+pub fn recovery_prompt(swap: &str) -> CommandOutcome {
+    CommandOutcome::Confirm(
+        format!("Found {} with unsaved changes. Recover them? (y/n)", swap),
+        PromptAction::Recover,
+    )
+}
+
+/// Saves the document, reporting how it went.
+pub fn save(editor: &mut Editor) -> CommandOutcome {
+    // A view keeps the document's file name, but not its text
+    if editor.read_only {
+        return CommandOutcome::Message("Return to the document first ('q').".to_string());
+    }
+    match save_file(editor) {
+        Ok(None) => CommandOutcome::Message("File saved.".to_string()),
+        Ok(Some(warning)) => CommandOutcome::Message(format!("File saved. {}", warning)),
+        Err(e) => CommandOutcome::Message(format!("Save failed: {}", e)),
+    }
+}
+
+/// Saves every modified document, the active one included. Returns the
+/// backup failures, if there were any.
+pub fn save_all(editor: &mut Editor) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    if editor.read_only {
+        return Err("Return to the document first ('q').".into());
+    }
+    let mut warnings = Vec::new();
+    if editor.modified {
+        if editor.changed_on_disk() {
+            return Err(format!("{} changed on disk - save it with 's' first", editor.filename.as_deref().unwrap_or("")).into());
+        }
+        warnings.extend(save_file(editor)?);
+    }
+    for doc in editor.tabs.iter_mut().flatten().filter(|doc| doc.modified) {
+        let path = doc.filename.as_ref().ok_or("Unnamed buffer - save it with saveas first")?;
+        if doc.disk_mtime.is_some() && file_mtime(path) != doc.disk_mtime {
+            return Err(format!("{} changed on disk - save it with 's' first", path).into());
+        }
+        if editor.make_backup {
+            warnings.extend(backup_file(path));
+        }
+        fs::write(path, file_contents(&doc.buffer, doc.final_newline))?;
+        doc.last_save_state = Some(doc.buffer.clone());
+        doc.modified = false;
+        doc.disk_mtime = file_mtime(path);
+        swap::remove(path);
+    }
+    Ok(warnings)
+}
+
+/// Opens `path` in a new tab, or switches to its tab when it is already
+/// open; a file that does not exist yet starts out empty.
+fn open_file(editor: &mut Editor, config: &EditorConfig, path: &str) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Return to the document first ('q').".to_string());
+    }
+    if editor.filename.as_deref() == Some(path) {
+        return CommandOutcome::Message(format!("{} is already open", path));
+    }
+    let open_index = editor
+        .tabs
+        .iter()
+        .position(|tab| tab.as_ref().is_some_and(|doc| doc.filename.as_deref() == Some(path)));
+    if let Some(index) = open_index {
+        switch_tab(editor, config, index);
+        return CommandOutcome::Message(format!("Switched to {}", path));
+    }
+    let (contents, message) = match read_text_file(path) {
+        Ok(contents) => (contents, format!("Opened {}", path)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (String::new(), format!("New file {}", path)),
+        // Binary files can only be looked at
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => return show_hex(editor, path),
+        Err(e) => return CommandOutcome::Message(format!("Cannot open {}: {}", path, e)),
+    };
+    let syntax_name = syntax::resolve_syntax(Some(path), contents.lines().next().unwrap_or(""), config);
+    open_tab(editor, config, Document::new(&contents, Some(path.to_string()), syntax_name));
+    match swap::find(path) {
+        Some(swap) => recovery_prompt(&swap),
+        None => CommandOutcome::Message(message),
+    }
+}
+
+/// Suffix pairs used by `alt` when the config has none.
+const DEFAULT_ALTERNATE_SUFFIXES: &[(&str, &str)] = &[
+    (".c", ".h"),
+    (".cpp", ".hpp"),
+    (".cc", ".h"),
+    (".rs", "_test.rs"),
+    (".ts", ".test.ts"),
+    (".js", ".test.js"),
+    (".py", "_test.py"),
+];
+
+/// Files related to `path` by the suffix pairs, best match first: a name
+/// ending in one suffix of a pair maps to the same stem with the other, and
+/// longer matched suffixes win so `foo_test.rs` pairs with `foo.rs`.
+fn alternate_candidates(path: &str, rules: &[(String, String)]) -> Vec<String> {
+    let mut matches: Vec<(usize, String)> = Vec::new();
+    for (a, b) in rules {
+        for (from, to) in [(a, b), (b, a)] {
+            if let Some(stem) = path.strip_suffix(from.as_str()) {
+                let candidate = format!("{}{}", stem, to);
+                if !stem.is_empty() && !stem.ends_with('/') && !matches.iter().any(|(_, c)| *c == candidate) {
+                    matches.push((from.len(), candidate));
+                }
+            }
+        }
+    }
+    matches.sort_by_key(|(len, _)| std::cmp::Reverse(*len));
+    matches.into_iter().map(|(_, candidate)| candidate).collect()
+}
+
+/// Opens the file related to the current one (`alt`), e.g. the header for a
+/// C source file.
+fn open_alternate(editor: &mut Editor, config: &EditorConfig) -> CommandOutcome {
+    let path = match &editor.filename {
+        Some(path) if !editor.read_only => path.clone(),
+        _ => return CommandOutcome::Message("No file to find a counterpart for.".to_string()),
+    };
+    let rules: Vec<(String, String)> = match &config.alternate_suffixes {
+        Some(rules) => rules.clone(),
+        None => DEFAULT_ALTERNATE_SUFFIXES
+            .iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect(),
+    };
+    let candidates = alternate_candidates(&path, &rules);
+    match candidates.iter().find(|c| Path::new(c).is_file()) {
+        Some(found) => open_file(editor, config, found),
+        None => match candidates.first() {
+            Some(first) => CommandOutcome::Message(format!("{} does not exist.", first)),
+            None => CommandOutcome::Message(format!("No related file rule matches {}.", path)),
+        },
+    }
+}
+
+/// Brings back the most recently closed document in a new tab.
+fn reopen(editor: &mut Editor, config: &EditorConfig) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Return to the document first ('q').".to_string());
+    }
+    match editor.closed_documents.pop() {
+        Some(doc) => {
+            open_tab(editor, config, doc);
+            let name = editor.filename.clone().unwrap_or_else(|| "[New File]".to_string());
+            CommandOutcome::Message(format!("Reopened {}", name))
+        }
+        None => CommandOutcome::Message("No closed documents.".to_string()),
+    }
+}
+
+/// Saves the document under a new name, which it keeps afterwards. A file
+/// that had no syntax of its own picks one up from the new extension.
+fn save_as(editor: &mut Editor, config: &EditorConfig, path: &str) -> CommandOutcome {
+    if path.is_empty() {
+        return CommandOutcome::Message("Usage: saveas <path>".to_string());
+    }
+    if editor.read_only {
+        return CommandOutcome::Message("Return to the document first ('q').".to_string());
+    }
+    let previous = editor.filename.replace(path.to_string());
+    match save_file(editor) {
+        Ok(warning) => {
+            if editor.syntax_name == "Plain Text" {
+                if let Some(name) = syntax::detect_syntax(path, &config.syntax_map) {
+                    editor.tab_width = config.tab_width_for(&name);
+                    editor.syntax_name = name;
+                }
+            }
+            match warning {
+                Some(warning) => CommandOutcome::Message(format!("Saved as {}. {}", path, warning)),
+                None => CommandOutcome::Message(format!("Saved as {}.", path)),
+            }
+        }
+        Err(e) => {
+            editor.filename = previous;
+            CommandOutcome::Message(format!("Save failed: {}", e))
+        }
+    }
+}
+
+/// `extract [-c] <path>`: writes the selection to `path`, asking first if
+/// the file exists.
+fn extract(editor: &mut Editor, config: &EditorConfig, args: &str) -> CommandOutcome {
+    let (copy, path) = match args.strip_prefix("-c ") {
+        Some(path) => (true, path.trim()),
+        None => (false, args),
+    };
+    if path.is_empty() || path == "-c" {
+        return CommandOutcome::Message("Usage: extract [-c] <path>".to_string());
+    }
+    if editor.selection_text().is_none() {
+        return CommandOutcome::Message("Nothing selected.".to_string());
+    }
+    if !copy && editor.read_only {
+        return CommandOutcome::Message("Buffer is read-only.".to_string());
+    }
+    if Path::new(path).exists() {
+        return CommandOutcome::Confirm(
+            format!("{} exists. Overwrite? (y/n)", path),
+            PromptAction::Extract { path: path.to_string(), copy },
+        );
+    }
+    extract_selection(editor, config, path, copy)
+}
+
+/// Writes the selection to `path` and, unless `copy`, removes it from the
+/// buffer, leaving the configured `extract_reference` line in place of
+/// selected lines.
+pub fn extract_selection(editor: &mut Editor, config: &EditorConfig, path: &str, copy: bool) -> CommandOutcome {
+    let (scope, text) = match editor.selection_text() {
+        Some(selection) => selection,
+        None => return CommandOutcome::Message("Nothing selected.".to_string()),
+    };
+    if let Err(e) = fs::write(path, &text) {
+        return CommandOutcome::Message(format!("Could not write {}: {}", path, e));
+    }
+    let count = text.lines().count();
+    if copy {
+        editor.deselect();
+        return CommandOutcome::Message(format!("Copied {} lines to {}.", count, path));
+    }
+    let reference = config.extract_reference.as_ref().map(|template| {
+        let indent = match scope {
+            AiScope::Lines { start_y, .. } => leading_whitespace(&editor.buffer[start_y]),
+            _ => String::new(),
+        };
+        format!("{}{}", indent, template.replace("{path}", path))
+    });
+    editor.remove_selection(scope, reference);
+    CommandOutcome::Message(format!("Moved {} lines to {}.", count, path))
+}
+
+/// `w <path>`: writes the selection, or the whole buffer, to `path`,
+/// asking first if the file exists. The buffer's own file is unaffected.
+fn write_to(editor: &mut Editor, path: &str) -> CommandOutcome {
+    if Path::new(path).exists() {
+        return CommandOutcome::Confirm(
+            format!("{} exists. Overwrite? (y/n)", path),
+            PromptAction::WriteTo { path: path.to_string() },
+        );
+    }
+    write_selection(editor, path)
+}
+
+/// Writes the selection, or the whole buffer, to `path`.
+pub fn write_selection(editor: &mut Editor, path: &str) -> CommandOutcome {
+    let text = match editor.selection_text() {
+        Some((_, text)) => text,
+        None => editor.buffer.join("\n"),
+    };
+    if let Err(e) = fs::write(path, &text) {
+        return CommandOutcome::Message(format!("Could not write {}: {}", path, e));
+    }
+    editor.deselect();
+    CommandOutcome::Message(format!("Wrote {} lines to {}.", text.lines().count(), path))
+}
+
+/// `read <path>` or `r <path>`: inserts the lines of a file above the
+/// cursor line, leaving the cursor on the line after them.
+fn read_file(editor: &mut Editor, path: &str) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Buffer is read-only.".to_string());
+    }
+    let contents = match read_text_file(path) {
+        Ok(contents) => contents,
+        Err(e) => return CommandOutcome::Message(format!("Could not read {}: {}", path, e)),
+    };
+    let mut lines: Vec<String> = contents.lines().map(|s| s.to_string()).collect();
+    let count = lines.len();
+    if count == 0 {
+        return CommandOutcome::Message(format!("{} is empty.", path));
+    }
+    let y = editor.cursor_y;
+    lines.push(editor.buffer[y].clone());
+    editor.replace_lines(y, y, lines);
+    editor.deselect();
+    editor.cursor_y = y + count;
+    editor.cursor_x = 0;
+    editor.scroll();
+    CommandOutcome::Message(format!("Read {} lines from {}.", count, path))
+}
+
+fn leading_whitespace(line: &str) -> String {
+    line.chars().take_while(|c| c.is_whitespace()).collect()
+}
+
+/// `reflow [width]`: re-wraps the selected lines, or the paragraph (or run
+/// of line comments) around the cursor.
+fn reflow(editor: &mut Editor, config: &EditorConfig, arg: &str) -> CommandOutcome {
+    let width = match arg {
+        "" => config.reflow_width.unwrap_or(80),
+        arg => match arg.parse::<usize>() {
+            Ok(width) if width > 0 => width,
+            _ => return CommandOutcome::Message("Invalid width.".to_string()),
+        },
+    };
+    let prefix = syntax::line_comment_prefix(&editor.syntax_name, config);
+    let (start, end) = if editor.selection_text().is_some() {
+        editor.selected_line_range()
+    } else {
+        let y = editor.cursor_y;
+        let comment_line = |line: &String| match &prefix {
+            Some(p) => line.trim_start().starts_with(p.as_str()),
+            None => false,
+        };
+        if comment_line(&editor.buffer[y]) {
+            let mut start = y;
+            while start > 0 && comment_line(&editor.buffer[start - 1]) {
+                start -= 1;
+            }
+            let mut end = y;
+            while end + 1 < editor.buffer.len() && comment_line(&editor.buffer[end + 1]) {
+                end += 1;
+            }
+            (start, end)
+        } else {
+            match editor.paragraph_bounds(y) {
+                Some(bounds) => bounds,
+                None => return CommandOutcome::Message("No paragraph at the cursor.".to_string()),
+            }
+        }
+    };
+    let lines = format::reflow(&editor.buffer[start..=end], width, prefix.as_deref());
+    let count = lines.len();
+    if editor.replace_lines(start, end, lines) {
+        editor.cursor_y = start;
+        editor.cursor_x = 0;
+        editor.deselect();
+        editor.scroll();
+        CommandOutcome::Message(format!("Reflowed to {} lines.", count))
+    } else {
+        CommandOutcome::Message("Buffer is read-only.".to_string())
+    }
+}
+
+/// `syntax <name>` switches the highlighting of the current document; a bare
+/// `syntax` lists the available names. Names match case-insensitively.
+fn set_syntax(editor: &mut Editor, config: &EditorConfig, name: &str) -> CommandOutcome {
+    let names = syntax::syntax_names();
+    if name.is_empty() {
+        if editor.read_only {
+            return CommandOutcome::Message("Return to the document first ('q').".to_string());
+        }
+        let lines = names
+            .iter()
+            .map(|n| format!("{} {}", if *n == editor.syntax_name { "*" } else { " " }, n))
+            .collect();
+        open_view(editor, lines, None);
+        return CommandOutcome::Message("Available syntaxes - use 'q' to return to document".to_string());
+    }
+    match names.into_iter().find(|n| n.eq_ignore_ascii_case(name)) {
+        Some(found) => {
+            editor.tab_width = config.tab_width_for(&found);
+            editor.syntax_name = found;
+            CommandOutcome::Message(format!("Syntax set to {}.", editor.syntax_name))
+        }
+        None => CommandOutcome::Message(format!("Unknown syntax: {}", name)),
+    }
+}
+
+/// Re-reads the config file and applies it to the running editor. The
+/// caller rebuilds the syntax engine if the theme changed.
+fn reload_config(editor: &mut Editor, config: &mut EditorConfig) -> CommandOutcome {
+    let new_config = match EditorConfig::load() {
+        Ok(new_config) => new_config,
+        Err(e) => return CommandOutcome::Message(format!("Config not reloaded: {}", e)),
+    };
+    // Keep the model picked with `model` if it still exists
+    let active_model = config.ai.as_ref().and_then(|ai| ai.default_model.clone());
+    *config = new_config;
+    if let (Some(ai), Some(id)) = (config.ai.as_mut(), active_model) {
+        if ai.models.iter().any(|m| m.id == id) {
+            ai.default_model = Some(id);
+        }
+    }
+
+    editor.apply_config(config);
+    // The document itself, not a help or list view shown over it
+    let first_line = match &editor.original_buffer {
+        Some(buffer) => buffer[0].clone(),
+        None => editor.buffer[0].clone(),
+    };
+    editor.syntax_name = syntax::resolve_syntax(editor.document_filename().as_deref(), &first_line, config);
+    editor.tab_width = config.tab_width_for(&editor.syntax_name);
+    CommandOutcome::Message("Config reloaded.".to_string())
+}
+
+/// `diff [path]`: with no path, reviews the unsaved edits against the file
+/// on disk. With one, reviews that file's differences as changes to take
+/// into the buffer.
+fn diff_with_file(editor: &mut Editor, path: &str) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Return to the document first ('q').".to_string());
+    }
+    let (path, own_file) = match (path, &editor.filename) {
+        ("", Some(filename)) => (filename.clone(), true),
+        ("", None) => return CommandOutcome::Message("Usage: diff <path>".to_string()),
+        (path, _) => (path.to_string(), false),
+    };
+    let contents = match read_text_file(&path) {
+        Ok(contents) => contents,
+        Err(e) => return CommandOutcome::Message(format!("Cannot read {}: {}", path, e)),
+    };
+    let mut lines: Vec<String> = contents.lines().map(|s| s.to_string()).collect();
+    if lines.is_empty() {
+        lines.push(String::new());
+    }
+    if own_file {
+        if !editor.start_disk_diff(lines) {
+            return CommandOutcome::Message(format!("No changes from {}.", path));
+        }
+        return CommandOutcome::Message(format!(
+            "Changes from {}: {} hunks - 'r' reverts one, 'q' to return",
+            path,
+            editor.get_hunks().len()
+        ));
+    }
+    editor.start_diff_mode(None, lines);
+    if editor.get_hunks().is_empty() {
+        editor.cancel_diff_mode();
+        return CommandOutcome::Message(format!("The buffer is the same as {}.", path));
+    }
+    CommandOutcome::Message(format!(
+        "Differences from {}: {} hunks - 'a' takes one in, 'q' to return",
+        path,
+        editor.get_hunks().len()
+    ))
+}
+
+/// How much of a file `hex` shows.
+const HEX_VIEW_MAX_BYTES: usize = 1024 * 1024;
+
+/// `hex [path]`: shows the bytes of a file, by default the current one as
+/// last saved, as a hex dump in a read-only view.
+fn show_hex(editor: &mut Editor, path: &str) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Return to the document first ('q').".to_string());
+    }
+    let path = match (path, &editor.filename) {
+        ("", Some(filename)) => filename.clone(),
+        ("", None) => return CommandOutcome::Message("Usage: hex [path]".to_string()),
+        (path, _) => path.to_string(),
+    };
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) => return CommandOutcome::Message(format!("Cannot open {}: {}", path, e)),
+    };
+    let shown = bytes.len().min(HEX_VIEW_MAX_BYTES);
+    open_view(editor, format::hex_dump(&bytes[..shown]), None);
+    if shown < bytes.len() {
+        CommandOutcome::Message(format!("{}: first {} of {} bytes - 'q' to return", path, shown, bytes.len()))
+    } else {
+        CommandOutcome::Message(format!("{}: {} bytes - 'q' to return", path, bytes.len()))
+    }
+}
+
+/// Lists the symbols of the current buffer in a view that jumps to the
+/// selected definition on Enter.
+fn show_symbols(editor: &mut Editor, config: &EditorConfig) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Return to the document first ('q').".to_string());
+    }
+    let syntax_name = editor.syntax_name.clone();
+    let patterns = config
+        .symbol_patterns
+        .as_ref()
+        .and_then(|map| map.get(&syntax_name).cloned())
+        .unwrap_or_else(|| symbols::default_patterns(&syntax_name));
+    if patterns.is_empty() {
+        return CommandOutcome::Message(format!("No symbol patterns for {}.", syntax_name));
+    }
+
+    let found = match symbols::scan(&editor.buffer, &patterns) {
+        Ok(found) => found,
+        Err(e) => return CommandOutcome::Message(e),
+    };
+    if found.is_empty() {
+        return CommandOutcome::Message("No symbols found.".to_string());
+    }
+
+    let width = (editor.buffer.len() as f64).log10() as usize + 1;
+    let rows = found
+        .iter()
+        .map(|s| format!("{:>width$}  {}", s.line + 1, s.name, width = width))
+        .collect();
+    let targets = found.iter().map(|s| s.line).collect();
+    let count = found.len();
+    open_view(editor, rows, Some(targets));
+    CommandOutcome::Message(format!("{} symbols - Enter to jump, 'q' to return", count))
+}
+
+/// Makes `id` the model used for AI requests, or lists the configured models
+/// when `id` is empty.
+fn switch_model(config: &mut EditorConfig, id: &str) -> CommandOutcome {
+    let ai = match config.ai.as_mut() {
+        Some(ai) => ai,
+        None => return CommandOutcome::Message("No AI config.".to_string()),
+    };
+    if id.is_empty() {
+        let models: Vec<String> = ai
+            .models
+            .iter()
+            .map(|m| format!("{} ({})", m.id, m.display_name))
+            .collect();
+        return CommandOutcome::Message(format!("Models: {}", models.join(", ")));
+    }
+    match ai.models.iter().find(|m| m.id == id) {
+        Some(model) => {
+            let message = format!("Active model: {}", model.display_name);
+            ai.default_model = Some(id.to_string());
+            CommandOutcome::Message(message)
+        }
+        None => CommandOutcome::Message(format!("Unknown model: {}", id)),
+    }
+}
+
+/// `sort` and `resort`: sorts the selection, or the whole buffer, by
+/// column keys.
+fn sort(editor: &mut Editor, specs: Vec<(usize, usize, bool, bool)>) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Buffer is read-only.".to_string());
+    }
+    let spec_text = Editor::format_sort_specs(&specs);
+    let (sorted, lines) = if editor.selection_text().is_some() {
+        let (start, end) = editor.selected_line_range();
+        (editor.sort_block(specs), end - start + 1)
+    } else {
+        (editor.sort_all(specs), editor.buffer.len())
+    };
+    if sorted {
+        CommandOutcome::Message(format!("Sorted {} lines by {}.", lines, spec_text))
+    } else {
+        CommandOutcome::Message("Nothing to sort.".to_string())
+    }
+}
+
+/// `!cmd` or `filter cmd`: pipes the selected lines, or the whole buffer,
+/// through a shell command and replaces them with its output. A failing
+/// command leaves the buffer alone and reports its stderr.
+fn filter(editor: &mut Editor, shell: &str) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Buffer is read-only.".to_string());
+    }
+    let (start, end) = editor.selected_line_range();
+    let input = editor.buffer[start..=end].join("\n") + "\n";
+    let lines = match run_shell(shell, Some(input)) {
+        Ok(lines) => lines,
+        Err(message) => return CommandOutcome::Message(message),
+    };
+    let count = lines.len();
+    editor.replace_lines(start, end, lines);
+    editor.deselect();
+    CommandOutcome::Message(format!("Filtered {} lines into {}.", end - start + 1, count))
+}
+
+/// `r!cmd`: inserts the output of a shell command at the cursor.
+fn read_shell(editor: &mut Editor, shell: &str) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Buffer is read-only.".to_string());
+    }
+    let lines = match run_shell(shell, None) {
+        Ok(lines) => lines,
+        Err(message) => return CommandOutcome::Message(message),
+    };
+    if editor.insert_lines(&lines) {
+        CommandOutcome::Message(format!("Inserted {} lines.", lines.len()))
+    } else {
+        CommandOutcome::Message(format!("'{}' printed nothing.", shell))
+    }
+}
+
+/// Runs `shell` through `sh -c`, feeding it `input` if there is any, and
+/// returns its stdout as lines. A failing command comes back as a message
+/// carrying its stderr.
+fn run_shell(shell: &str, input: Option<String>) -> Result<Vec<String>, String> {
+    let mut child = process::Command::new("sh")
+        .arg("-c")
+        .arg(shell)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Could not run shell: {}", e))?;
+    // Feed stdin from another thread so a command that writes as it reads
+    // cannot fill its stdout pipe and stall
+    let writer = input.map(|input| {
+        let mut stdin = child.stdin.take().unwrap();
+        thread::spawn(move || {
+            let _ = stdin.write_all(input.as_bytes());
+        })
+    });
+    let output = child.wait_with_output();
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+    let output = output.map_err(|e| format!("Could not run shell: {}", e))?;
+    if !output.status.success() {
+        // The prompt is one line
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = stderr.trim().lines().collect::<Vec<_>>().join("; ");
+        return Err(match stderr.as_str() {
+            "" => format!("'{}' failed ({}).", shell, output.status),
+            _ => format!("'{}' failed ({}): {}", shell, output.status, stderr),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).lines().map(|s| s.to_string()).collect())
+}
+
+/// `upper`, `lower`, `title` and `rot13`: rewrites the selection, or the
+/// cursor line.
+fn transform(editor: &mut Editor, transform: impl Fn(&str) -> String) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Buffer is read-only.".to_string());
+    }
+    match editor.transform_selection(transform) {
+        0 => CommandOutcome::Message("Nothing to change.".to_string()),
+        count => CommandOutcome::Message(format!("Changed {} lines.", count)),
+    }
+}
+
+/// F3: starts recording a keyboard macro, or stops and keeps it.
+pub fn toggle_recording(editor: &mut Editor) -> CommandOutcome {
+    match editor.recording.take() {
+        None => {
+            editor.recording = Some(Vec::new());
+            CommandOutcome::Message("Recording macro - F3 to stop.".to_string())
+        }
+        Some(keys) if keys.is_empty() => CommandOutcome::Message("Macro is empty, kept the previous one.".to_string()),
+        Some(keys) => {
+            let count = keys.len();
+            editor.last_macro = keys;
+            CommandOutcome::Message(format!("Recorded {} keys.", count))
+        }
+    }
+}
+
+/// F4 or `replay [N]`: asks the event loop to replay the last macro.
+pub fn replay(editor: &mut Editor, count: usize) -> CommandOutcome {
+    if editor.recording.is_some() {
+        CommandOutcome::Message("Stop recording first (F3).".to_string())
+    } else if editor.last_macro.is_empty() {
+        CommandOutcome::Message("No macro recorded.".to_string())
+    } else {
+        editor.macro_replay = Some(count);
+        CommandOutcome::Continue
+    }
+}
+
+/// One command line command, for completion and the `?` list.
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub args: &'static str,
+    pub summary: &'static str,
+}
+
+const fn command(name: &'static str, args: &'static str, summary: &'static str) -> CommandInfo {
+    CommandInfo { name, args, summary }
+}
+
+/// Every command `parse_command` (or the ui, for `exporthtml`) accepts.
+pub const COMMANDS: &[CommandInfo] = &[
+    command("q", "", "Quit, or close the current tab or view"),
+    command("quit", "", "Same as q"),
+    command("qa", "", "Quit, offering to save every tab"),
+    command("quitall", "", "Same as qa"),
+    command("qa!", "", "Quit, discarding unsaved changes"),
+    command("s", "", "Save the file"),
+    command("save", "", "Same as s"),
+    command("saveas", "<path>", "Save under a new file name"),
+    command("undo", "", "Undo the last edit"),
+    command("redo", "", "Redo the last undone edit"),
+    command("o", "", "Open a line below with the same indentation"),
+    command("O", "", "Open a line above with the same indentation"),
+    command("selpara", "", "Select the paragraph around the cursor"),
+    command("foldfunc", "", "Fold every top-level block but the current one"),
+    command("unfold", "", "Open all folds"),
+    command("copy", "", "Copy the selection"),
+    command("cut", "", "Cut the selection"),
+    command("paste", "", "Paste the clipboard"),
+    command("numberlines", "[width]", "Prefix lines with their line numbers"),
+    command("unnumberlines", "", "Remove numbers added by numberlines"),
+    command("extract", "[-c] <path>", "Move (or copy) the selection to a file"),
+    command("w", "<path>", "Write the selection or buffer to another file"),
+    command("r", "<path>", "Insert the contents of a file above the cursor line"),
+    command("read", "<path>", "Same as r"),
+    command("exporthtml", "<path>", "Write highlighted HTML of the selection or buffer"),
+    command("uniqc", "", "Collapse repeated lines with counts"),
+    command("alignassign", "", "Align assignment operators"),
+    command("reflow", "[width]", "Re-wrap the paragraph or selection"),
+    command("sort", "<from>-<to>[,asc|,desc][,num] ...", "Sort the selection or buffer by column keys"),
+    command("resort", "", "Sort again with the last sort keys"),
+    command("reverse", "", "Reverse the order of the selected lines"),
+    command("!", "<shell command>", "Pipe the selected lines or buffer through a shell command"),
+    command("filter", "<shell command>", "Same as !"),
+    command("r!", "<shell command>", "Insert the output of a shell command at the cursor"),
+    command("upper", "", "Upper-case the selection or cursor line"),
+    command("lower", "", "Lower-case the selection or cursor line"),
+    command("title", "", "Title-case the selection or cursor line"),
+    command("rot13", "", "Rot13 the selection or cursor line"),
+    command("incr", "[amount]", "Add to every number in the block selection"),
+    command("scope", "", "Highlight the { } block around the cursor"),
+    command("%", "", "Jump to the bracket matching the one under the cursor"),
+    command("zz", "", "Scroll the cursor line to the middle of the view"),
+    command("center", "", "Same as zz"),
+    command("zt", "", "Scroll the cursor line to the top of the view"),
+    command("zb", "", "Scroll the cursor line to the bottom of the view"),
+    command("lnum", "", "Toggle line numbers"),
+    command("relativenumber", "", "Toggle relative line numbers"),
+    command("goto", "<line>", "Jump to a line"),
+    command("find", "\"text\" [ins]", "Search for text"),
+    command("replace", "\"text\" \"new text\" [ins] [all]", "Replace text, one match at a time with F1 or all at once"),
+    command("open", "<file>", "Open a file in a new tab"),
+    command("alt", "", "Open the related file (foo.c / foo.h)"),
+    command("bnext", "", "Next tab"),
+    command("bprev", "", "Previous tab"),
+    command("bclose", "", "Close the current tab"),
+    command("split", "[N]", "Show a second buffer side by side"),
+    command("unsplit", "", "Close the split view"),
+    command("reopen", "", "Reopen the last closed file"),
+    command("replay", "[N]", "Replay the recorded macro N times"),
+    command("addcursors", "", "Put a cursor on every occurrence of the selection"),
+    command("reloadconfig", "", "Re-read the config file"),
+    command("help", "", "Open the help file"),
+    command("messages", "", "List recent status messages"),
+    command("hex", "[path]", "Show the bytes of a file as a hex dump"),
+    command("diff", "[path]", "Review the unsaved changes, or the differences from another file"),
+    command("symbols", "", "List the definitions in the file"),
+    command("syntax", "[name]", "Set or list the highlighting syntax"),
+    command("wrap", "[on|off]", "Toggle soft wrapping"),
+    command("vcur", "[on|off]", "Toggle moving the cursor past the end of lines"),
+    command("list", "[on|off]", "Toggle showing tabs, trailing spaces and line ends"),
+    command("cursorline", "[on|off]", "Toggle shading the cursor line"),
+    command("ruler", "[on|off]", "Toggle the column ruler"),
+    command("colorcolumn", "<column|off>", "Shade a column as a line length guide"),
+    command("spell", "[on|off]", "Toggle marking misspelled words"),
+    command("spelladd", "[word]", "Add a word, or the one under the cursor, to the word list"),
+    command("wrapmarker", "[on|off|<glyph>]", "Toggle or set the wrap marker"),
+    command("transposewords", "", "Swap the words around the cursor"),
+    command("normjson", "[commas|quotes]", "Make relaxed JSON strict"),
+    command("xmlfmt", "[min]", "Re-indent or minify XML"),
+    command("model", "[id]", "Switch or list AI models"),
+    command("prompt", "<prompt or file>", "Send a prompt to the AI"),
+    command("?", "", "List the commands"),
+];
+
+/// Opens the command list as a view.
+fn list_commands(editor: &mut Editor) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Return to the document first ('q').".to_string());
+    }
+    let width = COMMANDS.iter().map(|c| c.name.len() + c.args.len() + 1).max().unwrap_or(0);
+    let lines = COMMANDS
+        .iter()
+        .map(|c| format!("{:<width$}  {}", format!("{} {}", c.name, c.args), c.summary, width = width))
+        .collect();
+    open_view(editor, lines, None);
+    CommandOutcome::Message(format!("{} commands - 'q' to return", COMMANDS.len()))
+}
+
+/// `messages`: lists the recent status messages, oldest first.
+fn show_messages(editor: &mut Editor) -> CommandOutcome {
+    if editor.read_only {
+        return CommandOutcome::Message("Return to the document first ('q').".to_string());
+    }
+    if editor.message_log.is_empty() {
+        return CommandOutcome::Message("No messages yet.".to_string());
+    }
+    let width = editor.message_log.len().to_string().len();
+    let lines = editor
+        .message_log
+        .iter()
+        .enumerate()
+        .map(|(i, message)| format!("{:>width$}  {}", i + 1, message, width = width))
+        .collect();
+    let count = editor.message_log.len();
+    open_view(editor, lines, None);
+    CommandOutcome::Message(format!("{} messages - 'q' to return", count))
+}
+
+/// Completes the command name being typed. A unique match is filled in;
+/// otherwise the common prefix of the matches is, and further presses cycle
+/// through them. With nothing typed the command list opens instead.
+pub fn complete_command(editor: &mut Editor) -> CommandOutcome {
+    let typed = editor.command_buffer.trim_start().to_string();
+    if typed.is_empty() {
+        return list_commands(editor);
+    }
+    if typed.contains(' ') {
+        return CommandOutcome::Continue;
+    }
+    let (prefix, cycled) = match editor.command_completion.take() {
+        Some((prefix, index)) => (prefix, Some(index)),
+        None => (typed.clone(), None),
+    };
+    let matches: Vec<&CommandInfo> = COMMANDS.iter().filter(|c| c.name.starts_with(&prefix)).collect();
+    let completed = match matches.as_slice() {
+        [] => return CommandOutcome::Continue,
+        [only] if only.args.is_empty() => only.name.to_string(),
+        [only] => format!("{} ", only.name),
+        _ => {
+            let common = matches.iter().fold(matches[0].name, |common, c| {
+                let len = common.chars().zip(c.name.chars()).take_while(|(a, b)| a == b).count();
+                &common[..len]
+            });
+            if cycled.is_none() && common.len() > typed.len() {
+                common.to_string()
+            } else {
+                let index = cycled.map_or(0, |i| (i + 1) % matches.len());
+                editor.command_completion = Some((prefix, index));
+                matches[index].name.to_string()
+            }
+        }
+    };
+    editor.command_cursor = completed.len();
+    editor.command_buffer = completed;
+    CommandOutcome::Continue
+}
+
+/// A command line entry, parsed but not yet run. Arguments are checked
+/// here; whether the command applies to the current buffer is left to
+/// `dispatch`.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Quit,
+    QuitAll,
+    ForceQuitAll,
+    Save,
+    SaveAs(String),
+    TransposeWords,
+    Undo,
+    Redo,
+    OpenLineBelow,
+    OpenLineAbove,
+    SelectParagraph,
+    FoldFunction,
+    Unfold,
+    Copy,
+    Cut,
+    Paste,
+    /// `None` uses the configured width.
+    NumberLines(Option<usize>),
+    UnnumberLines,
+    Extract(String),
+    WriteTo(String),
+    ReadFile(String),
+    CountDuplicates,
+    AlignAssignments,
+    Reflow(String),
+    /// Column keys as `(start, end, asc, numeric)`, 0-based with `end`
+    /// exclusive.
+    Sort(Vec<(usize, usize, bool, bool)>),
+    Resort,
+    Reverse,
+    /// Shell command to pipe the selected lines through.
+    Filter(String),
+    /// Shell command whose output goes in at the cursor.
+    ReadShell(String),
+    Upper,
+    Lower,
+    Title,
+    Rot13,
+    /// Amount to add to each number in the block selection.
+    Increment(i64),
+    Scope,
+    MatchBracket,
+    AlignView(ViewAlign),
+    ToggleLineNumbers,
+    ToggleRelativeNumbers,
+    /// A 1-based line number.
+    Goto(usize),
+    Find { text: String, case_sensitive: bool },
+    Replace { text: String, with: String, case_sensitive: bool, all: bool },
+    Open(String),
+    Alternate,
+    NextTab,
+    PrevTab,
+    Split(String),
+    Unsplit,
+    CloseTab,
+    Reopen,
+    ListCommands,
+    ReloadConfig,
+    Help,
+    Messages,
+    /// Empty for the current file.
+    Hex(String),
+    /// Empty for the current file as saved.
+    Diff(String),
+    Symbols,
+    Syntax(String),
+    /// `None` toggles.
+    Wrap(Option<bool>),
+    /// `None` toggles.
+    VirtualCursor(Option<bool>),
+    /// `None` toggles.
+    ShowWhitespace(Option<bool>),
+    /// `None` toggles.
+    CursorLine(Option<bool>),
+    /// `None` toggles.
+    Ruler(Option<bool>),
+    /// `None` turns the guide off.
+    ColorColumn(Option<usize>),
+    /// `None` toggles.
+    Spell(Option<bool>),
+    /// An empty word means the one under the cursor.
+    SpellAdd(String),
+    WrapMarker(String),
+    NormalizeJson { strip_commas: bool, double_quotes: bool },
+    FormatXml { minify: bool },
+    Model(String),
+    Prompt(String),
+    Replay(usize),
+    AddCursors,
+    /// A known command with bad arguments; holds the message to show.
+    Invalid(String),
+    Unknown(String),
+}
+
+/// Parses sort keys typed as 1-based inclusive column ranges, each
+/// optionally followed by `n` (compare as numbers) and `d` (descending),
+/// or spelled out as `,num`, `,asc` and `,desc`: `1-10 15-20nd` is
+/// `1-10,asc 15-20,num,desc`. The reverse of `Editor::format_sort_specs`.
+fn parse_sort_specs(text: &str) -> Option<Vec<(usize, usize, bool, bool)>> {
+    let specs = text
+        .split_whitespace()
+        .map(|spec| {
+            let (spec, words) = spec.split_once(',').unwrap_or((spec, ""));
+            let flags_at = spec.find(|c: char| !c.is_ascii_digit() && c != '-').unwrap_or(spec.len());
+            let (range, flags) = spec.split_at(flags_at);
+            let (start, end) = range.split_once('-')?;
+            let (start, end) = (start.parse::<usize>().ok()?, end.parse::<usize>().ok()?);
+            if start == 0 || end < start || !flags.chars().all(|c| c == 'n' || c == 'd') {
+                return None;
+            }
+            let (mut asc, mut numeric) = (!flags.contains('d'), flags.contains('n'));
+            for word in words.split(',').filter(|word| !word.is_empty()) {
+                match word {
+                    "asc" => asc = true,
+                    "desc" => asc = false,
+                    "num" => numeric = true,
+                    _ => return None,
+                }
+            }
+            Some((start - 1, end, asc, numeric))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (!specs.is_empty()).then_some(specs)
+}
+
+/// Parses a command line entry. Never fails: anything unrecognised comes
+/// back as `Command::Unknown`.
+pub fn parse_command(cmd: &str) -> Command {
+    let cmd = cmd.trim();
+    let (name, arg) = cmd.split_once(' ').map_or((cmd, ""), |(name, arg)| (name, arg.trim()));
+    let invalid = |message: &str| Command::Invalid(message.to_string());
+    if let Some(shell) = cmd.strip_prefix("r!") {
+        return match shell.trim() {
+            "" => invalid("Usage: r!<shell command>"),
+            shell => Command::ReadShell(shell.to_string()),
+        };
+    }
+    if let Some(shell) = cmd.strip_prefix('!') {
+        return match shell.trim() {
+            "" => invalid("Usage: !<shell command>"),
+            shell => Command::Filter(shell.to_string()),
+        };
+    }
+    match (name, arg) {
+        ("q" | "quit", "") => Command::Quit,
+        ("qa!", "") => Command::ForceQuitAll,
+        ("qa" | "quitall", "") => Command::QuitAll,
+        ("s" | "save", "") => Command::Save,
+        ("saveas", path) if !path.is_empty() => Command::SaveAs(path.to_string()),
+        ("transposewords", "") => Command::TransposeWords,
+        ("undo", "") => Command::Undo,
+        ("redo", "") => Command::Redo,
+        ("o", "") => Command::OpenLineBelow,
+        ("O", "") => Command::OpenLineAbove,
+        ("selpara", "") => Command::SelectParagraph,
+        ("foldfunc", "") => Command::FoldFunction,
+        ("unfold", "") => Command::Unfold,
+        ("copy", "") => Command::Copy,
+        ("cut", "") => Command::Cut,
+        ("paste", "") => Command::Paste,
+        ("numberlines", "") => Command::NumberLines(None),
+        ("numberlines", width) => match width.parse::<usize>() {
+            Ok(width) => Command::NumberLines(Some(width)),
+            Err(_) => invalid("Invalid width."),
+        },
+        ("unnumberlines", "") => Command::UnnumberLines,
+        ("extract", args) if !args.is_empty() => Command::Extract(args.to_string()),
+        ("w", "") => invalid("Usage: w <path>"),
+        ("w", path) => Command::WriteTo(path.to_string()),
+        ("r" | "read", "") => invalid("Usage: read <path>"),
+        ("r" | "read", path) => Command::ReadFile(path.to_string()),
+        ("uniqc", "") => Command::CountDuplicates,
+        ("alignassign", "") => Command::AlignAssignments,
+        ("reflow", width) => Command::Reflow(width.to_string()),
+        ("sort", specs) => match parse_sort_specs(specs) {
+            Some(specs) => Command::Sort(specs),
+            None => invalid("Usage: sort <from>-<to>[,asc|,desc][,num] ..."),
+        },
+        ("resort", "") => Command::Resort,
+        ("reverse", "") => Command::Reverse,
+        ("filter", "") => invalid("Usage: filter <shell command>"),
+        ("filter", shell) => Command::Filter(shell.to_string()),
+        ("upper", "") => Command::Upper,
+        ("lower", "") => Command::Lower,
+        ("title", "") => Command::Title,
+        ("rot13", "") => Command::Rot13,
+        ("incr", "") => Command::Increment(1),
+        ("incr", delta) => match delta.parse::<i64>() {
+            Ok(delta) => Command::Increment(delta),
+            Err(_) => invalid("Usage: incr [amount]"),
+        },
+        ("scope", "") => Command::Scope,
+        ("%", "") => Command::MatchBracket,
+        ("zz" | "center", "") => Command::AlignView(ViewAlign::Center),
+        ("zt", "") => Command::AlignView(ViewAlign::Top),
+        ("zb", "") => Command::AlignView(ViewAlign::Bottom),
+        ("lnum", "") => Command::ToggleLineNumbers,
+        ("relativenumber", "") => Command::ToggleRelativeNumbers,
+        ("goto", line) if !line.is_empty() => match line.parse::<usize>() {
+            Ok(line) => Command::Goto(line),
+            Err(_) => invalid("Invalid line number."),
+        },
+        ("find", _) => match Editor::parse_find_command(cmd) {
+            Some((text, case_sensitive)) => Command::Find { text, case_sensitive },
+            None => Command::Unknown(cmd.to_string()),
+        },
+        ("replace", _) => match Editor::parse_replace_command(cmd) {
+            Some((text, with, case_sensitive, all)) => Command::Replace { text, with, case_sensitive, all },
+            None => invalid("Usage: replace \"text\" \"new text\" [ins] [all]"),
+        },
+        ("open", path) if !path.is_empty() => Command::Open(path.to_string()),
+        ("alt", "") => Command::Alternate,
+        ("bnext", "") => Command::NextTab,
+        ("bprev", "") => Command::PrevTab,
+        ("split", arg) => Command::Split(arg.to_string()),
+        ("unsplit", "") => Command::Unsplit,
+        ("bclose", "") => Command::CloseTab,
+        ("reopen", "") => Command::Reopen,
+        ("?", "") => Command::ListCommands,
+        ("reloadconfig", "") => Command::ReloadConfig,
+        ("help", "") => Command::Help,
+        ("messages", "") => Command::Messages,
+        ("diff", path) => Command::Diff(path.to_string()),
+        ("hex", path) => Command::Hex(path.to_string()),
+        ("symbols", "") => Command::Symbols,
+        ("syntax", name) => Command::Syntax(name.to_string()),
+        ("wrap", "") => Command::Wrap(None),
+        ("wrap", "on") => Command::Wrap(Some(true)),
+        ("wrap", "off") => Command::Wrap(Some(false)),
+        ("wrap", _) => invalid("Usage: wrap [on|off]"),
+        ("vcur", "") => Command::VirtualCursor(None),
+        ("vcur", "on") => Command::VirtualCursor(Some(true)),
+        ("vcur", "off") => Command::VirtualCursor(Some(false)),
+        ("vcur", _) => invalid("Usage: vcur [on|off]"),
+        ("list", "") => Command::ShowWhitespace(None),
+        ("list", "on") => Command::ShowWhitespace(Some(true)),
+        ("list", "off") => Command::ShowWhitespace(Some(false)),
+        ("list", _) => invalid("Usage: list [on|off]"),
+        ("cursorline", "") => Command::CursorLine(None),
+        ("cursorline", "on") => Command::CursorLine(Some(true)),
+        ("cursorline", "off") => Command::CursorLine(Some(false)),
+        ("cursorline", _) => invalid("Usage: cursorline [on|off]"),
+        ("ruler", "") => Command::Ruler(None),
+        ("ruler", "on") => Command::Ruler(Some(true)),
+        ("ruler", "off") => Command::Ruler(Some(false)),
+        ("ruler", _) => invalid("Usage: ruler [on|off]"),
+        ("spell", "") => Command::Spell(None),
+        ("spell", "on") => Command::Spell(Some(true)),
+        ("spell", "off") => Command::Spell(Some(false)),
+        ("spell", _) => invalid("Usage: spell [on|off]"),
+        ("spelladd", word) => Command::SpellAdd(word.to_string()),
+        ("colorcolumn", "off") => Command::ColorColumn(None),
+        ("colorcolumn", column) => match column.parse::<usize>() {
+            Ok(column) if column > 0 => Command::ColorColumn(Some(column)),
+            _ => invalid("Usage: colorcolumn <column|off>"),
+        },
+        ("wrapmarker", arg) => Command::WrapMarker(arg.to_string()),
+        ("normjson", "") => Command::NormalizeJson { strip_commas: true, double_quotes: true },
+        ("normjson", "commas") => Command::NormalizeJson { strip_commas: true, double_quotes: false },
+        ("normjson", "quotes") => Command::NormalizeJson { strip_commas: false, double_quotes: true },
+        ("normjson", _) => invalid("Usage: normjson [commas|quotes]"),
+        ("xmlfmt", "") => Command::FormatXml { minify: false },
+        ("xmlfmt", "min") => Command::FormatXml { minify: true },
+        ("model", id) => Command::Model(id.to_string()),
+        ("prompt", "") => invalid("Prompt command requires text or filename."),
+        ("prompt", prompt) => Command::Prompt(prompt.to_string()),
+        ("addcursors", "") => Command::AddCursors,
+        ("replay", "") => Command::Replay(1),
+        ("replay", count) => match count.parse::<usize>() {
+            Ok(count) if count > 0 => Command::Replay(count),
+            _ => invalid("Invalid count."),
+        },
+        _ => Command::Unknown(cmd.to_string()),
+    }
+}
+
+/// Runs a single command line entry against the editor.
+pub fn execute_command(editor: &mut Editor, config: &mut EditorConfig, cmd: &str) -> CommandOutcome {
+    // Typing after a command (which may have moved the cursor) starts a new
+    // undo step
+    editor.edit_group = None;
+    dispatch(editor, config, parse_command(cmd))
+}
+
+/// Runs a parsed command against the editor.
+pub fn dispatch(editor: &mut Editor, config: &mut EditorConfig, command: Command) -> CommandOutcome {
+    match command {
+        Command::Quit => {
+            if editor.read_only {
+                // Restore original document
+                restore_original(editor);
+                CommandOutcome::Message("Returned to document.".to_string())
+            } else if editor.tabs.len() > 1 && !editor.modified {
+                close_tab(editor, config)
+            } else if editor.tabs.len() > 1 {
+                CommandOutcome::Confirm(
+                    "Changes have been made. Close buffer anyway? (y/n)".to_string(),
+                    PromptAction::CloseTab,
+                )
+            } else if !editor.modified {
+                CommandOutcome::Quit
+            } else {
+                CommandOutcome::Confirm(
+                    "Changes have been made. Abort? (y/n)".to_string(),
+                    PromptAction::Quit,
+                )
+            }
+        }
+        Command::ForceQuitAll => CommandOutcome::Quit,
+        Command::QuitAll => {
+            // The document behind a help or list view is the one that matters
+            if editor.read_only && editor.original_buffer.is_some() {
+                restore_original(editor);
+            }
+            if !editor.modified && !editor.tabs.iter().flatten().any(|doc| doc.modified) {
+                CommandOutcome::Quit
+            } else {
+                CommandOutcome::Confirm(
+                    "Unsaved changes. Save all and quit? (y/n, qa! discards)".to_string(),
+                    PromptAction::SaveAndQuit,
+                )
+            }
+        }
+        Command::Save if editor.filename.is_none() => {
+            CommandOutcome::Message("No file name - use 'saveas <path>'.".to_string())
+        }
+        Command::Save if editor.changed_on_disk() => {
+            CommandOutcome::Confirm("File changed on disk. Overwrite? (y/n)".to_string(), PromptAction::Save)
+        }
+        Command::Save => save(editor),
+        Command::SaveAs(path) => save_as(editor, config, &path),
+        Command::TransposeWords => {
+            if editor.read_only {
+                CommandOutcome::Message("Buffer is read-only.".to_string())
+            } else if editor.transpose_words() {
+                CommandOutcome::Continue
+            } else {
+                CommandOutcome::Message("No words to transpose.".to_string())
+            }
+        }
+        Command::Undo => {
+            if editor.undo() {
+                CommandOutcome::Message("Undid last change.".to_string())
+            } else {
+                CommandOutcome::Message("Nothing to undo.".to_string())
+            }
+        }
+        Command::Redo => {
+            if editor.redo() {
+                CommandOutcome::Message("Redid last change.".to_string())
+            } else {
+                CommandOutcome::Message("Nothing to redo.".to_string())
+            }
+        }
+        Command::OpenLineBelow => {
+            editor.open_line_below();
+            editor.focus = Focus::Editor;
+            CommandOutcome::Continue
+        }
+        Command::OpenLineAbove => {
+            editor.open_line_above();
+            editor.focus = Focus::Editor;
+            CommandOutcome::Continue
+        }
+        Command::SelectParagraph => {
+            if editor.select_paragraph() {
+                let (start, end) = (editor.selection_start.unwrap().0, editor.selection_end.unwrap().0);
+                CommandOutcome::Message(format!("Selected lines {}-{}.", start + 1, end + 1))
+            } else {
+                CommandOutcome::Message("Cursor is not in a paragraph.".to_string())
+            }
+        }
+        Command::FoldFunction => match editor.fold_except_current() {
+            Some((start, end)) => CommandOutcome::Message(format!(
+                "Folded {} other blocks, showing lines {}-{}.",
+                editor.folds.len(),
+                start + 1,
+                end + 1
+            )),
+            None => CommandOutcome::Message("Cursor is not in a top-level block.".to_string()),
+        },
+        Command::Unfold => {
+            if editor.unfold_all() {
+                CommandOutcome::Message("All folds opened.".to_string())
+            } else {
+                CommandOutcome::Message("Nothing is folded.".to_string())
+            }
+        }
+        Command::Copy => {
+            if editor.copy_selection() {
+                CommandOutcome::Message(format!("Copied {} lines.", editor.clipboard.len()))
+            } else {
+                CommandOutcome::Message("Nothing selected.".to_string())
+            }
+        }
+        Command::Cut => {
+            if editor.cut_selection() {
+                CommandOutcome::Message(format!("Cut {} lines.", editor.clipboard.len()))
+            } else {
+                CommandOutcome::Message("Nothing selected.".to_string())
+            }
+        }
+        Command::Paste => {
+            if editor.paste() {
+                editor.focus = Focus::Editor;
+                CommandOutcome::Continue
+            } else {
+                CommandOutcome::Message("Nothing to paste.".to_string())
+            }
+        }
+        Command::NumberLines(width) => {
+            let width = width.or(config.number_width);
+            let separator = config.number_separator.as_deref().unwrap_or(" ");
+            match editor.number_lines(width, separator) {
+                0 => CommandOutcome::Message("Buffer is read-only.".to_string()),
+                count => CommandOutcome::Message(format!("Numbered {} lines.", count)),
+            }
+        }
+        Command::UnnumberLines => {
+            let separator = config.number_separator.as_deref().unwrap_or(" ");
+            match editor.unnumber_lines(separator) {
+                0 => CommandOutcome::Message("No numbered lines found.".to_string()),
+                count => CommandOutcome::Message(format!("Removed numbers from {} lines.", count)),
+            }
+        }
+        Command::Extract(args) => extract(editor, config, &args),
+        Command::WriteTo(path) => write_to(editor, &path),
+        Command::ReadFile(path) => read_file(editor, &path),
+        Command::CountDuplicates => {
+            let (start, end) = editor.selected_line_range();
+            let separator = config.uniqc_separator.as_deref().unwrap_or(" ");
+            let lines = format::count_duplicates(&editor.buffer[start..=end], config.uniqc_width, separator);
+            let removed = end - start + 1 - lines.len();
+            if editor.replace_lines(start, end, lines) {
+                editor.deselect();
+                CommandOutcome::Message(format!("Collapsed {} duplicate lines.", removed))
+            } else {
+                CommandOutcome::Message("Buffer is read-only.".to_string())
+            }
+        }
+        Command::AlignAssignments => {
+            let (start, end) = if editor.selection_text().is_some() {
+                editor.selected_line_range()
+            } else {
+                match editor.paragraph_bounds(editor.cursor_y) {
+                    Some(bounds) => bounds,
+                    None => return CommandOutcome::Message("No lines to align.".to_string()),
+                }
+            };
+            let (lines, count) = format::align_assignments(&editor.buffer[start..=end]);
+            if count == 0 {
+                CommandOutcome::Message("No assignments found.".to_string())
+            } else if editor.replace_lines(start, end, lines) {
+                CommandOutcome::Message(format!("Aligned {} lines.", count))
+            } else {
+                CommandOutcome::Message("Buffer is read-only.".to_string())
+            }
+        }
+        Command::Reflow(width) => reflow(editor, config, &width),
+        Command::Resort => match editor.last_sort_specs.clone() {
+            Some(specs) => sort(editor, specs),
+            None => CommandOutcome::Message("No sort to repeat.".to_string()),
+        },
+        Command::Sort(specs) => sort(editor, specs),
+        Command::Upper => transform(editor, |text| text.to_uppercase()),
+        Command::Lower => transform(editor, |text| text.to_lowercase()),
+        Command::Title => transform(editor, format::title_case),
+        Command::Rot13 => transform(editor, format::rot13),
+        Command::Filter(shell) => filter(editor, &shell),
+        Command::ReadShell(shell) => read_shell(editor, &shell),
+        Command::Reverse => {
+            let (start, end) = editor.selected_line_range();
+            let lines = editor.buffer[start..=end].iter().rev().cloned().collect();
+            if editor.replace_lines(start, end, lines) {
+                CommandOutcome::Message(format!("Reversed {} lines.", end - start + 1))
+            } else {
+                CommandOutcome::Message("Buffer is read-only.".to_string())
+            }
+        }
+        Command::Increment(_) if editor.read_only => CommandOutcome::Message("Buffer is read-only.".to_string()),
+        Command::Increment(delta) => match editor.increment_block(delta) {
+            None => CommandOutcome::Message("Select a block first (Ctrl+B).".to_string()),
+            Some((0, 0)) => CommandOutcome::Message("No numbers in the selection.".to_string()),
+            Some((changed, 0)) => CommandOutcome::Message(format!("Changed {} numbers.", changed)),
+            Some((changed, too_wide)) => CommandOutcome::Message(format!(
+                "Changed {} numbers; {} no longer fit and were left alone.",
+                changed, too_wide
+            )),
+        },
+        Command::Scope => match editor.enclosing_braces() {
+            Some((open, close)) => {
+                // Jump to the opening brace, or to the closing one when already there
+                let target = if (editor.cursor_y, editor.cursor_x) == open { close } else { open };
+                editor.cursor_y = target.0;
+                editor.cursor_x = target.1;
+                editor.scroll();
+                editor.scope_braces = Some((open, close));
+                editor.focus = Focus::Editor;
+                CommandOutcome::Message(format!("Block lines {}-{}.", open.0 + 1, close.0 + 1))
+            }
+            None => CommandOutcome::Message("Cursor is not inside a { } block.".to_string()),
+        },
+        Command::MatchBracket => {
+            if editor.jump_to_matching_bracket() {
+                editor.focus = Focus::Editor;
+                CommandOutcome::Continue
+            } else {
+                CommandOutcome::Message("No matching bracket.".to_string())
+            }
+        }
+        Command::AlignView(align) => {
+            editor.align_view(align);
+            editor.focus = Focus::Editor;
+            CommandOutcome::Continue
+        }
+        Command::ToggleLineNumbers => {
+            editor.show_line_numbers = !editor.show_line_numbers;
+            CommandOutcome::Message("Line numbers toggled.".to_string())
+        }
+        Command::ToggleRelativeNumbers => {
+            editor.relative_numbers = !editor.relative_numbers;
+            let state = if editor.relative_numbers { "on" } else { "off" };
+            CommandOutcome::Message(format!("Relative line numbers {}.", state))
+        }
+        Command::Goto(line_num) => {
+            if line_num >= 1 && line_num <= editor.buffer.len() {
+                editor.cursor_y = line_num - 1;
+                editor.cursor_x = 0;
+                // Adjust scroll_y to make the line visible
+                if editor.cursor_y < editor.scroll_y {
+                    editor.scroll_y = editor.cursor_y;
+                } else if editor.cursor_y >= editor.scroll_y + editor.editor_visible_height {
+                    editor.scroll_y = editor.cursor_y - editor.editor_visible_height + 1;
+                }
+                editor.focus = Focus::Editor;
+                CommandOutcome::Message(format!("Jumped to line {}", line_num))
+            } else {
+                CommandOutcome::Message("Line number out of range.".to_string())
+            }
+        }
+        Command::Find { text, case_sensitive } => {
+            if editor.find(&text, SearchScope::All, case_sensitive) {
+                editor.focus = Focus::Editor;
+                let case_text = if case_sensitive { "case-sensitive" } else { "case-insensitive" };
+                CommandOutcome::Message(format!(
+                    "Found {} matches for '{}' ({})",
+                    editor.search_matches.len(),
+                    text,
+                    case_text
+                ))
+            } else {
+                CommandOutcome::Message("No matches found.".to_string())
+            }
+        }
+        Command::Replace { text, with, case_sensitive, all } => {
+            if editor.read_only {
+                return CommandOutcome::Message("Return to the document first ('q').".to_string());
+            }
+            let scope = if editor.selection_mode == SelectionMode::Block && editor.selection_start.is_some() {
+                SearchScope::Block
+            } else {
+                SearchScope::All
+            };
+            let count = editor.replace(&text, &with, scope, all, case_sensitive);
+            if count == 0 {
+                return CommandOutcome::Message("No matches found.".to_string());
+            }
+            editor.focus = Focus::Editor;
+            if all {
+                CommandOutcome::Message(format!("Replaced {} matches of '{}'.", count, text))
+            } else {
+                CommandOutcome::Message(format!(
+                    "Found {} matches for '{}'; F1 replaces the one at the cursor.",
+                    count, text
+                ))
+            }
+        }
+        Command::Open(path) => open_file(editor, config, &path),
+        Command::Alternate => open_alternate(editor, config),
+        Command::NextTab => cycle_tab(editor, config, 1),
+        Command::PrevTab => cycle_tab(editor, config, -1),
+        Command::Split(arg) => split_view(editor, &arg),
+        Command::Unsplit => {
+            editor.split = None;
+            CommandOutcome::Continue
+        }
+        Command::CloseTab => {
+            if editor.read_only {
+                CommandOutcome::Message("Return to the document first ('q').".to_string())
+            } else {
+                close_tab(editor, config)
+            }
+        }
+        Command::Reopen => reopen(editor, config),
+        Command::ListCommands => list_commands(editor),
+        Command::ReloadConfig => reload_config(editor, config),
+        Command::Help => {
+            // Load help text
+            match std::fs::read_to_string("help/help.txt") {
+                Ok(content) => {
+                    open_view(editor, content.lines().map(|s| s.to_string()).collect(), None);
+                    CommandOutcome::Message("Help mode - use 'q' to return to document".to_string())
+                }
+                Err(_) => CommandOutcome::Message("Help file not found.".to_string()),
+            }
+        }
+        Command::Messages => show_messages(editor),
+        Command::Hex(path) => show_hex(editor, &path),
+        Command::Diff(path) => diff_with_file(editor, &path),
+        Command::Symbols => show_symbols(editor, config),
+        Command::Syntax(name) => set_syntax(editor, config, &name),
+        Command::Wrap(state) => {
+            editor.soft_wrap = state.unwrap_or(!editor.soft_wrap);
+            editor.scroll();
+            let state = if editor.soft_wrap { "on" } else { "off" };
+            CommandOutcome::Message(format!("Soft wrap {}.", state))
+        }
+        Command::VirtualCursor(state) => {
+            editor.set_virtual_cursor(state.unwrap_or(!editor.virtual_cursor));
+            let state = if editor.virtual_cursor { "on" } else { "off" };
+            CommandOutcome::Message(format!("Virtual cursor {}.", state))
+        }
+        Command::ShowWhitespace(state) => {
+            editor.show_whitespace = state.unwrap_or(!editor.show_whitespace);
+            let state = if editor.show_whitespace { "on" } else { "off" };
+            CommandOutcome::Message(format!("Whitespace display {}.", state))
+        }
+        Command::CursorLine(state) => {
+            editor.cursor_line = state.unwrap_or(!editor.cursor_line);
+            let state = if editor.cursor_line { "on" } else { "off" };
+            CommandOutcome::Message(format!("Cursor line highlight {}.", state))
+        }
+        Command::Ruler(state) => {
+            editor.show_ruler = state.unwrap_or(!editor.show_ruler);
+            let state = if editor.show_ruler { "on" } else { "off" };
+            CommandOutcome::Message(format!("Ruler {}.", state))
+        }
+        Command::Spell(state) => {
+            editor.spell = Some(state.unwrap_or(!editor.spell_enabled()));
+            if editor.spell_enabled() {
+                if let Err(e) = editor.spell_checker() {
+                    return CommandOutcome::Message(e);
+                }
+            }
+            let state = if editor.spell_enabled() { "on" } else { "off" };
+            CommandOutcome::Message(format!("Spell checking {}.", state))
+        }
+        Command::SpellAdd(word) => {
+            let word = (!word.is_empty()).then_some(word.as_str());
+            CommandOutcome::Message(editor.accept_word(word))
+        }
+        Command::ColorColumn(column) => {
+            editor.color_column = column;
+            match column {
+                Some(column) => CommandOutcome::Message(format!("Column guide at {}.", column)),
+                None => CommandOutcome::Message("Column guide off.".to_string()),
+            }
+        }
+        Command::WrapMarker(arg) => {
+            match arg.as_str() {
+                "" => editor.show_wrap_marker = !editor.show_wrap_marker,
+                "on" => editor.show_wrap_marker = true,
+                "off" => editor.show_wrap_marker = false,
+                glyph => {
+                    editor.wrap_marker = glyph.to_string();
+                    editor.show_wrap_marker = true;
+                }
+            }
+            let state = if editor.show_wrap_marker { "on" } else { "off" };
+            CommandOutcome::Message(format!(
+                "Wrap marker '{}' ({}) {}.",
+                editor.wrap_marker, editor.wrap_marker_color, state
+            ))
+        }
+        Command::NormalizeJson { strip_commas, double_quotes } => {
+            let (start, end) = editor.selected_line_range();
+            let text = editor.buffer[start..=end].join("\n");
+            match format::normalize_json(&text, strip_commas, double_quotes) {
+                Ok(result) if result == text => CommandOutcome::Message("JSON already normalized.".to_string()),
+                Ok(result) => {
+                    if editor.replace_lines(start, end, result.lines().map(|s| s.to_string()).collect()) {
+                        CommandOutcome::Message("JSON normalized.".to_string())
+                    } else {
+                        CommandOutcome::Message("Buffer is read-only.".to_string())
+                    }
+                }
+                Err(e) if start > 0 => CommandOutcome::Message(format!("{} (counting from line {})", e, start + 1)),
+                Err(e) => CommandOutcome::Message(e),
+            }
+        }
+        Command::FormatXml { minify } => {
+            let (start, end) = editor.selected_line_range();
+            let text = editor.buffer[start..=end].join("\n");
+            let result = if minify {
+                format::minify_xml(&text)
+            } else {
+                format::format_xml(&text, editor.tab_width)
+            };
+            match result {
+                Ok(result) if result == text => CommandOutcome::Message("XML already formatted.".to_string()),
+                Ok(result) => {
+                    if editor.replace_lines(start, end, result.lines().map(|s| s.to_string()).collect()) {
+                        editor.deselect();
+                        CommandOutcome::Message("XML formatted.".to_string())
+                    } else {
+                        CommandOutcome::Message("Buffer is read-only.".to_string())
+                    }
+                }
+                Err(e) if start > 0 => CommandOutcome::Message(format!("{} (counting from line {})", e, start + 1)),
+                Err(e) => CommandOutcome::Message(e),
+            }
+        }
+        Command::Model(id) => switch_model(config, &id),
+        Command::Prompt(prompt_arg) => send_prompt(editor, config, prompt_arg),
+        Command::Replay(count) => replay(editor, count),
+        Command::AddCursors => match editor.add_cursors_at_matches() {
+            0 => CommandOutcome::Message("Select text within one line first.".to_string()),
+            count => {
+                editor.focus = Focus::Editor;
+                CommandOutcome::Message(format!("{} cursors.", count))
+            }
+        },
+        Command::Invalid(message) => CommandOutcome::Message(message),
+        Command::Unknown(cmd) => CommandOutcome::Message(format!("Unknown command: {}", cmd)),
+    }
+}
+
+/// `prompt "text"` or `prompt <file>`: sends the selection, or the whole
+/// buffer, to the model on a background thread.
+fn send_prompt(editor: &mut Editor, config: &EditorConfig, prompt_arg: String) -> CommandOutcome {
+    // Only the selected region is sent when there is one
+    let (scope, text) = editor
+        .selection_text()
+        .unwrap_or_else(|| (AiScope::Buffer, editor.buffer.join("\n")));
+    editor.ai_scope = scope;
+    let (tx, rx) = mpsc::channel();
+    editor.ai_response_receiver = Some(rx);
+    editor.ai_status = AiStatus::InProgress {
+        start_time: Instant::now(),
+        spinner_state: 0,
+        received: String::new(),
+    };
+
+    let thread_config = config.clone();
+
+    thread::spawn(move || {
+        let chunk_tx = tx.clone();
+        let mut on_chunk = |chunk: &str| {
+            let _ = chunk_tx.send(AiEvent::Chunk(chunk.to_string()));
+        };
+        let result = if prompt_arg.starts_with('"') && prompt_arg.ends_with('"') {
+            let user_prompt = &prompt_arg[1..prompt_arg.len() - 1];
+            ai::send_prompt_with_system(&thread_config, None, user_prompt, &text, &mut on_chunk)
+        } else {
+            match load_prompt_file(&prompt_arg, &config::prompt_dirs(&thread_config)) {
+                Ok((system_prompt, user_prompt)) => {
+                    let final_user_prompt = user_prompt.replace("{{TEXT}}", &text);
+                    ai::send_prompt_with_system(
+                        &thread_config,
+                        Some(&system_prompt),
+                        &final_user_prompt,
+                        "",
+                        &mut on_chunk,
+                    )
+                }
+                Err(e) => Err(e),
+            }
+        };
+        let _ = tx.send(AiEvent::Done(result.map_err(|e| e.to_string())));
+    });
+    CommandOutcome::Continue
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> EditorConfig {
+        toml::from_str("theme = \"base16-ocean.dark\"\ntab_width = 4\n[syntax_map]\n").unwrap()
+    }
+
+    fn run(text: &str, cmd: &str) -> (Editor, CommandOutcome) {
+        let mut config = config();
+        let mut editor = Editor::new(text, &config);
+        let outcome = execute_command(&mut editor, &mut config, cmd);
+        (editor, outcome)
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("vedit-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn goto_moves_to_the_line() {
+        let (editor, outcome) = run("one\ntwo\nthree", "goto 3");
+        assert_eq!(outcome, CommandOutcome::Message("Jumped to line 3".to_string()));
+        assert_eq!((editor.cursor_y, editor.cursor_x), (2, 0));
+        assert!(editor.focus == Focus::Editor);
+    }
+
+    #[test]
+    fn goto_out_of_range_leaves_the_cursor() {
+        let (editor, outcome) = run("one\ntwo", "goto 3");
+        assert_eq!(outcome, CommandOutcome::Message("Line number out of range.".to_string()));
+        assert_eq!(editor.cursor_y, 0);
+        let (_, outcome) = run("one", "goto x");
+        assert_eq!(outcome, CommandOutcome::Message("Invalid line number.".to_string()));
+    }
+
+    #[test]
+    fn find_moves_to_the_first_match() {
+        let (editor, outcome) = run("alpha\nbeta alpha\nALPHA", "find \"alpha\"");
+        assert_eq!(outcome, CommandOutcome::Message("Found 2 matches for 'alpha' (case-sensitive)".to_string()));
+        assert_eq!(editor.search_matches, vec![(0, 0, 5), (1, 5, 10)]);
+
+        let (editor, _) = run("beta\nbeta alpha\nALPHA", "find 'alpha' ins");
+        assert_eq!(editor.search_matches.len(), 2);
+        assert_eq!((editor.cursor_y, editor.cursor_x), (1, 5));
+
+        let (_, outcome) = run("beta", "find \"alpha\"");
+        assert_eq!(outcome, CommandOutcome::Message("No matches found.".to_string()));
+    }
+
+    #[test]
+    fn save_writes_the_buffer_and_clears_modified() {
+        let path = temp_path("save.txt");
+        let mut config = config();
+        let mut editor = Editor::new("one\ntwo", &config);
+        editor.filename = Some(path.display().to_string());
+        editor.modified = true;
+        let outcome = execute_command(&mut editor, &mut config, "s");
+        assert_eq!(outcome, CommandOutcome::Message("File saved.".to_string()));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo");
+        assert!(!editor.modified);
+        fs::remove_file(&path).unwrap();
+
+        let (_, outcome) = run("text", "s");
+        assert_eq!(outcome, CommandOutcome::Message("No file name - use 'saveas <path>'.".to_string()));
+    }
+
+    #[test]
+    fn views_refuse_to_be_saved_over_the_document() {
+        let path = temp_path("view.txt");
+        fs::write(&path, "one").unwrap();
+        let mut config = config();
+        let mut editor = Editor::new("one", &config);
+        editor.filename = Some(path.display().to_string());
+        editor.buffer = vec!["two".to_string()];
+        editor.modified = true;
+        editor.show_message("Edited.".to_string());
+        execute_command(&mut editor, &mut config, "messages");
+        assert!(editor.read_only);
+
+        let refused = CommandOutcome::Message("Return to the document first ('q').".to_string());
+        assert_eq!(execute_command(&mut editor, &mut config, "s"), refused);
+        let other = temp_path("view-other.txt");
+        assert_eq!(execute_command(&mut editor, &mut config, &format!("saveas {}", other.display())), refused);
+        assert!(save_all(&mut editor).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one");
+        assert!(!other.exists());
+
+        execute_command(&mut editor, &mut config, "q");
+        assert_eq!(execute_command(&mut editor, &mut config, "s"), CommandOutcome::Message("File saved.".to_string()));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "two");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn binary_files_open_as_a_hex_view_that_cannot_be_saved() {
+        let text = temp_path("hex-text.txt");
+        let binary = temp_path("hex-binary.bin");
+        fs::write(&text, "one\n").unwrap();
+        fs::write(&binary, [0xffu8, 0x00, 0x41]).unwrap();
+        let mut config = config();
+        let mut editor = Editor::new("one", &config);
+        editor.filename = Some(text.display().to_string());
+        let outcome = execute_command(&mut editor, &mut config, &format!("open {}", binary.display()));
+        assert_eq!(outcome, CommandOutcome::Message(format!("{}: 3 bytes - 'q' to return", binary.display())));
+        assert!(editor.read_only);
+
+        let outcome = execute_command(&mut editor, &mut config, "s");
+        assert_eq!(outcome, CommandOutcome::Message("Return to the document first ('q').".to_string()));
+        assert_eq!(fs::read_to_string(&text).unwrap(), "one\n");
+        fs::remove_file(&text).unwrap();
+        fs::remove_file(&binary).unwrap();
+    }
+
+    #[test]
+    fn write_saves_the_buffer_to_another_path() {
+        let path = temp_path("write.txt");
+        let _ = fs::remove_file(&path);
+        let cmd = format!("w {}", path.display());
+        let (mut editor, outcome) = run("one\ntwo", &cmd);
+        assert_eq!(outcome, CommandOutcome::Message(format!("Wrote 2 lines to {}.", path.display())));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo");
+        assert!(!editor.modified);
+
+        // A second write asks before overwriting
+        let mut config = config();
+        let outcome = execute_command(&mut editor, &mut config, &cmd);
+        assert!(matches!(outcome, CommandOutcome::Confirm(_, PromptAction::WriteTo { .. })));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unknown_commands_are_reported() {
+        let (editor, outcome) = run("text", "frobnicate now");
+        assert_eq!(outcome, CommandOutcome::Message("Unknown command: frobnicate now".to_string()));
+        assert_eq!(editor.buffer, vec!["text"]);
+        assert!(!editor.modified);
+    }
+
+    #[test]
+    fn normjson_leaves_the_buffer_alone_on_errors() {
+        let (editor, outcome) = run("{\"a\": [1,],}", "normjson");
+        assert_eq!(outcome, CommandOutcome::Message("JSON normalized.".to_string()));
+        assert_eq!(editor.buffer, vec!["{\"a\": [1]}"]);
+
+        let (editor, outcome) = run("{\"a\": [1,}", "normjson");
+        assert!(matches!(outcome, CommandOutcome::Message(m) if m.starts_with("JSON error:")));
+        assert_eq!(editor.buffer, vec!["{\"a\": [1,}"]);
+        assert!(!editor.modified);
+    }
+
+    #[test]
+    fn reopen_restores_a_closed_buffer_with_its_cursor_and_undo() {
+        let path = temp_path("reopen.txt");
+        fs::write(&path, "first\nsecond\nthird").unwrap();
+        let mut config = config();
+        let mut editor = Editor::new("main", &config);
+        let open = format!("open {}", path.display());
+        assert_eq!(
+            execute_command(&mut editor, &mut config, &open),
+            CommandOutcome::Message(format!("Opened {}", path.display()))
+        );
+        editor.cursor_y = 2;
+        editor.cursor_x = 3;
+        editor.type_char('X');
+        editor.cursor_y = 1;
+        editor.cursor_x = 4;
+
+        assert_eq!(
+            execute_command(&mut editor, &mut config, "bclose"),
+            CommandOutcome::Message(format!("Closed {}", path.display()))
+        );
+        assert_eq!(editor.buffer, vec!["main"]);
+        assert_eq!(editor.tabs.len(), 1);
+
+        assert_eq!(
+            execute_command(&mut editor, &mut config, "reopen"),
+            CommandOutcome::Message(format!("Reopened {}", path.display()))
+        );
+        assert_eq!(editor.buffer, vec!["first", "second", "thiXd"]);
+        assert_eq!((editor.cursor_y, editor.cursor_x), (1, 4));
+        assert_eq!(editor.active_tab, 1);
+        assert!(editor.modified);
+        assert!(editor.undo());
+        assert_eq!(editor.buffer, vec!["first", "second", "third"]);
+
+        assert_eq!(
+            execute_command(&mut editor, &mut config, "reopen"),
+            CommandOutcome::Message("No closed documents.".to_string())
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn scope_jumps_between_the_enclosing_braces() {
+        let mut config = config();
+        let mut editor = Editor::new("a {\n  b {\n    c\n  }\n}", &config);
+        (editor.cursor_y, editor.cursor_x) = (2, 4);
+        let outcome = execute_command(&mut editor, &mut config, "scope");
+        assert_eq!(outcome, CommandOutcome::Message("Block lines 2-4.".to_string()));
+        assert_eq!((editor.cursor_y, editor.cursor_x), (1, 4));
+        assert_eq!(editor.scope_braces, Some(((1, 4), (3, 2))));
+        execute_command(&mut editor, &mut config, "scope");
+        assert_eq!((editor.cursor_y, editor.cursor_x), (3, 2));
+
+        let (_, outcome) = run("no braces", "scope");
+        assert_eq!(outcome, CommandOutcome::Message("Cursor is not inside a { } block.".to_string()));
+    }
+
+    #[test]
+    fn resort_reapplies_the_last_sort_after_edits() {
+        let mut config = config();
+        let mut editor = Editor::new("b 2\na 10\nb 1", &config);
+        let outcome = execute_command(&mut editor, &mut config, "sort 1-1 3-4,num");
+        assert_eq!(outcome, CommandOutcome::Message("Sorted 3 lines by 1-1 3-4n.".to_string()));
+        assert_eq!(editor.buffer, vec!["a 10", "b 1", "b 2"]);
+
+        editor.buffer.insert(1, "c 0".to_string());
+        editor.buffer.push("a 3".to_string());
+        let outcome = execute_command(&mut editor, &mut config, "resort");
+        assert_eq!(outcome, CommandOutcome::Message("Sorted 5 lines by 1-1 3-4n.".to_string()));
+        assert_eq!(editor.buffer, vec!["a 3", "a 10", "b 1", "b 2", "c 0"]);
+        editor.undo();
+        assert_eq!(editor.buffer, vec!["a 10", "c 0", "b 1", "b 2", "a 3"]);
+    }
+
+    #[test]
+    fn resort_needs_an_earlier_sort() {
+        let (editor, outcome) = run("b\na", "resort");
+        assert_eq!(outcome, CommandOutcome::Message("No sort to repeat.".to_string()));
+        assert_eq!(editor.buffer, vec!["b", "a"]);
+    }
+
+    #[test]
+    fn remembered_specs_parse_back_to_the_same_specs() {
+        let specs = parse_sort_specs("1-3,desc 5-8,num 9-9n,asc").unwrap();
+        assert_eq!(specs, vec![(0, 3, false, false), (4, 8, true, true), (8, 9, true, true)]);
+        assert_eq!(parse_sort_specs(&Editor::format_sort_specs(&specs)), Some(specs));
+    }
+
+    fn default_rules() -> Vec<(String, String)> {
+        DEFAULT_ALTERNATE_SUFFIXES.iter().map(|(a, b)| (a.to_string(), b.to_string())).collect()
+    }
+
+    #[test]
+    fn alternate_candidates_pair_both_ways() {
+        let rules = default_rules();
+        assert_eq!(alternate_candidates("src/foo.c", &rules), vec!["src/foo.h"]);
+        assert_eq!(alternate_candidates("src/foo.h", &rules), vec!["src/foo.c", "src/foo.cc"]);
+        assert_eq!(alternate_candidates("app.test.ts", &rules), vec!["app.ts", "app.test.test.ts"]);
+    }
+
+    #[test]
+    fn alternate_candidates_prefer_the_longest_suffix() {
+        let rules = default_rules();
+        assert_eq!(alternate_candidates("foo_test.rs", &rules), vec!["foo.rs", "foo_test_test.rs"]);
+        assert_eq!(alternate_candidates("foo.rs", &rules), vec!["foo_test.rs"]);
+    }
+
+    #[test]
+    fn alternate_candidates_need_a_stem_and_a_rule() {
+        let rules = default_rules();
+        assert!(alternate_candidates(".c", &rules).is_empty());
+        assert!(alternate_candidates("src/.h", &rules).is_empty());
+        assert!(alternate_candidates("notes.md", &rules).is_empty());
+        let custom = vec![("Controller.php".to_string(), "View.php".to_string())];
+        assert_eq!(alternate_candidates("UserController.php", &custom), vec!["UserView.php"]);
+    }
+
+    #[test]
+    fn alt_opens_the_existing_counterpart() {
+        let (source, header) = (temp_path("alt.c"), temp_path("alt.h"));
+        std::fs::write(&source, "int f(void) { return 1; }\n").unwrap();
+        std::fs::write(&header, "int f(void);\n").unwrap();
+        let mut config = config();
+        let mut editor = Editor::new("", &config);
+        execute_command(&mut editor, &mut config, &format!("open {}", source.display()));
+        execute_command(&mut editor, &mut config, "alt");
+        assert_eq!(editor.filename.as_deref(), Some(header.to_str().unwrap()));
+        assert_eq!(editor.buffer, vec!["int f(void);"]);
+        std::fs::remove_file(&source).unwrap();
+        std::fs::remove_file(&header).unwrap();
+    }
+
+    #[test]
+    fn saving_adds_no_undo_steps() {
+        let path = temp_path("save-undo.txt");
+        let mut editor = Editor::new("", &config());
+        editor.filename = Some(path.to_str().unwrap().to_string());
+        save_file(&mut editor).unwrap();
+        save_file(&mut editor).unwrap();
+        assert_eq!((editor.undo_index, editor.undo_history.len()), (0, 1));
+        assert!(!editor.undo());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn undo_after_saving_returns_to_the_saved_text() {
+        let path = temp_path("save-multibyte.txt");
+        let mut editor = Editor::new("héllo 漢", &config());
+        editor.filename = Some(path.to_str().unwrap().to_string());
+        editor.cursor_x = 8;
+        editor.type_char('!');
+        save_file(&mut editor).unwrap();
+        let depth = (editor.undo_index, editor.undo_history.len());
+        save_file(&mut editor).unwrap();
+        assert_eq!((editor.undo_index, editor.undo_history.len()), depth);
+
+        editor.type_char('?');
+        assert!(editor.modified);
+        assert!(editor.undo());
+        assert_eq!(editor.buffer, vec!["héllo 漢!"]);
+        assert!(!editor.modified);
+        assert!(editor.undo());
+        assert_eq!(editor.buffer, vec!["héllo 漢"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replace_all_rewrites_every_match_as_one_undo_step() {
+        let (mut editor, outcome) = run("cat cat\ndog\nCat", "replace \"cat\" \"bird\" all");
+        assert_eq!(outcome, CommandOutcome::Message("Replaced 2 matches of 'cat'.".to_string()));
+        assert_eq!(editor.buffer, vec!["bird bird", "dog", "Cat"]);
+        assert!(editor.modified);
+        editor.undo();
+        assert_eq!(editor.buffer, vec!["cat cat", "dog", "Cat"]);
+
+        let (editor, _) = run("cat Cat", "replace \"cat\" \"x\" ins all");
+        assert_eq!(editor.buffer, vec!["x x"]);
+    }
+
+    #[test]
+    fn replace_steps_through_matches_with_replace_next() {
+        let (mut editor, outcome) = run("a-a\na", "replace \"a\" \"bb\"");
+        assert_eq!(
+            outcome,
+            CommandOutcome::Message("Found 3 matches for 'a'; F1 replaces the one at the cursor.".to_string())
+        );
+        assert_eq!(editor.buffer, vec!["a-a", "a"]);
+        assert!(editor.replace_next());
+        assert_eq!(editor.buffer, vec!["bb-a", "a"]);
+        assert_eq!((editor.cursor_y, editor.cursor_x), (0, 3));
+        editor.replace_next();
+        editor.replace_next();
+        assert_eq!(editor.buffer, vec!["bb-bb", "bb"]);
+        assert_eq!(editor.replace_text, None);
+        assert!(!editor.replace_next());
+    }
+
+    #[test]
+    fn replace_rejects_bad_usage() {
+        let (editor, outcome) = run("a", "replace \"a\"");
+        assert!(matches!(outcome, CommandOutcome::Message(m) if m.starts_with("Usage: replace")));
+        assert_eq!(editor.buffer, vec!["a"]);
+        let (_, outcome) = run("a", "replace \"b\" \"c\" all");
+        assert_eq!(outcome, CommandOutcome::Message("No matches found.".to_string()));
+    }
+
+    #[test]
+    fn uniqc_collapses_the_buffer_as_one_undo_step() {
+        let (mut editor, outcome) = run("x\nx\ny", "uniqc");
+        assert_eq!(outcome, CommandOutcome::Message("Collapsed 1 duplicate lines.".to_string()));
+        assert_eq!(editor.buffer, vec!["2 x", "1 y"]);
+        editor.undo();
+        assert_eq!(editor.buffer, vec!["x", "x", "y"]);
+    }
+
+    #[test]
+    fn option_commands_set_and_toggle() {
+        let mut config = config();
+        let mut editor = Editor::new("text", &config);
+        let outcome = execute_command(&mut editor, &mut config, "colorcolumn 80");
+        assert_eq!(outcome, CommandOutcome::Message("Column guide at 80.".to_string()));
+        assert_eq!(editor.color_column, Some(80));
+        execute_command(&mut editor, &mut config, "colorcolumn off");
+        assert_eq!(editor.color_column, None);
+
+        let wrap = editor.soft_wrap;
+        execute_command(&mut editor, &mut config, "wrap");
+        assert_eq!(editor.soft_wrap, !wrap);
+        let outcome = execute_command(&mut editor, &mut config, "wrap off");
+        assert_eq!(outcome, CommandOutcome::Message("Soft wrap off.".to_string()));
+        assert!(!editor.soft_wrap);
+    }
+
+    #[test]
+    fn make_backup_keeps_the_previous_version() {
+        let path = temp_path("backup.txt");
+        let backup = format!("{}~", path.display());
+        std::fs::write(&path, "old\n").unwrap();
+        let mut config = config();
+        let mut editor = Editor::new("", &config);
+        execute_command(&mut editor, &mut config, &format!("open {}", path.display()));
+        editor.make_backup = true;
+        editor.buffer = vec!["new".to_string()];
+        assert_eq!(save_file(&mut editor).unwrap(), None);
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), "old\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new\n");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
+
+    #[test]
+    fn save_all_reports_failed_backups() {
+        let path = temp_path("backup-fail.txt");
+        let backup = format!("{}~", path.display());
+        std::fs::write(&path, "old").unwrap();
+        // A directory where the backup should go makes the copy fail
+        std::fs::create_dir(&backup).unwrap();
+        let mut config = config();
+        let mut editor = Editor::new("", &config);
+        execute_command(&mut editor, &mut config, &format!("open {}", path.display()));
+        editor.make_backup = true;
+        editor.buffer = vec!["new".to_string()];
+        editor.modified = true;
+        let warnings = save_all(&mut editor).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with(&format!("Backup to {} failed", backup)));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_dir(&backup).unwrap();
+    }
+
+    #[test]
+    fn a_new_file_has_nothing_to_back_up() {
+        let path = temp_path("backup-new.txt");
+        assert_eq!(backup_file(path.to_str().unwrap()), None);
+        assert!(!Path::new(&format!("{}~", path.display())).exists());
+    }
+
+    #[test]
+    fn parse_command_accepts_aliases() {
+        assert_eq!(parse_command("q"), Command::Quit);
+        assert_eq!(parse_command("quit"), Command::Quit);
+        assert_eq!(parse_command("s"), Command::Save);
+        assert_eq!(parse_command("save"), Command::Save);
+        assert_eq!(parse_command("qa!"), Command::ForceQuitAll);
+        assert_eq!(parse_command("quitall"), Command::QuitAll);
+        assert_eq!(parse_command("zz"), Command::AlignView(ViewAlign::Center));
+        assert_eq!(parse_command("center"), Command::AlignView(ViewAlign::Center));
+        assert_eq!(parse_command("r notes.txt"), Command::ReadFile("notes.txt".to_string()));
+        assert_eq!(parse_command("read notes.txt"), Command::ReadFile("notes.txt".to_string()));
+        assert_eq!(parse_command("filter sort -u"), parse_command("!sort -u"));
+    }
+
+    #[test]
+    fn parse_command_reads_arguments() {
+        assert_eq!(parse_command("  goto 12  "), Command::Goto(12));
+        assert_eq!(parse_command("numberlines 4"), Command::NumberLines(Some(4)));
+        assert_eq!(parse_command("incr -3"), Command::Increment(-3));
+        assert_eq!(parse_command("wrap off"), Command::Wrap(Some(false)));
+        assert_eq!(parse_command("colorcolumn 80"), Command::ColorColumn(Some(80)));
+        assert_eq!(parse_command("saveas my file.txt"), Command::SaveAs("my file.txt".to_string()));
+        assert_eq!(parse_command("r!date -u"), Command::ReadShell("date -u".to_string()));
+        assert_eq!(parse_command("sort 1-3,desc"), Command::Sort(vec![(0, 3, false, false)]));
+        assert_eq!(
+            parse_command("find \"a b\" ins"),
+            Command::Find { text: "a b".to_string(), case_sensitive: false }
+        );
+        assert_eq!(
+            parse_command("replace \"é\" \"e\" all"),
+            Command::Replace { text: "é".to_string(), with: "e".to_string(), case_sensitive: true, all: true }
+        );
+    }
+
+    #[test]
+    fn parse_command_reports_missing_and_bad_arguments() {
+        let invalid = |message: &str| Command::Invalid(message.to_string());
+        assert_eq!(parse_command("w"), invalid("Usage: w <path>"));
+        assert_eq!(parse_command("!"), invalid("Usage: !<shell command>"));
+        assert_eq!(parse_command("r"), invalid("Usage: read <path>"));
+        assert_eq!(parse_command("read  "), invalid("Usage: read <path>"));
+        assert_eq!(parse_command("goto x"), invalid("Invalid line number."));
+        assert_eq!(parse_command("numberlines wide"), invalid("Invalid width."));
+        assert_eq!(parse_command("colorcolumn 0"), invalid("Usage: colorcolumn <column|off>"));
+        assert_eq!(parse_command("replay 0"), invalid("Invalid count."));
+        assert_eq!(parse_command("sort 3-1"), invalid("Usage: sort <from>-<to>[,asc|,desc][,num] ..."));
+        assert_eq!(parse_command("replace \"a\""), invalid("Usage: replace \"text\" \"new text\" [ins] [all]"));
+        assert_eq!(parse_command("wrap maybe"), invalid("Usage: wrap [on|off]"));
+    }
+
+    #[test]
+    fn parse_command_leaves_unknown_names_and_extra_arguments_unknown() {
+        assert_eq!(parse_command("frobnicate"), Command::Unknown("frobnicate".to_string()));
+        assert_eq!(parse_command(""), Command::Unknown(String::new()));
+        assert_eq!(parse_command("Q"), Command::Unknown("Q".to_string()));
+        assert_eq!(parse_command("undo twice"), Command::Unknown("undo twice".to_string()));
+        assert_eq!(parse_command("goto"), Command::Unknown("goto".to_string()));
+        assert_eq!(parse_command("find nothing quoted"), Command::Unknown("find nothing quoted".to_string()));
+    }
+
+    fn save_round_trip(name: &str, contents: &str) -> String {
+        let path = temp_path(name);
+        std::fs::write(&path, contents).unwrap();
+        let mut config = config();
+        let mut editor = Editor::new("", &config);
+        execute_command(&mut editor, &mut config, &format!("open {}", path.display()));
+        save_file(&mut editor).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        saved
+    }
+
+    #[test]
+    fn saving_keeps_the_final_newline_or_its_absence() {
+        assert_eq!(save_round_trip("newline.txt", "one\ntwo\n"), "one\ntwo\n");
+        assert_eq!(save_round_trip("no-newline.txt", "one\ntwo"), "one\ntwo");
+        assert_eq!(save_round_trip("multibyte.txt", "héllo\n漢字\n"), "héllo\n漢字\n");
+    }
+
+    #[test]
+    fn saving_an_empty_file_or_a_lone_newline() {
+        assert_eq!(save_round_trip("empty.txt", ""), "");
+        assert_eq!(save_round_trip("lone-newline.txt", "\n"), "\n");
+    }
+
+    #[test]
+    fn a_new_buffer_is_saved_without_a_final_newline() {
+        let path = temp_path("new-buffer.txt");
+        let mut editor = Editor::new("", &config());
+        editor.filename = Some(path.to_str().unwrap().to_string());
+        editor.type_char('x');
+        save_file(&mut editor).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "x");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,9 +1,12 @@
-use serde::Deserialize;
+use crate::logfile;
+use serde::{Deserialize, Serialize};
+use std::env;
 use std::fs;
+use std::path::PathBuf;
 
 use std::collections::HashMap;
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum Provider {
     AnythingLLM,
@@ -27,6 +30,7 @@ pub struct ModelConfig {
     pub max_tokens: Option<usize>,
     pub temperature: Option<f32>,
     pub timeout_ms: Option<u64>,
+    pub stream: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -35,24 +39,206 @@ pub struct AiConfig {
     pub max_tokens_default: Option<usize>,
     pub temperature_default: Option<f32>,
     pub timeout_ms_default: Option<u64>,
+    pub max_retries: Option<u32>,
+    pub log_path: Option<String>,
+    pub log_max_bytes: Option<u64>,
+    pub prompt_dir: Option<String>,
     pub models: Vec<ModelConfig>,
 }
 
+/// Background colors from the `[colors]` section, as names ("blue"),
+/// `#rrggbb` or 0-255 indices. Unset ones keep the built-in colors.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ColorsConfig {
+    pub selection_bg: Option<String>,
+    pub block_bg: Option<String>,
+    pub cursor_line_bg: Option<String>,
+    pub color_column_bg: Option<String>,
+    pub diff_context_bg: Option<String>,
+    pub diff_added_bg: Option<String>,
+    pub diff_removed_bg: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct EditorConfig {
     pub theme: String,
     pub tab_width: usize,
+    pub tab_width_by_syntax: Option<HashMap<String, usize>>,
     pub syntax_map: HashMap<String, String>,
+    pub shebang_map: Option<HashMap<String, String>>,
     pub vcur: Option<String>,
+    pub auto_pairs: Option<bool>,
+    pub relative_numbers: Option<bool>,
+    pub show_whitespace: Option<bool>,
+    pub cursor_line: Option<bool>,
+    pub ruler: Option<bool>,
+    pub color_column: Option<usize>,
+    pub scrolloff: Option<usize>,
+    pub sidescrolloff: Option<usize>,
+    pub spell_syntaxes: Option<Vec<String>>,
+    pub spell_dictionary: Option<String>,
+    pub diff_wrap: Option<bool>,
+    pub undo_granularity: Option<String>,
+    pub block_paste: Option<String>,
+    pub number_width: Option<usize>,
+    pub number_separator: Option<String>,
+    pub message_timeout_ms: Option<u64>,
+    pub swap_interval_ms: Option<u64>,
+    pub make_backup: Option<bool>,
+    pub extract_reference: Option<String>,
+    pub alternate_suffixes: Option<Vec<(String, String)>>,
+    pub reflow_width: Option<usize>,
+    pub uniqc_width: Option<usize>,
+    pub uniqc_separator: Option<String>,
+    pub comment_prefixes: Option<HashMap<String, String>>,
+    pub wrap_marker: Option<String>,
+    pub wrap_marker_color: Option<String>,
+    pub symbol_patterns: Option<HashMap<String, Vec<String>>>,
+    pub ai_diff_target_only: Option<bool>,
+    pub colors: Option<ColorsConfig>,
+    pub color_mode: Option<String>,
     pub ai: Option<AiConfig>,
 }
 
+/// The `vedit` directories under `$XDG_CONFIG_HOME` and the platform
+/// config directory (`~/.config` on Linux, `~/Library/Application Support`
+/// on macOS, `%APPDATA%` on Windows), without repeats.
+fn config_dirs() -> Vec<PathBuf> {
+    let home = home::home_dir();
+    let mut bases = Vec::new();
+    if let Some(xdg) = env::var_os("XDG_CONFIG_HOME").filter(|v| !v.is_empty()) {
+        bases.push(PathBuf::from(xdg));
+    }
+    if cfg!(windows) {
+        bases.extend(env::var_os("APPDATA").map(PathBuf::from));
+    } else if cfg!(target_os = "macos") {
+        bases.extend(home.as_ref().map(|h| h.join("Library").join("Application Support")));
+    } else {
+        bases.extend(home.as_ref().map(|h| h.join(".config")));
+    }
+
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    for base in bases {
+        let dir = base.join("vedit");
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    dirs
+}
+
+/// Places the config is looked for, in order: `config.toml` in each of
+/// the `config_dirs`, then `~/.vedit.toml`.
+fn candidate_paths() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = config_dirs().into_iter().map(|dir| dir.join("config.toml")).collect();
+    paths.extend(home::home_dir().map(|h| h.join(".vedit.toml")));
+    paths
+}
+
+/// Directories searched for `NAME.prompt` files, in order: the `[ai]`
+/// `prompt_dir`, `prompts` under the current directory, then `prompts` in
+/// each of the `config_dirs` and in `~/.vedit`.
+pub fn prompt_dirs(config: &EditorConfig) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = config
+        .ai
+        .as_ref()
+        .and_then(|ai| ai.prompt_dir.as_deref())
+        .map(logfile::resolve)
+        .into_iter()
+        .collect();
+    dirs.push(PathBuf::from("prompts"));
+    dirs.extend(config_dirs().into_iter().map(|dir| dir.join("prompts")));
+    dirs.extend(home::home_dir().map(|h| h.join(".vedit").join("prompts")));
+    dirs
+}
+
 impl EditorConfig {
     pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
-        let home = home::home_dir().ok_or("Failed to get home directory")?;
-        let path = home.join(".vedit.toml");
+        let candidates = candidate_paths();
+        let path = candidates.iter().find(|p| p.is_file()).ok_or_else(|| {
+            let tried: Vec<String> = candidates.iter().map(|p| p.display().to_string()).collect();
+            format!("No config file found (looked for {})", tried.join(", "))
+        })?;
         let content = fs::read_to_string(path)?;
-        let config: EditorConfig = toml::from_str(&content)?;
+        let config: EditorConfig = toml::from_str(&content)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        config
+            .validate()
+            .map_err(|problems| format!("{}:\n  {}", path.display(), problems.join("\n  ")))?;
         Ok(config)
     }
+
+    /// Tab width for `syntax_name`, falling back to the global `tab_width`.
+    pub fn tab_width_for(&self, syntax_name: &str) -> usize {
+        self.tab_width_by_syntax
+            .as_ref()
+            .and_then(|widths| widths.get(syntax_name).copied())
+            .unwrap_or(self.tab_width)
+    }
+
+    /// Checks values that parse but cannot work, naming the field at fault.
+    fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        if self.tab_width == 0 {
+            problems.push("tab_width must be greater than 0".to_string());
+        }
+        for (syntax, width) in self.tab_width_by_syntax.iter().flatten() {
+            if *width == 0 {
+                problems.push(format!("tab_width_by_syntax.{} must be greater than 0", syntax));
+            }
+        }
+        if let Some(granularity) = &self.undo_granularity {
+            if !["char", "word", "line"].contains(&granularity.as_str()) {
+                problems.push(format!(
+                    "undo_granularity: '{}' is not one of char, word, line",
+                    granularity
+                ));
+            }
+        }
+        if let Some(mode) = &self.block_paste {
+            if mode != "replace" && mode != "overwrite" {
+                problems.push(format!("block_paste: '{}' is not one of replace, overwrite", mode));
+            }
+        }
+        if let Some(mode) = &self.color_mode {
+            if !["truecolor", "256", "16"].contains(&mode.as_str()) {
+                problems.push(format!("color_mode: '{}' is not one of truecolor, 256, 16", mode));
+            }
+        }
+        let themes = crate::syntax::theme_names();
+        if !themes.contains(&self.theme) {
+            let mut themes = themes;
+            themes.sort();
+            problems.push(format!(
+                "theme: unknown theme '{}' (available: {})",
+                self.theme,
+                themes.join(", ")
+            ));
+        }
+        if let Some(ai) = &self.ai {
+            if let Some(id) = &ai.default_model {
+                if !ai.models.iter().any(|m| &m.id == id) {
+                    problems.push(format!("ai.default_model: no model with id '{}'", id));
+                }
+            }
+            for model in &ai.models {
+                match reqwest::Url::parse(&model.endpoint) {
+                    Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+                    Ok(_) => problems.push(format!(
+                        "ai.models '{}': endpoint '{}' must start with http:// or https://",
+                        model.id, model.endpoint
+                    )),
+                    Err(e) => problems.push(format!(
+                        "ai.models '{}': endpoint '{}' is not a valid URL ({})",
+                        model.id, model.endpoint, e
+                    )),
+                }
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
 }
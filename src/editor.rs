@@ -1,41 +1,93 @@
 use unicode_width::UnicodeWidthStr;
+use crate::ai::AiEvent;
 use crate::config::EditorConfig;
-use std::time::Instant;
+use crate::spell::{self, SpellChecker};
+use crate::syntax::HighlightCache;
+use crossterm::event::KeyEvent;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::ops::Range;
+use std::time::{Instant, SystemTime};
 use std::sync::mpsc::Receiver;
 
+#[derive(Default)]
 pub enum AiStatus {
+    #[default]
     Idle,
-    InProgress { start_time: Instant, spinner_state: usize },
+    InProgress { start_time: Instant, spinner_state: usize, received: String },
     Success { message: String, timestamp: Instant },
     Failure { message: String, timestamp: Instant },
 }
 
-impl Default for AiStatus {
-    fn default() -> Self {
-        AiStatus::Idle
-    }
-}
-
 #[derive(PartialEq)]
 pub enum Focus {
     Editor,
     CommandLine,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum PromptAction {
     Save,
     Quit,
-    AcceptAi,
+    SaveAndQuit,
+    /// Replace the buffer with its recovery file.
+    Recover,
+    /// Overwrite `path` with the selection (`extract`).
+    Extract { path: String, copy: bool },
+    /// Overwrite `path` with the selection or buffer (`w`).
+    WriteTo { path: String },
+    /// Close the active tab, discarding its changes.
+    CloseTab,
 }
 
 #[derive(Clone)]
 pub enum PromptType {
     Confirm,
-    Message,
+    /// Informational; dismissed by any key or after `message_timeout_ms`
+    /// from the time it was shown.
+    Message(Instant),
     Fill,
 }
 
+/// The part of the buffer an AI request was sent for, so the response can be
+/// spliced back over just that region.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AiScope {
+    Buffer,
+    Lines { start_y: usize, end_y: usize },
+    Block { start_y: usize, end_y: usize, start_x: usize, end_x: usize },
+}
+
+/// How many keystrokes one undo step takes back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UndoGranularity {
+    /// Every keystroke.
+    Char,
+    /// A run of word characters typed in a row.
+    Word,
+    /// Everything edited while the cursor stayed on one line.
+    Line,
+}
+
+/// What pasting over a block selection does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockPaste {
+    /// The block's text is removed and the clipboard lines are inserted in
+    /// its place, one per row starting at the top.
+    Replace,
+    /// The clipboard lines are written into the block's rectangle, padded or
+    /// cut to its width; the buffer keeps its shape.
+    Overwrite,
+}
+
+/// Where `align_view` puts the cursor line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ViewAlign {
+    Top,
+    Center,
+    Bottom,
+}
+
 #[derive(Clone, PartialEq)]
 pub enum SelectionMode {
     None,
@@ -54,10 +106,177 @@ fn column_to_byte_index(line: &str, column: usize) -> usize {
     line.len()
 }
 
+/// Byte range of the first match of `target` in `line` at or after byte
+/// `from`. Ignoring case compares lowercased characters, but the range is
+/// always in `line` itself, whose lowercase form can be a different length.
+fn find_in_line(line: &str, target: &str, case_sensitive: bool, from: usize) -> Option<(usize, usize)> {
+    if case_sensitive {
+        return line[from..].find(target).map(|pos| (from + pos, from + pos + target.len()));
+    }
+    let target = target.to_lowercase();
+    line[from..].char_indices().map(|(pos, _)| from + pos).find_map(|start| {
+        let mut lowered = String::new();
+        for (pos, c) in line[start..].char_indices() {
+            lowered.extend(c.to_lowercase());
+            if !target.starts_with(&lowered) {
+                return None;
+            }
+            if lowered.len() == target.len() {
+                return Some((start, start + pos + c.len_utf8()));
+            }
+        }
+        None
+    })
+}
+
+/// Orders two rows by their sort keys, one key per `(start, end, asc,
+/// numeric)` spec. A numeric key compares by value when it parses as a
+/// number; keys that do not parse sort after those that do, as text.
+fn compare_sort_keys(a: &[String], b: &[String], sort_specs: &[(usize, usize, bool, bool)]) -> Ordering {
+    for (i, &(_, _, asc, numeric)) in sort_specs.iter().enumerate() {
+        let cmp = if numeric {
+            match (a[i].trim().parse::<f64>(), b[i].trim().parse::<f64>()) {
+                (Ok(x), Ok(y)) => x.total_cmp(&y),
+                (Ok(_), Err(_)) => Ordering::Less,
+                (Err(_), Ok(_)) => Ordering::Greater,
+                (Err(_), Err(_)) => a[i].cmp(&b[i]),
+            }
+        } else {
+            a[i].cmp(&b[i])
+        };
+        let cmp = if asc { cmp } else { cmp.reverse() };
+        if cmp != Ordering::Equal {
+            return cmp;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Letters, digits and `_` make up words; everything else separates them.
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The character `auto_pairs` closes `c` with, if it opens a pair.
+fn pair_closer(c: char) -> Option<char> {
+    match c {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        '"' | '\'' => Some(c),
+        _ => None,
+    }
+}
+
+fn char_index_to_column(line: &str, index: usize) -> usize {
+    line.chars().take(index).map(|c| c.to_string().width()).sum()
+}
+
+/// Pads `line` with spaces so it reaches display column `column`.
+fn pad_to_column(line: &mut String, column: usize) {
+    let width = line.width();
+    if width < column {
+        line.push_str(&" ".repeat(column - width));
+    }
+}
+
+/// Cuts or pads `text` to exactly `width` display columns.
+fn fit_to_width(text: &str, width: usize) -> String {
+    let mut result = String::new();
+    for c in text.chars() {
+        if result.width() + c.to_string().width() > width {
+            break;
+        }
+        result.push(c);
+    }
+    pad_to_column(&mut result, width);
+    result
+}
+
+/// Last-modified time of `path`, if it can be read.
+pub fn file_mtime(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn leading_indent(line: &str) -> String {
+    line.chars().take_while(|c| c.is_whitespace()).collect()
+}
+
+/// Splits a leading `"quoted"` or `'quoted'` string off `text`, returning
+/// it without its quotes and the text after it.
+fn split_quoted(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_start();
+    let quote = text.chars().next().filter(|&c| c == '"' || c == '\'')?;
+    let quoted = &text[1..];
+    let end_quote = quoted.find(quote)?;
+    Some((&quoted[..end_quote], &quoted[end_quote + 1..]))
+}
+
+/// The unfocused half of a split view: the tab it shows and its own cursor
+/// and scroll position. The focused half is the editor itself.
+pub struct SplitPane {
+    pub tab: usize,
+    pub cursor_x: usize,
+    pub cursor_y: usize,
+    pub scroll_x: usize,
+    pub scroll_y: usize,
+    /// Whether this pane is drawn on the left of the focused one.
+    pub on_left: bool,
+    pub highlight_cache: HighlightCache,
+}
+
+/// The per-file part of the editor state, kept aside while another file is
+/// being edited (in another tab, or closed for `reopen`).
+pub struct Document {
+    pub buffer: Vec<String>,
+    pub filename: Option<String>,
+    pub syntax_name: String,
+    pub cursor_x: usize,
+    pub cursor_y: usize,
+    pub scroll_x: usize,
+    pub scroll_y: usize,
+    pub modified: bool,
+    pub undo_history: Vec<Vec<String>>,
+    pub undo_index: usize,
+    pub last_save_state: Option<Vec<String>>,
+    pub disk_mtime: Option<SystemTime>,
+    pub final_newline: bool,
+}
+
+impl Document {
+    pub fn new(contents: &str, filename: Option<String>, syntax_name: String) -> Self {
+        let mut buffer = contents.lines().map(|s| s.to_string()).collect::<Vec<_>>();
+        if buffer.is_empty() {
+            buffer.push(String::new());
+        }
+        Document {
+            undo_history: vec![buffer.clone()],
+            last_save_state: Some(buffer.clone()),
+            disk_mtime: filename.as_deref().and_then(file_mtime),
+            buffer,
+            filename,
+            syntax_name,
+            cursor_x: 0,
+            cursor_y: 0,
+            scroll_x: 0,
+            scroll_y: 0,
+            modified: false,
+            undo_index: 0,
+            final_newline: contents.ends_with('\n'),
+        }
+    }
+}
+
+/// How many status messages `messages` can show.
+const MESSAGE_LOG_MAX: usize = 100;
+
 pub struct Editor {
     pub buffer: Vec<String>,
     pub cursor_x: usize,
     pub cursor_y: usize,
+    /// Secondary cursors as (y, x). Typing, Backspace, Enter and the arrow
+    /// keys act at each of them as well as at the primary cursor.
+    pub extra_cursors: Vec<(usize, usize)>,
     pub scroll_y: usize,
     pub scroll_x: usize,
     pub editor_visible_height: usize,
@@ -65,11 +284,20 @@ pub struct Editor {
     pub focus: Focus,
     pub command_buffer: String,
     pub command_cursor: usize,
+    /// Prefix being completed and the match last filled in, while Tab
+    /// cycles through command names.
+    pub command_completion: Option<(String, usize)>,
+    /// Keys pressed since macro recording started, while it is on.
+    pub recording: Option<Vec<KeyEvent>>,
+    pub last_macro: Vec<KeyEvent>,
+    /// Replay count requested by `replay` or F4, run by the event loop.
+    pub macro_replay: Option<usize>,
     pub overwrite_mode: bool,
     pub modified: bool,
     pub quit: bool,
     pub read_only: bool,
     pub filename: Option<String>,
+    pub syntax_name: String,
     pub original_buffer: Option<Vec<String>>,
     pub original_filename: Option<String>,
     pub original_cursor_y: usize,
@@ -77,18 +305,69 @@ pub struct Editor {
     pub original_scroll_y: usize,
     pub original_scroll_x: usize,
     pub original_modified: bool,
+    /// Source line for each row of a read-only list view, such as the
+    /// symbol list; Enter on a row jumps back to that line.
+    pub view_targets: Option<Vec<usize>>,
     pub prompt: Option<(String, PromptType, Option<PromptAction>)>,
     pub selection_start: Option<(usize, usize)>,
     pub selection_end: Option<(usize, usize)>,
     pub selection_mode: SelectionMode,
     pub virtual_cursor: bool,
+    pub auto_pairs: bool,
+    pub tab_width: usize,
     pub show_line_numbers: bool,
+    /// Number the gutter by distance from the cursor line (`relativenumber`).
+    pub relative_numbers: bool,
+    /// Show long lines across several screen rows instead of scrolling
+    /// sideways (`wrap`).
+    pub soft_wrap: bool,
+    pub show_wrap_marker: bool,
+    /// Draw tabs, trailing spaces and line ends as visible glyphs (`list`).
+    pub show_whitespace: bool,
+    /// Shade the row of the cursor line (`cursorline`).
+    pub cursor_line: bool,
+    /// Draw the column ruler above the text (`ruler`).
+    pub show_ruler: bool,
+    /// 1-based column shaded as a line length guide (`colorcolumn`).
+    pub color_column: Option<usize>,
+    /// Spell checking forced on or off (`spell`); `None` checks the
+    /// syntaxes in `spell_syntaxes`.
+    pub spell: Option<bool>,
+    pub spell_syntaxes: Vec<String>,
+    /// `n` and `p` in diff mode go round from the last hunk to the first
+    /// and back.
+    pub diff_wrap: bool,
+    pub spell_dictionary: String,
+    /// Loaded on first use; the error is kept so a missing dictionary is
+    /// not read again every frame.
+    spell_checker: Option<Result<SpellChecker, String>>,
+    /// Lines kept in view above and below the cursor (`scrolloff`).
+    pub scroll_off: usize,
+    /// Columns kept in view either side of the cursor (`sidescrolloff`).
+    pub side_scroll_off: usize,
+    pub wrap_marker: String,
+    pub wrap_marker_color: String,
     pub command_history: Vec<String>,
     pub history_index: usize,
     pub temp_command_buffer: String,
+    /// Query and the history entry it matched (`None` when nothing does)
+    /// while Ctrl+R searches the command history.
+    pub history_search: Option<(String, Option<usize>)>,
     pub undo_history: Vec<Vec<String>>,
     pub undo_index: usize,
+    pub undo_granularity: UndoGranularity,
+    /// Line, expected next column and word-ness of the last keystroke edit,
+    /// for deciding whether the next one joins the same undo step. Moving
+    /// the cursor clears it.
+    pub edit_group: Option<(usize, usize, bool)>,
     pub last_save_state: Option<Vec<String>>,
+    /// Modification time of the file when it was loaded or last saved, to
+    /// notice changes made on disk by something else.
+    pub disk_mtime: Option<SystemTime>,
+    /// Whether the file ended with a newline, so saving writes one back.
+    pub final_newline: bool,
+    /// Copy a file to `NAME~` before saving over it.
+    pub make_backup: bool,
     pub search_target: Option<String>,
     pub search_scope: SearchScope,
     pub search_case_sensitive: bool,
@@ -96,16 +375,43 @@ pub struct Editor {
     pub current_match_index: usize,
     pub matches_in_last_line: usize,
     pub replace_text: Option<String>,
-    pub replace_all: bool,
     pub diff_mode: DiffMode,
     pub ai_status: AiStatus,
-    pub ai_response_receiver: Option<Receiver<Result<String, String>>>,
+    pub ai_response_receiver: Option<Receiver<AiEvent>>,
+    pub ai_scope: AiScope,
+    /// An AI response for a selection is reviewed against just the selected
+    /// lines rather than the whole buffer.
+    pub ai_diff_target_only: bool,
+    /// Folded line ranges `(start, end)`: `start` stays visible as the fold
+    /// line, `start + 1..=end` are hidden. Sorted and non-overlapping.
+    pub folds: Vec<(usize, usize)>,
+    /// Buffer length when the folds were made; any change drops them.
+    pub folds_line_count: usize,
+    /// Opening and closing brace (line, column) of the block found by the
+    /// `scope` command, highlighted until the next edit.
+    pub scope_braces: Option<((usize, usize), (usize, usize))>,
+    /// Column ranges (start, end exclusive, ascending) of the last sort, for
+    /// `resort`.
+    pub last_sort_specs: Option<Vec<(usize, usize, bool, bool)>>,
+    /// Syntax highlighting of the lines drawn so far, reused across redraws.
+    pub highlight_cache: HighlightCache,
+    /// Lines copied or cut with `copy`/`cut`, for `paste`.
+    pub clipboard: Vec<String>,
+    pub block_paste: BlockPaste,
+    /// Open documents in tab order. The active tab's slot is `None`: its
+    /// state lives in the editor's own fields.
+    pub tabs: Vec<Option<Document>>,
+    pub active_tab: usize,
+    pub split: Option<SplitPane>,
+    /// Most recently closed documents, newest last.
+    pub closed_documents: Vec<Document>,
+    /// Recent status messages, newest last, for `messages`.
+    pub message_log: Vec<String>,
 }
 
 #[derive(Clone, PartialEq)]
 pub enum SearchScope {
     All,
-    Line,
     Block,
 }
 
@@ -116,14 +422,31 @@ pub enum DiffLine {
     Removed(String),
 }
 
+/// Which side of an accepted hunk is applied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HunkPart {
+    /// Added lines go in and removed lines go out.
+    All,
+    /// Added lines go in, removed lines stay.
+    AdditionsOnly,
+    /// Removed lines go out, added lines are left out.
+    DeletionsOnly,
+}
+
 #[derive(Clone, Debug)]
 pub struct Hunk {
     pub old_start: usize,
     pub old_lines: usize,
     pub new_start: usize,
-    pub new_lines: usize,
     pub lines: Vec<DiffLine>,
     pub accepted: bool,
+    /// Set once the hunk has been accepted or rejected, so a rejected hunk
+    /// can be told apart from one not looked at yet.
+    pub reviewed: bool,
+    pub part: HunkPart,
+    /// Indexes into `lines` of the changes left out when the hunk is
+    /// accepted, picked one by one in the review.
+    pub excluded: HashSet<usize>,
 }
 
 #[derive(Clone)]
@@ -131,9 +454,16 @@ pub enum DiffMode {
     Inactive,
     Active {
         original_buffer: Vec<String>,
+        /// The lines of `original_buffer` the AI response replaces; `None`
+        /// when it replaces the whole buffer. Hunk positions stay relative to
+        /// the whole buffer either way.
+        target: Option<Range<usize>>,
+        /// The AI's text for `target`.
         modified_buffer: Vec<String>,
         hunks: Vec<Hunk>,
         current_hunk: usize,
+        /// The line of the current hunk that toggling acts on.
+        current_line: usize,
         accept_all: bool,
     },
 }
@@ -144,12 +474,12 @@ impl Editor {
         if buffer.is_empty() {
             buffer.push(String::new());
         }
-        let virtual_cursor = config.vcur.as_ref().map(|s| s == "on").unwrap_or(true);
         let buffer_clone = buffer.clone();
-        Editor {
+        let mut editor = Editor {
             buffer,
             cursor_x: 0,
             cursor_y: 0,
+            extra_cursors: Vec::new(),
             scroll_y: 0,
             scroll_x: 0,
             editor_visible_height: 0,
@@ -157,11 +487,16 @@ impl Editor {
             focus: Focus::Editor,
             command_buffer: String::new(),
             command_cursor: 0,
+            command_completion: None,
+            recording: None,
+            last_macro: Vec::new(),
+            macro_replay: None,
             overwrite_mode: true,
             modified: false,
             quit: false,
             read_only: false,
             filename: None,
+            syntax_name: "Plain Text".to_string(),
             original_buffer: None,
             original_filename: None,
             original_cursor_y: 0,
@@ -169,18 +504,43 @@ impl Editor {
             original_scroll_y: 0,
             original_scroll_x: 0,
             original_modified: false,
+            view_targets: None,
             prompt: None,
              selection_start: None,
              selection_end: None,
              selection_mode: SelectionMode::None,
-             virtual_cursor,
+             virtual_cursor: true,
+             auto_pairs: false,
+             tab_width: config.tab_width,
              show_line_numbers: false,
+             relative_numbers: false,
+             soft_wrap: false,
+             show_wrap_marker: true,
+             show_whitespace: false,
+             cursor_line: false,
+             show_ruler: true,
+             color_column: None,
+             spell: None,
+             spell_syntaxes: Vec::new(),
+             diff_wrap: false,
+             spell_dictionary: String::new(),
+             spell_checker: None,
+             scroll_off: 0,
+             side_scroll_off: 0,
+             wrap_marker: String::new(),
+             wrap_marker_color: String::new(),
              command_history: Vec::new(),
              history_index: 0,
+             history_search: None,
              temp_command_buffer: String::new(),
              undo_history: vec![buffer_clone.clone()],
              undo_index: 0,
+             undo_granularity: UndoGranularity::Char,
+             edit_group: None,
              last_save_state: Some(buffer_clone),
+             disk_mtime: None,
+             final_newline: contents.ends_with('\n'),
+             make_backup: false,
              search_target: None,
              search_scope: SearchScope::All,
              search_case_sensitive: true,
@@ -188,17 +548,149 @@ impl Editor {
              current_match_index: 0,
              matches_in_last_line: 0,
 replace_text: None,
-            replace_all: false,
             diff_mode: DiffMode::Inactive,
             ai_status: AiStatus::default(),
             ai_response_receiver: None,
-        }
+            ai_scope: AiScope::Buffer,
+            ai_diff_target_only: true,
+            folds: Vec::new(),
+            folds_line_count: 0,
+            scope_braces: None,
+            last_sort_specs: None,
+            highlight_cache: HighlightCache::default(),
+            clipboard: Vec::new(),
+            block_paste: BlockPaste::Replace,
+            tabs: vec![None],
+            active_tab: 0,
+            split: None,
+            closed_documents: Vec::new(),
+            message_log: Vec::new(),
+        };
+        editor.apply_config(config);
+        editor
+    }
+
+    /// Shows `message` on the prompt line and keeps it in the message log.
+    pub fn show_message(&mut self, message: String) {
+        self.message_log.push(message.clone());
+        if self.message_log.len() > MESSAGE_LOG_MAX {
+            self.message_log.remove(0);
+        }
+        self.prompt = Some((message, PromptType::Message(Instant::now()), None));
+    }
+
+    /// Takes over the settings that come from the config file. The tab width
+    /// is the global one; callers that know the syntax refine it.
+    pub fn apply_config(&mut self, config: &EditorConfig) {
+        self.virtual_cursor = config.vcur.as_ref().map(|s| s == "on").unwrap_or(true);
+        self.auto_pairs = config.auto_pairs.unwrap_or(false);
+        self.relative_numbers = config.relative_numbers.unwrap_or(false);
+        self.show_whitespace = config.show_whitespace.unwrap_or(false);
+        self.cursor_line = config.cursor_line.unwrap_or(false);
+        self.show_ruler = config.ruler.unwrap_or(true);
+        self.color_column = config.color_column.filter(|&column| column > 0);
+        self.spell_syntaxes = config
+            .spell_syntaxes
+            .clone()
+            .unwrap_or_else(|| spell::DEFAULT_SYNTAXES.iter().map(|s| s.to_string()).collect());
+        self.spell_dictionary = config.spell_dictionary.clone().unwrap_or_else(|| spell::DEFAULT_DICTIONARY.to_string());
+        self.spell_checker = None;
+        self.diff_wrap = config.diff_wrap.unwrap_or(false);
+        self.scroll_off = config.scrolloff.unwrap_or(3);
+        self.side_scroll_off = config.sidescrolloff.unwrap_or(0);
+        self.make_backup = config.make_backup.unwrap_or(false);
+        self.tab_width = config.tab_width;
+        self.wrap_marker = config.wrap_marker.clone().unwrap_or_else(|| "↪".to_string());
+        self.wrap_marker_color = config.wrap_marker_color.clone().unwrap_or_else(|| "darkgray".to_string());
+        self.block_paste = match config.block_paste.as_deref() {
+            Some("overwrite") => BlockPaste::Overwrite,
+            _ => BlockPaste::Replace,
+        };
+        self.undo_granularity = match config.undo_granularity.as_deref() {
+            Some("word") => UndoGranularity::Word,
+            Some("line") => UndoGranularity::Line,
+            _ => UndoGranularity::Char,
+        };
+        self.ai_diff_target_only = config.ai_diff_target_only.unwrap_or(true);
+    }
+
+    /// Makes `doc` the document being edited and returns the previous one.
+    /// Selection, search results and folds belong to the old text and are
+    /// dropped.
+    pub fn swap_document(&mut self, doc: Document) -> Document {
+        let previous = Document {
+            buffer: std::mem::replace(&mut self.buffer, doc.buffer),
+            filename: std::mem::replace(&mut self.filename, doc.filename),
+            syntax_name: std::mem::replace(&mut self.syntax_name, doc.syntax_name),
+            cursor_x: std::mem::replace(&mut self.cursor_x, doc.cursor_x),
+            cursor_y: std::mem::replace(&mut self.cursor_y, doc.cursor_y),
+            scroll_x: std::mem::replace(&mut self.scroll_x, doc.scroll_x),
+            scroll_y: std::mem::replace(&mut self.scroll_y, doc.scroll_y),
+            modified: std::mem::replace(&mut self.modified, doc.modified),
+            undo_history: std::mem::replace(&mut self.undo_history, doc.undo_history),
+            undo_index: std::mem::replace(&mut self.undo_index, doc.undo_index),
+            last_save_state: std::mem::replace(&mut self.last_save_state, doc.last_save_state),
+            disk_mtime: std::mem::replace(&mut self.disk_mtime, doc.disk_mtime),
+            final_newline: std::mem::replace(&mut self.final_newline, doc.final_newline),
+        };
+        self.deselect();
+        self.extra_cursors.clear();
+        self.edit_group = None;
+        self.scope_braces = None;
+        self.search_matches.clear();
+        self.current_match_index = 0;
+        self.unfold_all();
+        previous
+    }
+
+    /// Name and modified flag of every tab, in order.
+    pub fn tab_titles(&self) -> Vec<(String, bool)> {
+        let title = |filename: &Option<String>| match filename {
+            Some(path) => std::path::Path::new(path)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.clone()),
+            None => "[New File]".to_string(),
+        };
+        self.tabs
+            .iter()
+            .map(|tab| match tab {
+                Some(doc) => (title(&doc.filename), doc.modified),
+                None => (title(self.document_filename()), self.document_modified()),
+            })
+            .collect()
+    }
+
+    /// The active document's file name, also while a view covers it.
+    pub fn document_filename(&self) -> &Option<String> {
+        if self.original_buffer.is_some() { &self.original_filename } else { &self.filename }
+    }
+
+    /// Whether the active document has unsaved changes, also while a view
+    /// covers it.
+    pub fn document_modified(&self) -> bool {
+        if self.original_buffer.is_some() { self.original_modified } else { self.modified }
     }
 
     pub fn move_cursor(&mut self, dx: isize, dy: isize) {
+        // Typing after moving starts a new undo step
+        self.edit_group = None;
+        if !self.extra_cursors.is_empty() {
+            self.move_all_cursors(dx, dy);
+            return;
+        }
+        if self.soft_wrap && dx == 0 && dy.abs() == 1 {
+            self.move_screen_row(dy > 0);
+            return;
+        }
         let new_y = (self.cursor_y as isize + dy).clamp(0, self.buffer.len() as isize - 1);
         self.cursor_y = new_y as usize;
 
+        if let Some((start, end)) = self.fold_hiding(self.cursor_y) {
+            // Step over folded lines in the direction of travel
+            self.cursor_y = if dy > 0 && end + 1 < self.buffer.len() { end + 1 } else { start };
+        }
+
         let line = &self.buffer[self.cursor_y];
         let line_width = line.width();
 
@@ -219,25 +711,357 @@ replace_text: None,
         self.scroll();
     }
 
+    /// `move_cursor` for every cursor; the view follows the primary one.
+    fn move_all_cursors(&mut self, dx: isize, dy: isize) {
+        let primary = (self.cursor_y, self.cursor_x);
+        let scroll = (self.scroll_y, self.scroll_x);
+        let mut extras = std::mem::take(&mut self.extra_cursors);
+        for cursor in &mut extras {
+            (self.cursor_y, self.cursor_x) = *cursor;
+            self.move_cursor(dx, dy);
+            *cursor = (self.cursor_y, self.cursor_x);
+        }
+        (self.cursor_y, self.cursor_x) = primary;
+        (self.scroll_y, self.scroll_x) = scroll;
+        self.move_cursor(dx, dy);
+        self.set_extra_cursors(extras);
+    }
+
+    /// Keeps `cursors` as the secondary cursors, sorted, without duplicates
+    /// or one on top of the primary cursor.
+    fn set_extra_cursors(&mut self, mut cursors: Vec<(usize, usize)>) {
+        cursors.sort();
+        cursors.dedup();
+        cursors.retain(|&cursor| cursor != (self.cursor_y, self.cursor_x));
+        self.extra_cursors = cursors;
+    }
+
+    /// Turns virtual space on or off. Turning it off pulls every cursor
+    /// back onto the end of its line.
+    pub fn set_virtual_cursor(&mut self, on: bool) {
+        self.virtual_cursor = on;
+        if on {
+            return;
+        }
+        self.cursor_x = self.cursor_x.min(self.buffer[self.cursor_y].width());
+        let cursors = self
+            .extra_cursors
+            .iter()
+            .map(|&(y, x)| (y, x.min(self.buffer[y].width())))
+            .collect();
+        self.set_extra_cursors(cursors);
+        self.scroll();
+    }
+
+    /// Adds a cursor one line below the lowest cursor, or above the highest
+    /// one when `dy` is negative, at the same column. Returns false at the
+    /// edge of the buffer.
+    pub fn add_cursor(&mut self, dy: isize) -> bool {
+        let mut cursors = self.extra_cursors.clone();
+        cursors.push((self.cursor_y, self.cursor_x));
+        let edge = if dy > 0 { cursors.iter().max() } else { cursors.iter().min() };
+        let (y, x) = *edge.unwrap();
+        let y = y as isize + dy;
+        if y < 0 || y >= self.buffer.len() as isize {
+            return false;
+        }
+        let y = y as usize;
+        let x = if self.virtual_cursor { x } else { x.min(self.buffer[y].width()) };
+        cursors.push((y, x));
+        self.set_extra_cursors(cursors);
+        true
+    }
+
+    /// Puts a cursor at the start of every occurrence of the selected text,
+    /// which must lie within one line. The primary cursor goes to the
+    /// selection itself. Returns the number of cursors, or 0 when nothing
+    /// usable is selected.
+    pub fn add_cursors_at_matches(&mut self) -> usize {
+        let (y, x, text) = match self.selection_text() {
+            Some((AiScope::Block { start_y, end_y, start_x, .. }, text)) if start_y == end_y && !text.is_empty() => {
+                (start_y, start_x, text)
+            }
+            _ => return 0,
+        };
+        let cursors: Vec<(usize, usize)> = self
+            .buffer
+            .iter()
+            .enumerate()
+            .flat_map(|(line_y, line)| {
+                line.match_indices(text.as_str()).map(move |(byte, _)| (line_y, line[..byte].width()))
+            })
+            .collect();
+        self.deselect();
+        (self.cursor_y, self.cursor_x) = (y, x);
+        self.set_extra_cursors(cursors);
+        self.scroll();
+        self.extra_cursors.len() + 1
+    }
+
+    /// Runs a keystroke edit at every cursor, from the last one in the
+    /// buffer to the first so that positions still to be visited stay
+    /// valid, shifting the cursors already visited past the change. The
+    /// whole keystroke is one undo step.
+    fn edit_at_cursors(&mut self, edit: impl Fn(&mut Self)) {
+        let primary = (self.cursor_y, self.cursor_x);
+        let mut cursors = std::mem::take(&mut self.extra_cursors);
+        cursors.push(primary);
+        cursors.retain(|&(y, _)| y < self.buffer.len());
+        cursors.sort();
+        cursors.dedup();
+        let start = self.begin_undo_group();
+        let mut visited: Vec<((usize, usize), bool)> = Vec::new();
+        for &(y, x) in cursors.iter().rev() {
+            (self.cursor_y, self.cursor_x) = (y, x);
+            let (old_len, old_width) = (self.buffer.len(), self.buffer[y].width());
+            edit(self);
+            // The rest of line y ends up on `tail_y`, shifted by `dx`
+            let dy = self.buffer.len() as isize - old_len as isize;
+            let tail_y = (y as isize + dy) as usize;
+            let dx = self.buffer[tail_y].width() as isize - old_width as isize;
+            for (cursor, _) in &mut visited {
+                *cursor = if cursor.0 == y {
+                    (tail_y, (cursor.1 as isize + dx).max(0) as usize)
+                } else {
+                    ((cursor.0 as isize + dy) as usize, cursor.1)
+                };
+            }
+            visited.push(((self.cursor_y, self.cursor_x), (y, x) == primary));
+        }
+        self.end_undo_group(start);
+        let (primary, extras): (Vec<_>, Vec<_>) = visited.into_iter().partition(|&(_, is_primary)| is_primary);
+        (self.cursor_y, self.cursor_x) = primary[0].0;
+        self.set_extra_cursors(extras.into_iter().map(|(cursor, _)| cursor).collect());
+        self.scroll();
+    }
+
+    /// Whether the gutter is shown, with absolute or relative numbers.
+    pub fn show_gutter(&self) -> bool {
+        self.show_line_numbers || self.relative_numbers
+    }
+
+    /// The gutter label for line `y` when the cursor is on `cursor_y`.
+    pub fn line_number_label(&self, y: usize, cursor_y: usize) -> String {
+        if self.relative_numbers && y != cursor_y {
+            y.abs_diff(cursor_y).to_string()
+        } else {
+            (y + 1).to_string()
+        }
+    }
+
+    /// Moves the cursor one screen row up or down with soft wrap on, keeping
+    /// its screen column.
+    fn move_screen_row(&mut self, down: bool) {
+        let (row, column) = self.cursor_segment();
+        let rows = self.wrap_starts(self.cursor_y).len();
+        if down && row + 1 < rows {
+            self.place_on_segment(row + 1, column);
+        } else if !down && row > 0 {
+            self.place_on_segment(row - 1, column);
+        } else {
+            let step = if down { 1 } else { -1 };
+            let mut new_y = (self.cursor_y as isize + step).clamp(0, self.buffer.len() as isize - 1) as usize;
+            if let Some((start, end)) = self.fold_hiding(new_y) {
+                new_y = if down && end + 1 < self.buffer.len() { end + 1 } else { start };
+            }
+            if new_y != self.cursor_y {
+                self.cursor_y = new_y;
+                let row = if down { 0 } else { self.wrap_starts(new_y).len() - 1 };
+                self.place_on_segment(row, column);
+            }
+        }
+        self.scroll();
+    }
+
+    /// Width of the marker drawn at the start of continuation rows.
+    fn wrap_marker_width(&self) -> usize {
+        if self.show_wrap_marker { self.wrap_marker.width() } else { 0 }
+    }
+
+    /// Columns at which line `y` starts each screen row when soft-wrapped;
+    /// the first is always 0. Continuation rows lose the marker's width.
+    pub fn wrap_starts(&self, y: usize) -> Vec<usize> {
+        let width = self.editor_visible_width.max(1);
+        let mut starts = vec![0];
+        let mut room = width;
+        let mut column = 0;
+        for c in self.buffer[y].chars() {
+            let char_width = c.to_string().width();
+            let row_start = starts[starts.len() - 1];
+            if column > row_start && column + char_width - row_start > room {
+                starts.push(column);
+                room = width.saturating_sub(self.wrap_marker_width()).max(1);
+            }
+            column += char_width;
+        }
+        starts
+    }
+
+    /// The cursor's wrapped row within its line and its column on screen
+    /// relative to the text area (before horizontal scrolling when soft
+    /// wrap is off).
+    pub fn cursor_segment(&self) -> (usize, usize) {
+        if !self.soft_wrap {
+            return (0, self.cursor_x);
+        }
+        let starts = self.wrap_starts(self.cursor_y);
+        let row = starts.iter().rposition(|&start| start <= self.cursor_x).unwrap_or(0);
+        let marker = if row > 0 { self.wrap_marker_width() } else { 0 };
+        (row, self.cursor_x - starts[row] + marker)
+    }
+
+    /// Puts the cursor on wrapped row `row` of its line, as close to screen
+    /// column `column` as that row allows.
+    fn place_on_segment(&mut self, row: usize, column: usize) {
+        let starts = self.wrap_starts(self.cursor_y);
+        let row = row.min(starts.len() - 1);
+        let marker = if row > 0 { self.wrap_marker_width() } else { 0 };
+        let limit = match starts.get(row + 1) {
+            Some(&next) => next.saturating_sub(1).max(starts[row]),
+            None if self.virtual_cursor => usize::MAX,
+            None => self.buffer[self.cursor_y].width(),
+        };
+        self.cursor_x = (starts[row] + column.saturating_sub(marker)).min(limit);
+    }
+
+    /// Screen rows from `scroll_y` as (buffer line, wrapped row within it).
+    /// Without soft wrap every line is a single row.
+    pub fn screen_rows(&self) -> Vec<(usize, usize)> {
+        let mut rows = Vec::new();
+        for y in self.visible_rows() {
+            let count = if self.soft_wrap { self.wrap_starts(y).len() } else { 1 };
+            rows.extend((0..count).map(|row| (y, row)));
+            if rows.len() >= self.editor_visible_height {
+                break;
+            }
+        }
+        rows.truncate(self.editor_visible_height);
+        rows
+    }
+
+    /// Moves the view by `lines` (negative is up) without moving the cursor,
+    /// stopping when the last line reaches the bottom.
+    pub fn scroll_view(&mut self, lines: isize) {
+        let max = self.buffer.len().saturating_sub(self.editor_visible_height);
+        self.scroll_y = self.scroll_y.saturating_add_signed(lines).min(max);
+    }
+
+    /// Whether misspelled words are marked: as set by `spell`, or else by
+    /// the syntax. Read-only views are never checked.
+    pub fn spell_enabled(&self) -> bool {
+        !self.read_only && self.spell.unwrap_or_else(|| self.spell_syntaxes.contains(&self.syntax_name))
+    }
+
+    /// The spell checker, loading the dictionary the first time.
+    pub fn spell_checker(&mut self) -> Result<&mut SpellChecker, String> {
+        let dictionary = &self.spell_dictionary;
+        self.spell_checker
+            .get_or_insert_with(|| {
+                SpellChecker::load(std::path::Path::new(dictionary))
+                    .map_err(|e| format!("Cannot read dictionary {}: {}", dictionary, e))
+            })
+            .as_mut()
+            .map_err(|e| e.clone())
+    }
+
+    /// Display column ranges of the unknown words on line `y`, once the
+    /// checker is loaded.
+    pub fn misspelled(&self, y: usize) -> Vec<(usize, usize)> {
+        match &self.spell_checker {
+            Some(Ok(checker)) => checker.misspelled(&self.buffer[y]),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Adds `word`, or the word under the cursor, to the personal word list.
+    pub fn accept_word(&mut self, word: Option<&str>) -> String {
+        let word = match word {
+            Some(word) => word.to_string(),
+            None => match spell::word_at(&self.buffer[self.cursor_y], self.cursor_x) {
+                Some(word) => word.to_string(),
+                None => return "No word under the cursor.".to_string(),
+            },
+        };
+        match self.spell_checker().map(|checker| checker.accept(&word)) {
+            Ok(Ok(())) => format!("Added '{}' to the word list.", word),
+            Ok(Err(e)) => format!("Cannot save '{}': {}", word, e),
+            Err(e) => e,
+        }
+    }
+
+    /// Scrolls so the cursor line is at the top, middle or bottom of the
+    /// view, short of scrolling past either end of the buffer. The top and
+    /// bottom keep the `scroll_off` margin.
+    pub fn align_view(&mut self, align: ViewAlign) {
+        let height = self.editor_visible_height;
+        let margin = self.scroll_off.min(height.saturating_sub(1) / 2);
+        let above = match align {
+            ViewAlign::Top => margin,
+            ViewAlign::Center => height.saturating_sub(1) / 2,
+            ViewAlign::Bottom => height.saturating_sub(1 + margin),
+        };
+        let max = self.buffer.len().saturating_sub(height);
+        self.scroll_y = self.cursor_y.saturating_sub(above).min(max);
+        // Wrapped lines can take more rows than counted here
+        self.scroll();
+    }
+
+    /// Brings the cursor into view, keeping `scroll_off` lines above and
+    /// below it and `side_scroll_off` columns either side where there is
+    /// room.
     pub fn scroll(&mut self) {
-        if self.cursor_y < self.scroll_y {
-            self.scroll_y = self.cursor_y;
+        // Past half the view the margins would leave nowhere for the cursor
+        let margin = self.scroll_off.min(self.editor_visible_height.saturating_sub(1) / 2);
+        if self.cursor_y < self.scroll_y + margin {
+            self.scroll_y = self.cursor_y.saturating_sub(margin);
+        }
+        if self.soft_wrap {
+            // Every line takes at least one row, so nothing above this can
+            // share the screen with the cursor
+            self.scroll_x = 0;
+            self.scroll_y = self.scroll_y.max((self.cursor_y + 1).saturating_sub(self.editor_visible_height));
+            let (cursor_row, _) = self.cursor_segment();
+            let rows_after = self.wrap_starts(self.cursor_y).len() - cursor_row - 1
+                + (self.cursor_y + 1..self.buffer.len())
+                    .filter(|&y| self.fold_hiding(y).is_none())
+                    .take(margin)
+                    .map(|y| self.wrap_starts(y).len())
+                    .sum::<usize>();
+            let below = margin.min(rows_after);
+            while self.scroll_y < self.cursor_y {
+                let rows: usize = (self.scroll_y..self.cursor_y)
+                    .filter(|&y| self.fold_hiding(y).is_none())
+                    .map(|y| self.wrap_starts(y).len())
+                    .sum();
+                if rows + cursor_row + below < self.editor_visible_height {
+                    break;
+                }
+                self.scroll_y += 1;
+            }
+            return;
         }
-        if self.cursor_y >= self.scroll_y + self.editor_visible_height {
-            self.scroll_y = self.cursor_y - self.editor_visible_height + 1;
+        // No margin is kept below the last line
+        let bottom = (self.cursor_y + margin).min(self.buffer.len() - 1);
+        if bottom >= self.scroll_y + self.editor_visible_height {
+            self.scroll_y = bottom + 1 - self.editor_visible_height;
         }
-        if self.cursor_x < self.scroll_x {
-            self.scroll_x = self.cursor_x;
+        let side_margin = self.side_scroll_off.min(self.editor_visible_width.saturating_sub(1) / 2);
+        if self.cursor_x < self.scroll_x + side_margin {
+            self.scroll_x = self.cursor_x.saturating_sub(side_margin);
         }
-        if self.cursor_x >= self.scroll_x + self.editor_visible_width {
-            self.scroll_x = self.cursor_x - self.editor_visible_width + 1;
+        if self.cursor_x + side_margin >= self.scroll_x + self.editor_visible_width {
+            self.scroll_x = self.cursor_x + side_margin + 1 - self.editor_visible_width;
         }
     }
 
     pub fn type_char(&mut self, c: char) {
         if self.read_only { return; }
+        if !self.extra_cursors.is_empty() {
+            self.edit_at_cursors(|editor| editor.type_char(c));
+            return;
+        }
         // Save state before making changes
-        self.save_state();
+        self.record_edit(Some(c));
         
         let line = &mut self.buffer[self.cursor_y];
         let line_width = line.width();
@@ -249,6 +1073,42 @@ replace_text: None,
         let byte_index = column_to_byte_index(line, self.cursor_x);
         let char_width = c.to_string().width();
 
+        if self.auto_pairs {
+            let before = line[..byte_index].chars().last();
+            let after = line[byte_index..].chars().next();
+            if after == Some(c) && matches!(c, ')' | ']' | '}' | '"' | '\'') {
+                // Step over the closer instead of doubling it
+                self.cursor_x += char_width;
+                self.scroll();
+                return;
+            }
+            // Close only in front of a gap or a closer, and never a quote
+            // that follows a word (an apostrophe)
+            let open_here = after.is_none_or(|a| a.is_whitespace() || matches!(a, ')' | ']' | '}'));
+            let apostrophe = pair_closer(c) == Some(c) && before.is_some_and(is_word_char);
+            if let Some(closer) = pair_closer(c).filter(|_| !self.overwrite_mode && open_here && !apostrophe) {
+                line.insert(byte_index, closer);
+                line.insert(byte_index, c);
+                self.modified = true;
+                self.cursor_x += char_width;
+                self.scroll();
+                return;
+            }
+        }
+
+        let mut byte_index = byte_index;
+        if matches!(c, '}' | ']' | ')') && byte_index > 0 && line[..byte_index].trim().is_empty() {
+            // A closer opening the line goes back one indentation level
+            let dedent = if line[..byte_index].ends_with('\t') {
+                1
+            } else {
+                line[..byte_index].chars().rev().take(self.tab_width).take_while(|&c| c == ' ').count()
+            };
+            line.replace_range(byte_index - dedent..byte_index, "");
+            byte_index -= dedent;
+            self.cursor_x = line[..byte_index].width();
+        }
+
         if self.overwrite_mode {
             if byte_index < line.len() {
                 line.remove(byte_index);
@@ -264,17 +1124,68 @@ replace_text: None,
         self.scroll();
     }
 
+    /// Swaps the word before the cursor with the word after it, leaving
+    /// whatever separates them in place, and moves the cursor past the pair.
+    /// Inside a word, that word is the first of the two. Returns false when
+    /// there are not two words to swap.
+    pub fn transpose_words(&mut self) -> bool {
+        if self.read_only { return false; }
+        let line = &self.buffer[self.cursor_y];
+        let chars: Vec<(usize, char)> = line.char_indices().collect();
+        let cursor = column_to_byte_index(line, self.cursor_x);
+        let mut i = chars.iter().position(|&(b, _)| b >= cursor).unwrap_or(chars.len());
+        // Step to the end of the word the cursor is in
+        while i < chars.len() && i > 0 && is_word_char(chars[i].1) && is_word_char(chars[i - 1].1) {
+            i += 1;
+        }
+        let mut end1 = i;
+        while end1 > 0 && !is_word_char(chars[end1 - 1].1) {
+            end1 -= 1;
+        }
+        let mut start1 = end1;
+        while start1 > 0 && is_word_char(chars[start1 - 1].1) {
+            start1 -= 1;
+        }
+        let mut start2 = i;
+        while start2 < chars.len() && !is_word_char(chars[start2].1) {
+            start2 += 1;
+        }
+        let mut end2 = start2;
+        while end2 < chars.len() && is_word_char(chars[end2].1) {
+            end2 += 1;
+        }
+        if start1 == end1 || start2 == end2 {
+            return false;
+        }
+        let byte = |i: usize| chars.get(i).map_or(line.len(), |&(b, _)| b);
+        let (s1, e1, s2, e2) = (byte(start1), byte(end1), byte(start2), byte(end2));
+        let swapped = format!(
+            "{}{}{}{}{}",
+            &line[..s1],
+            &line[s2..e2],
+            &line[e1..s2],
+            &line[s1..e1],
+            &line[e2..]
+        );
+        self.save_state();
+        self.cursor_x = swapped[..e2].width();
+        self.buffer[self.cursor_y] = swapped;
+        self.modified = true;
+        self.scroll();
+        true
+    }
+
     pub fn delete_char(&mut self) {
         if self.read_only { return; }
-        // Save state before making changes
-        self.save_state();
-        
-        let line = &mut self.buffer[self.cursor_y];
-        let line_width = line.width();
-        if self.virtual_cursor && self.cursor_x >= line_width {
-            // In virtual space, do nothing
+        let line_width = self.buffer[self.cursor_y].width();
+        if self.cursor_x >= line_width && (self.virtual_cursor || self.cursor_y + 1 == self.buffer.len()) {
+            // Nothing to delete in virtual space or at the end of the buffer
             return;
         }
+        // Save state before making changes
+        self.record_edit(None);
+
+        let line = &mut self.buffer[self.cursor_y];
         let byte_index = column_to_byte_index(line, self.cursor_x);
 
         if byte_index < line.len() {
@@ -288,32 +1199,43 @@ replace_text: None,
 
     pub fn backspace(&mut self) {
         if self.read_only { return; }
+        if !self.extra_cursors.is_empty() {
+            self.edit_at_cursors(|editor| editor.backspace());
+            return;
+        }
+        if self.cursor_x == 0 && self.cursor_y == 0 {
+            return;
+        }
+        if self.cursor_x > self.buffer[self.cursor_y].width() {
+            // In virtual space, just move left
+            self.cursor_x -= 1;
+            self.scroll();
+            return;
+        }
         // Save state before making changes
-        self.save_state();
+        self.record_edit(None);
 
         if self.cursor_x > 0 {
+            // Delete the char before cursor
             let line = &mut self.buffer[self.cursor_y];
-            let line_width = line.width();
-            if self.cursor_x <= line_width {
-                // Delete the char before cursor
-                let byte_index = column_to_byte_index(line, self.cursor_x);
-                if byte_index > 0 {
-                    // Find the start of the char before byte_index
-                    let mut prev_char_start = 0;
-                    let mut char_to_remove = ' ';
-                    for (idx, c) in line.char_indices() {
-                        if idx >= byte_index {
-                            break;
-                        }
-                        prev_char_start = idx;
-                        char_to_remove = c;
+            let byte_index = column_to_byte_index(line, self.cursor_x);
+            if byte_index > 0 {
+                // Find the start of the char before byte_index
+                let mut prev_char_start = 0;
+                let mut char_to_remove = ' ';
+                for (idx, c) in line.char_indices() {
+                    if idx >= byte_index {
+                        break;
                     }
-                    line.remove(prev_char_start);
-                    self.cursor_x -= char_to_remove.to_string().width();
+                    prev_char_start = idx;
+                    char_to_remove = c;
                 }
-            } else {
-                // In virtual space, just move left
-                self.cursor_x -= 1;
+                // Deleting the opener of an empty pair takes the closer too
+                if self.auto_pairs && pair_closer(char_to_remove).is_some_and(|closer| line[byte_index..].starts_with(closer)) {
+                    line.remove(byte_index);
+                }
+                line.remove(prev_char_start);
+                self.cursor_x -= char_to_remove.to_string().width();
             }
         } else if self.cursor_y > 0 {
             let prev_line_width = self.buffer[self.cursor_y - 1].width();
@@ -332,12 +1254,32 @@ replace_text: None,
 
     pub fn insert_newline(&mut self) {
         if self.read_only { return; }
+        if !self.extra_cursors.is_empty() {
+            self.edit_at_cursors(|editor| editor.insert_newline());
+            return;
+        }
         // Save state before making changes
-        self.save_state();
+        self.record_edit(None);
         
         let line = &mut self.buffer[self.cursor_y];
         let byte_index = column_to_byte_index(line, self.cursor_x);
         let rest = line[byte_index..].to_string();
+        let before = line[..byte_index].chars().last();
+        let after = rest.chars().next();
+        if self.auto_pairs && matches!((before, after), (Some('{'), Some('}')) | (Some('['), Some(']')) | (Some('('), Some(')'))) {
+            // Open up an empty pair: body on its own indented line, closer
+            // back at the opening line's indentation
+            let indent = leading_indent(line);
+            let body = format!("{}{}", indent, " ".repeat(self.tab_width));
+            line.truncate(byte_index);
+            self.cursor_x = body.width();
+            self.buffer.insert(self.cursor_y + 1, body);
+            self.buffer.insert(self.cursor_y + 2, indent + &rest);
+            self.cursor_y += 1;
+            self.modified = true;
+            self.scroll();
+            return;
+        }
         line.truncate(byte_index);
         self.buffer.insert(self.cursor_y + 1, rest);
         self.cursor_y += 1;
@@ -346,14 +1288,29 @@ replace_text: None,
         self.scroll();
     }
 
-    #[allow(dead_code)]
-    pub fn get_line(&self, y: usize) -> Option<&String> {
-        self.buffer.get(y)
+    /// Opens a new line below the cursor line carrying over its indentation.
+    pub fn open_line_below(&mut self) {
+        if self.read_only { return; }
+        self.save_state();
+
+        let indent = leading_indent(&self.buffer[self.cursor_y]);
+        self.cursor_x = indent.width();
+        self.buffer.insert(self.cursor_y + 1, indent);
+        self.cursor_y += 1;
+        self.modified = true;
+        self.scroll();
     }
 
-    #[allow(dead_code)]
-    pub fn num_lines(&self) -> usize {
-        self.buffer.len()
+    /// Opens a new line above the cursor line carrying over its indentation.
+    pub fn open_line_above(&mut self) {
+        if self.read_only { return; }
+        self.save_state();
+
+        let indent = leading_indent(&self.buffer[self.cursor_y]);
+        self.cursor_x = indent.width();
+        self.buffer.insert(self.cursor_y, indent);
+        self.modified = true;
+        self.scroll();
     }
 
     pub fn select_line(&mut self) {
@@ -377,6 +1334,214 @@ replace_text: None,
         }
     }
 
+    /// Returns the first and last line of the run of non-blank lines around
+    /// `y`, or `None` when `y` is itself blank.
+    pub fn paragraph_bounds(&self, y: usize) -> Option<(usize, usize)> {
+        let is_blank = |idx: usize| self.buffer[idx].trim().is_empty();
+        if y >= self.buffer.len() || is_blank(y) {
+            return None;
+        }
+        let mut start = y;
+        while start > 0 && !is_blank(start - 1) {
+            start -= 1;
+        }
+        let mut end = y;
+        while end + 1 < self.buffer.len() && !is_blank(end + 1) {
+            end += 1;
+        }
+        Some((start, end))
+    }
+
+    /// Selects the paragraph around the cursor as a line selection.
+    pub fn select_paragraph(&mut self) -> bool {
+        if let Some((start, end)) = self.paragraph_bounds(self.cursor_y) {
+            let max_x = self.scroll_x + self.editor_visible_width;
+            self.selection_start = Some((start, 0));
+            self.selection_end = Some((end, max_x));
+            self.selection_mode = SelectionMode::Line;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Top-level blocks found by indentation: a non-blank line at column
+    /// zero, the indented and blank lines after it, and a closing bracket
+    /// line at column zero (continuing past `} else {` style lines).
+    pub fn top_level_blocks(&self) -> Vec<(usize, usize)> {
+        let closers: &[char] = &['}', ')', ']'];
+        let mut blocks = Vec::new();
+        let mut y = 0;
+        while y < self.buffer.len() {
+            let line = &self.buffer[y];
+            if line.trim().is_empty() || line.starts_with(char::is_whitespace) {
+                y += 1;
+                continue;
+            }
+            let mut end = y;
+            let mut j = y + 1;
+            while j < self.buffer.len() {
+                let next = &self.buffer[j];
+                if next.trim().is_empty() {
+                    j += 1;
+                    continue;
+                }
+                if next.starts_with(char::is_whitespace) {
+                    end = j;
+                    j += 1;
+                    continue;
+                }
+                if next.starts_with(closers) {
+                    end = j;
+                    if next.trim_end().ends_with('{') {
+                        j += 1;
+                        continue;
+                    }
+                }
+                break;
+            }
+            blocks.push((y, end));
+            y = end + 1;
+        }
+        blocks
+    }
+
+    /// Folds every multi-line top-level block except the one containing the
+    /// cursor, which is returned. `None` if the cursor is outside any block.
+    pub fn fold_except_current(&mut self) -> Option<(usize, usize)> {
+        let blocks = self.top_level_blocks();
+        let current = *blocks
+            .iter()
+            .find(|(start, end)| (*start..=*end).contains(&self.cursor_y))?;
+        self.folds = blocks
+            .into_iter()
+            .filter(|&(start, end)| end > start && (start, end) != current)
+            .collect();
+        self.folds_line_count = self.buffer.len();
+        self.scroll_y = self.scroll_y.min(current.0);
+        Some(current)
+    }
+
+    pub fn unfold_all(&mut self) -> bool {
+        let had_folds = !self.folds.is_empty();
+        self.folds.clear();
+        had_folds
+    }
+
+    /// The fold that hides line `y`, if any.
+    pub fn fold_hiding(&self, y: usize) -> Option<(usize, usize)> {
+        self.folds.iter().copied().find(|&(start, end)| y > start && y <= end)
+    }
+
+    /// Drops the folds once the line count has changed under them, and opens
+    /// any fold the cursor has jumped into.
+    pub fn sync_folds(&mut self) {
+        if self.folds.is_empty() {
+            return;
+        }
+        if self.buffer.len() != self.folds_line_count {
+            self.folds.clear();
+        } else if let Some(fold) = self.fold_hiding(self.cursor_y) {
+            self.folds.retain(|&f| f != fold);
+        }
+    }
+
+    /// Buffer lines shown on screen from `scroll_y`, skipping folded lines.
+    pub fn visible_rows(&self) -> Vec<usize> {
+        (self.scroll_y..self.buffer.len())
+            .filter(|&y| self.fold_hiding(y).is_none())
+            .take(self.editor_visible_height)
+            .collect()
+    }
+
+    /// Scans backward from just before (line `y`, char `index`) for an
+    /// `open` bracket that is not closed before that point.
+    pub fn find_unmatched_open(&self, y: usize, index: usize, open: char, close: char) -> Option<(usize, usize)> {
+        let mut depth = 0;
+        for line_y in (0..=y).rev() {
+            let chars: Vec<char> = self.buffer[line_y].chars().collect();
+            let end = if line_y == y { index.min(chars.len()) } else { chars.len() };
+            for i in (0..end).rev() {
+                if chars[i] == close {
+                    depth += 1;
+                } else if chars[i] == open {
+                    if depth == 0 {
+                        return Some((line_y, i));
+                    }
+                    depth -= 1;
+                }
+            }
+        }
+        None
+    }
+
+    /// Scans forward from the `open` bracket at (line `y`, char `index`) for
+    /// the `close` bracket matching it.
+    pub fn find_matching_close(&self, y: usize, index: usize, open: char, close: char) -> Option<(usize, usize)> {
+        let mut depth = 0;
+        for line_y in y..self.buffer.len() {
+            let start = if line_y == y { index + 1 } else { 0 };
+            for (i, c) in self.buffer[line_y].chars().enumerate().skip(start) {
+                if c == open {
+                    depth += 1;
+                } else if c == close {
+                    if depth == 0 {
+                        return Some((line_y, i));
+                    }
+                    depth -= 1;
+                }
+            }
+        }
+        None
+    }
+
+    /// The innermost `{ }` block around the cursor, as (line, column) of its
+    /// opening and closing braces. A cursor on a brace counts as inside.
+    pub fn enclosing_braces(&self) -> Option<((usize, usize), (usize, usize))> {
+        let line = &self.buffer[self.cursor_y];
+        let index = line[..column_to_byte_index(line, self.cursor_x)].chars().count();
+        let open = if line.chars().nth(index) == Some('{') {
+            (self.cursor_y, index)
+        } else {
+            self.find_unmatched_open(self.cursor_y, index, '{', '}')?
+        };
+        let close = self.find_matching_close(open.0, open.1, '{', '}')?;
+        let column = |(y, i): (usize, usize)| (y, char_index_to_column(&self.buffer[y], i));
+        Some((column(open), column(close)))
+    }
+
+    /// The bracket matching the `(`, `[`, `{` or closer under the cursor,
+    /// as (line, column). None off a bracket or when it is unbalanced.
+    pub fn matching_bracket(&self) -> Option<(usize, usize)> {
+        let line = self.buffer.get(self.cursor_y)?;
+        let index = line[..column_to_byte_index(line, self.cursor_x)].chars().count();
+        let (y, i) = match line.chars().nth(index)? {
+            '(' => self.find_matching_close(self.cursor_y, index, '(', ')'),
+            '[' => self.find_matching_close(self.cursor_y, index, '[', ']'),
+            '{' => self.find_matching_close(self.cursor_y, index, '{', '}'),
+            ')' => self.find_unmatched_open(self.cursor_y, index, '(', ')'),
+            ']' => self.find_unmatched_open(self.cursor_y, index, '[', ']'),
+            '}' => self.find_unmatched_open(self.cursor_y, index, '{', '}'),
+            _ => None,
+        }?;
+        Some((y, char_index_to_column(&self.buffer[y], i)))
+    }
+
+    /// Moves the cursor to the bracket matching the one under it. Returns
+    /// false, leaving the cursor alone, when there is no match.
+    pub fn jump_to_matching_bracket(&mut self) -> bool {
+        match self.matching_bracket() {
+            Some((y, x)) => {
+                self.cursor_y = y;
+                self.cursor_x = x;
+                self.edit_group = None;
+                self.scroll();
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn select_block(&mut self) {
         if self.selection_mode == SelectionMode::Block && self.selection_start.is_some() {
             // Extend to current position
@@ -440,6 +1605,335 @@ replace_text: None,
         }
     }
 
+    /// Copies the selection to the clipboard.
+    pub fn copy_selection(&mut self) -> bool {
+        match self.selection_text() {
+            Some((_, text)) => {
+                self.clipboard = text.split('\n').map(|s| s.to_string()).collect();
+                self.deselect();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Copies the selection to the clipboard and removes it: whole lines for
+    /// a line selection, the block's columns for a block selection.
+    pub fn cut_selection(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let (scope, text) = match self.selection_text() {
+            Some(selection) => selection,
+            None => return false,
+        };
+        self.clipboard = text.split('\n').map(|s| s.to_string()).collect();
+        self.remove_selection(scope, None);
+        true
+    }
+
+    /// Removes the selected `scope` as one undo step. Selected lines are
+    /// replaced by `replacement` when given; a block always just loses its
+    /// columns.
+    pub fn remove_selection(&mut self, scope: AiScope, replacement: Option<String>) {
+        self.save_state();
+        match scope {
+            AiScope::Lines { start_y, end_y } => {
+                self.buffer.splice(start_y..=end_y, replacement);
+                if self.buffer.is_empty() {
+                    self.buffer.push(String::new());
+                }
+                self.cursor_y = start_y.min(self.buffer.len() - 1);
+                self.cursor_x = 0;
+            }
+            AiScope::Block { start_y, end_y, start_x, end_x } => {
+                self.remove_block_columns(start_y, end_y, start_x, end_x);
+                self.cursor_y = start_y;
+                self.cursor_x = start_x;
+            }
+            AiScope::Buffer => {}
+        }
+        self.modified = true;
+        self.deselect();
+        self.scroll();
+    }
+
+    /// Pastes the clipboard. Without a selection the text goes in at the
+    /// cursor; a line selection is replaced by the clipboard lines; a block
+    /// selection is handled as `block_paste` says. One undo step either way.
+    pub fn paste(&mut self) -> bool {
+        if self.read_only || self.clipboard.is_empty() {
+            return false;
+        }
+        let clip = self.clipboard.clone();
+        let selection = self.selection_text().map(|(scope, _)| scope);
+        self.save_state();
+        match selection {
+            Some(AiScope::Lines { start_y, end_y }) => {
+                self.buffer.splice(start_y..=end_y, clip);
+                self.cursor_y = start_y;
+                self.cursor_x = 0;
+            }
+            Some(AiScope::Block { start_y, end_y, start_x, end_x }) => {
+                match self.block_paste {
+                    BlockPaste::Overwrite => {
+                        for (k, y) in (start_y..=end_y).enumerate() {
+                            let text = fit_to_width(clip.get(k).map(|s| s.as_str()).unwrap_or(""), end_x - start_x);
+                            let line = &mut self.buffer[y];
+                            pad_to_column(line, end_x);
+                            let range = column_to_byte_index(line, start_x)..column_to_byte_index(line, end_x);
+                            line.replace_range(range, &text);
+                        }
+                    }
+                    BlockPaste::Replace => {
+                        self.remove_block_columns(start_y, end_y, start_x, end_x);
+                        for (k, text) in clip.iter().enumerate() {
+                            let y = start_y + k;
+                            if y >= self.buffer.len() {
+                                self.buffer.push(String::new());
+                            }
+                            let line = &mut self.buffer[y];
+                            pad_to_column(line, start_x);
+                            let byte_index = column_to_byte_index(line, start_x);
+                            line.insert_str(byte_index, text);
+                        }
+                    }
+                }
+                self.cursor_y = start_y;
+                self.cursor_x = start_x;
+            }
+            _ => self.splice_at_cursor(&clip),
+        }
+        self.modified = true;
+        self.deselect();
+        self.scroll();
+        true
+    }
+
+    /// Inserts `lines` at the cursor as one undo step, leaving the cursor
+    /// after the inserted text. Used for command output.
+    pub fn insert_lines(&mut self, lines: &[String]) -> bool {
+        if self.read_only || lines.is_empty() {
+            return false;
+        }
+        self.save_state();
+        self.splice_at_cursor(lines);
+        self.modified = true;
+        self.deselect();
+        self.scroll();
+        true
+    }
+
+    /// Splits the cursor line and puts `lines` in between, the first joined
+    /// to the text before the cursor and the last to the text after it.
+    fn splice_at_cursor(&mut self, lines: &[String]) {
+        let line = &mut self.buffer[self.cursor_y];
+        pad_to_column(line, self.cursor_x);
+        let byte_index = column_to_byte_index(line, self.cursor_x);
+        let rest = line.split_off(byte_index);
+        let last = lines.len() - 1;
+        let end_x = if last == 0 { line.width() } else { 0 } + lines[last].width();
+        line.push_str(&lines[0]);
+        let mut tail: Vec<String> = lines[1..].to_vec();
+        match tail.last_mut() {
+            Some(last_line) => last_line.push_str(&rest),
+            None => line.push_str(&rest),
+        }
+        let at = self.cursor_y + 1;
+        self.buffer.splice(at..at, tail);
+        self.cursor_y += last;
+        self.cursor_x = end_x;
+    }
+
+    /// Removes display columns `start_x..end_x` from lines `start_y..=end_y`.
+    fn remove_block_columns(&mut self, start_y: usize, end_y: usize, start_x: usize, end_x: usize) {
+        for line in &mut self.buffer[start_y..=end_y] {
+            let range = column_to_byte_index(line, start_x)..column_to_byte_index(line, end_x);
+            line.replace_range(range, "");
+        }
+    }
+
+    /// Returns the scope and text of the active selection, one row per line.
+    /// Block selections yield only the columns inside the block.
+    pub fn selection_text(&self) -> Option<(AiScope, String)> {
+        let (start, end) = (self.selection_start?, self.selection_end?);
+        let min_y = start.0.min(end.0);
+        let max_y = start.0.max(end.0).min(self.buffer.len() - 1);
+        match self.selection_mode {
+            SelectionMode::Line => Some((
+                AiScope::Lines { start_y: min_y, end_y: max_y },
+                self.buffer[min_y..=max_y].join("\n"),
+            )),
+            SelectionMode::Block => {
+                let min_x = start.1.min(end.1);
+                let max_x = start.1.max(end.1);
+                let text = (min_y..=max_y)
+                    .map(|y| self.extract_block_text(&self.buffer[y], min_x, max_x + 1))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                Some((
+                    AiScope::Block { start_y: min_y, end_y: max_y, start_x: min_x, end_x: max_x + 1 },
+                    text,
+                ))
+            }
+            SelectionMode::None => None,
+        }
+    }
+
+    /// Builds the buffer that results from replacing `ai_scope` with the AI
+    /// response. Lines outside the scope are left untouched.
+    pub fn splice_ai_response(&self, response: &str) -> Vec<String> {
+        let response_lines: Vec<String> = response.lines().map(|s| s.to_string()).collect();
+        let mut result = self.buffer.clone();
+        match self.ai_scope {
+            AiScope::Buffer => result = response_lines,
+            AiScope::Lines { start_y, end_y } => {
+                let start_y = start_y.min(result.len());
+                let end_y = end_y.min(result.len().saturating_sub(1));
+                if start_y <= end_y {
+                    result.splice(start_y..=end_y, response_lines);
+                } else {
+                    result.splice(start_y..start_y, response_lines);
+                }
+            }
+            AiScope::Block { start_y, end_y, start_x, end_x } => {
+                let end_y = end_y.min(result.len().saturating_sub(1));
+                let mut new_rows = response_lines.into_iter();
+                for line in result.iter_mut().take(end_y + 1).skip(start_y) {
+                    // Rows the response does not cover lose their block text
+                    let new_text = new_rows.next().unwrap_or_default();
+                    if line.width() < start_x {
+                        line.push_str(&" ".repeat(start_x - line.width()));
+                    }
+                    let start_byte = column_to_byte_index(line, start_x);
+                    let end_byte = column_to_byte_index(line, end_x);
+                    line.replace_range(start_byte..end_byte, &new_text);
+                }
+                // Any surplus rows become new lines below the block
+                result.splice(end_y + 1..end_y + 1, new_rows);
+            }
+        }
+        if result.is_empty() {
+            result.push(String::new());
+        }
+        result
+    }
+
+    /// Returns the first and last line covered by the selection, or the
+    /// whole buffer when nothing is selected.
+    pub fn selected_line_range(&self) -> (usize, usize) {
+        match (self.selection_start, self.selection_end) {
+            (Some(start), Some(end)) if self.selection_mode != SelectionMode::None => {
+                let last = self.buffer.len() - 1;
+                (start.0.min(end.0).min(last), start.0.max(end.0).min(last))
+            }
+            _ => (0, self.buffer.len() - 1),
+        }
+    }
+
+    /// Rewrites the selected text with `transform` as one undo step: the
+    /// block's columns in each row for a block selection, whole lines for a
+    /// line selection, the cursor line otherwise. Returns how many lines
+    /// changed.
+    pub fn transform_selection(&mut self, transform: impl Fn(&str) -> String) -> usize {
+        if self.read_only { return 0; }
+        let (start, end) = match self.selection_text() {
+            Some(_) => self.selected_line_range(),
+            None => (self.cursor_y, self.cursor_y),
+        };
+        let columns = match (self.selection_start, self.selection_end) {
+            (Some(a), Some(b)) if self.selection_mode == SelectionMode::Block => Some((a.1.min(b.1), a.1.max(b.1) + 1)),
+            _ => None,
+        };
+        let lines: Vec<String> = self.buffer[start..=end]
+            .iter()
+            .map(|line| match columns {
+                // Byte offsets come from the original line; the new text may
+                // be longer or shorter than what it replaces
+                Some((from, to)) => {
+                    let (from, to) = (column_to_byte_index(line, from), column_to_byte_index(line, to));
+                    format!("{}{}{}", &line[..from], transform(&line[from..to]), &line[to..])
+                }
+                None => transform(line),
+            })
+            .collect();
+        let changed = lines.iter().zip(&self.buffer[start..=end]).filter(|(new, old)| new != old).count();
+        if changed > 0 {
+            self.replace_lines(start, end, lines);
+        }
+        changed
+    }
+
+    /// Replaces lines `start..=end` with `lines` as a single undo step.
+    pub fn replace_lines(&mut self, start: usize, end: usize, lines: Vec<String>) -> bool {
+        if self.read_only { return false; }
+        self.save_state();
+
+        self.buffer.splice(start..=end, lines);
+        if self.buffer.is_empty() {
+            self.buffer.push(String::new());
+        }
+        self.cursor_y = self.cursor_y.min(self.buffer.len() - 1);
+        self.modified = true;
+        self.scroll();
+        true
+    }
+
+    /// Prefixes each line of the selection (or buffer) with its line number,
+    /// right-aligned to `width` (default: the widest number) and followed by
+    /// `separator`. Returns how many lines were numbered.
+    pub fn number_lines(&mut self, width: Option<usize>, separator: &str) -> usize {
+        if self.read_only { return 0; }
+        let (start, end) = self.selected_line_range();
+        let width = width.unwrap_or_else(|| (end + 1).to_string().len());
+        self.save_state();
+
+        for y in start..=end {
+            let prefix = format!("{:>width$}{}", y + 1, separator, width = width);
+            if y == self.cursor_y {
+                self.cursor_x += prefix.width();
+            }
+            self.buffer[y].insert_str(0, &prefix);
+        }
+        self.modified = true;
+        self.scroll();
+        end - start + 1
+    }
+
+    /// Removes a leading `<spaces><digits><separator>` prefix, as added by
+    /// `number_lines`, from each line of the selection (or buffer). Lines
+    /// without one are left alone. Returns how many lines changed.
+    pub fn unnumber_lines(&mut self, separator: &str) -> usize {
+        if self.read_only { return 0; }
+        let (start, end) = self.selected_line_range();
+        let prefix_len = |line: &str| {
+            let digits = line.trim_start_matches(' ');
+            let rest = digits.trim_start_matches(|c: char| c.is_ascii_digit());
+            if rest.len() == digits.len() {
+                return None;
+            }
+            rest.strip_prefix(separator).map(|after| line.len() - after.len())
+        };
+        if !(start..=end).any(|y| prefix_len(&self.buffer[y]).is_some()) {
+            return 0;
+        }
+        self.save_state();
+
+        let mut count = 0;
+        for y in start..=end {
+            if let Some(len) = prefix_len(&self.buffer[y]) {
+                if y == self.cursor_y {
+                    self.cursor_x = self.cursor_x.saturating_sub(self.buffer[y][..len].width());
+                }
+                self.buffer[y].replace_range(..len, "");
+                count += 1;
+            }
+        }
+        self.modified = true;
+        self.scroll();
+        count
+    }
+
     pub fn deselect(&mut self) {
         self.selection_start = None;
         self.selection_end = None;
@@ -524,31 +2018,47 @@ replace_text: None,
         }
     }
 
+    /// Scrolls up a page less one line of context, moving the cursor by
+    /// the same amount so it stays on its screen row.
     pub fn page_up(&mut self) {
+        self.edit_group = None;
         if self.editor_visible_height > 0 {
             let page_height = self.editor_visible_height - 1; // Keep one line for context
-            if self.cursor_y >= page_height {
-                self.cursor_y -= page_height;
-            } else {
-                self.cursor_y = 0;
-            }
             self.scroll();
+            self.scroll_y = self.scroll_y.saturating_sub(page_height);
+            self.cursor_y = self.cursor_y.saturating_sub(page_height);
+            if let Some((start, _)) = self.fold_hiding(self.cursor_y) {
+                self.cursor_y = start;
+            }
+            self.clamp_paged_cursor();
         }
     }
 
+    /// Scrolls down a page less one line of context, moving the cursor by
+    /// the same amount so it stays on its screen row. Once the last page is
+    /// showing, the cursor carries on down to the last line.
     pub fn page_down(&mut self) {
+        self.edit_group = None;
         if self.editor_visible_height > 0 {
             let page_height = self.editor_visible_height - 1; // Keep one line for context
-            let new_y = self.cursor_y + page_height;
-            if new_y < self.buffer.len() {
-                self.cursor_y = new_y;
-            } else {
-                self.cursor_y = self.buffer.len() - 1;
-            }
             self.scroll();
+            let max_scroll = self.buffer.len().saturating_sub(self.editor_visible_height);
+            self.scroll_y = (self.scroll_y + page_height).min(max_scroll);
+            self.cursor_y = (self.cursor_y + page_height).min(self.buffer.len() - 1);
+            if let Some((start, end)) = self.fold_hiding(self.cursor_y) {
+                self.cursor_y = if end + 1 < self.buffer.len() { end + 1 } else { start };
+            }
+            self.clamp_paged_cursor();
         }
     }
 
+    fn clamp_paged_cursor(&mut self) {
+        if !self.virtual_cursor {
+            self.cursor_x = self.cursor_x.min(self.buffer[self.cursor_y].width());
+        }
+        self.scroll();
+    }
+
     pub fn add_to_history(&mut self, command: String) {
         if !command.trim().is_empty() {
             self.command_history.push(command);
@@ -583,6 +2093,65 @@ replace_text: None,
         }
     }
 
+    /// Ctrl+R on the command line: starts a reverse search of the history,
+    /// or moves on to the next older entry that matches.
+    pub fn history_search_older(&mut self) {
+        let (query, from) = match self.history_search.take() {
+            Some((query, Some(index))) => (query, index),
+            Some((query, None)) => (query, 0),
+            None => {
+                self.temp_command_buffer = self.command_buffer.clone();
+                (String::new(), self.command_history.len())
+            }
+        };
+        // Skip repeats of the entry already shown
+        let shown = self.command_buffer.clone();
+        let found = self.command_history[..from]
+            .iter()
+            .rposition(|entry| entry.contains(&query) && *entry != shown);
+        self.show_history_match(query, found);
+    }
+
+    /// Adds `c` to the search query, or with `None` takes the last
+    /// character off, and shows the newest entry that still matches.
+    pub fn history_search_edit(&mut self, c: Option<char>) {
+        let Some((mut query, index)) = self.history_search.take() else { return };
+        let from = match c {
+            Some(c) => {
+                query.push(c);
+                // A longer query can only match the current entry or older
+                index.map_or(0, |index| index + 1)
+            }
+            None => {
+                query.pop();
+                self.command_history.len()
+            }
+        };
+        let found = self.command_history[..from].iter().rposition(|entry| entry.contains(&query));
+        self.show_history_match(query, found);
+    }
+
+    fn show_history_match(&mut self, query: String, found: Option<usize>) {
+        if let Some(index) = found {
+            self.command_buffer = self.command_history[index].clone();
+            self.command_cursor = self.command_buffer.len();
+        }
+        self.history_search = Some((query, found));
+    }
+
+    /// Ends the history search, keeping the match for editing.
+    pub fn history_search_accept(&mut self) {
+        self.history_search = None;
+        self.history_index = self.command_history.len();
+    }
+
+    /// Ends the history search, bringing back what was typed before it.
+    pub fn history_search_cancel(&mut self) {
+        self.history_search = None;
+        self.command_buffer = self.temp_command_buffer.clone();
+        self.command_cursor = self.command_buffer.len();
+    }
+
     pub fn command_move_left(&mut self) {
         if self.command_cursor > 0 {
             self.command_cursor -= 1;
@@ -617,63 +2186,104 @@ replace_text: None,
             self.command_cursor += 1;
         }
     }
-
-    pub fn parse_find_command(cmd: &str) -> Option<(String, bool)> {
-        let cmd = cmd.trim();
-        if !cmd.starts_with("find ") {
-            return None;
-        }
-        
-        let rest = cmd[5..].trim();
-        if rest.is_empty() {
-            return None;
-        }
-        
-        // Determine quote type and extract search string
-        let (search_str, remaining) = if rest.starts_with('"') {
-            // Double quoted string
-            if let Some(end_quote) = rest[1..].find('"') {
-                (&rest[1..=end_quote], &rest[end_quote + 2..])
-            } else {
-                return None;
-            }
-        } else if rest.starts_with('\'') {
-            // Single quoted string
-            if let Some(end_quote) = rest[1..].find('\'') {
-                (&rest[1..=end_quote], &rest[end_quote + 2..])
-            } else {
-                return None;
-            }
-        } else {
-            return None;
-        };
-        
+
+    pub fn parse_find_command(cmd: &str) -> Option<(String, bool)> {
+        let rest = cmd.trim().strip_prefix("find ")?;
+        let (search_str, remaining) = split_quoted(rest)?;
+
         // Check for case insensitive flag
         let case_sensitive = !remaining.trim().contains("ins");
         
         Some((search_str.to_string(), case_sensitive))
     }
 
+    /// Parses `replace "old" "new" [ins] [all]` into the text to find, its
+    /// replacement, whether to match case and whether to replace every match.
+    pub fn parse_replace_command(cmd: &str) -> Option<(String, String, bool, bool)> {
+        let rest = cmd.trim().strip_prefix("replace ")?;
+        let (find_str, remaining) = split_quoted(rest)?;
+        let (replace_str, remaining) = split_quoted(remaining)?;
+        let mut case_sensitive = true;
+        let mut all = false;
+        for flag in remaining.split_whitespace() {
+            match flag {
+                "ins" => case_sensitive = false,
+                "all" => all = true,
+                _ => return None,
+            }
+        }
+        Some((find_str.to_string(), replace_str.to_string(), case_sensitive, all))
+    }
+
+    /// Records the current buffer as an undo point, ahead of a change.
+    /// `undo_history[undo_index]` is the last recorded state; edits made
+    /// since then are recorded by the next call (or by `undo`).
     pub fn save_state(&mut self) {
-        // Save current buffer state to undo history
-        let current_state = self.buffer.clone();
-        
-        // If we're not at the latest state, truncate history
-        if self.undo_index < self.undo_history.len() - 1 {
+        self.edit_group = None;
+        self.scope_braces = None;
+        if self.undo_history[self.undo_index] != self.buffer {
+            // A new change drops whatever could have been redone
             self.undo_history.truncate(self.undo_index + 1);
+            self.undo_history.push(self.buffer.clone());
+            self.undo_index += 1;
         }
-        
-        // Add new state
-        self.undo_history.push(current_state);
-        self.undo_index += 1;
     }
 
+    /// Starts an undo step spanning several edits, such as a macro replay.
+    /// Pass the result to `end_undo_group`.
+    pub fn begin_undo_group(&mut self) -> usize {
+        self.save_state();
+        self.undo_index
+    }
+
+    /// Collapses the undo points recorded since `begin_undo_group` into one.
+    pub fn end_undo_group(&mut self, start: usize) {
+        self.save_state();
+        if self.undo_index > start + 1 {
+            self.undo_history.drain(start + 1..self.undo_index);
+            self.undo_index = start + 1;
+        }
+    }
+
+    /// Records an undo point before a keystroke edit, unless the configured
+    /// granularity makes this keystroke part of the previous step.
+    fn record_edit(&mut self, typed: Option<char>) {
+        let word = typed.is_some_and(is_word_char);
+        let continues = match (self.undo_granularity, self.edit_group) {
+            (UndoGranularity::Word, Some((y, x, was_word))) => {
+                word && was_word && y == self.cursor_y && x == self.cursor_x
+            }
+            (UndoGranularity::Line, Some((y, _, _))) => y == self.cursor_y,
+            _ => false,
+        };
+        if !continues {
+            self.save_state();
+        }
+        let next_x = typed.map_or(usize::MAX, |c| self.cursor_x + c.to_string().width());
+        self.edit_group = Some((self.cursor_y, next_x, word));
+    }
+
+    /// Whether the file has been modified on disk since it was loaded or
+    /// last saved from here.
+    pub fn changed_on_disk(&self) -> bool {
+        match (&self.filename, self.disk_mtime) {
+            (Some(path), Some(loaded)) => file_mtime(path).is_some_and(|now| now != loaded),
+            _ => false,
+        }
+    }
+
+    /// Makes the current buffer the baseline `modified` is measured against.
+    /// Saving leaves the undo history alone; the next keystroke starts a new
+    /// undo step, so undoing it returns to exactly what was saved.
     pub fn mark_as_saved(&mut self) {
+        self.edit_group = None;
         self.last_save_state = Some(self.buffer.clone());
         self.modified = false;
     }
 
     pub fn undo(&mut self) -> bool {
+        // Record edits made since the last undo point so redo can return
+        self.save_state();
         // Can't undo if we're at the beginning of history
         if self.undo_index == 0 {
             return false;
@@ -700,6 +2310,8 @@ replace_text: None,
     }
 
     pub fn redo(&mut self) -> bool {
+        // Edits made after an undo replace the redo history
+        self.save_state();
         // Can't redo if we're at the latest state
         if self.undo_index >= self.undo_history.len() - 1 {
             return false;
@@ -725,23 +2337,11 @@ replace_text: None,
         true
     }
 
-    pub fn can_undo(&self) -> bool {
-        self.undo_index > 0
-    }
-
-    pub fn can_redo(&self) -> bool {
-        self.undo_index < self.undo_history.len() - 1
-    }
-
-    pub fn get_undo_info(&self) -> (usize, usize) {
-        // Returns (current_position, total_states)
-        (self.undo_index, self.undo_history.len())
-    }
-
-    pub fn sort_all(&mut self, sort_specs: Vec<(usize, usize, bool)>) -> bool {
+    pub fn sort_all(&mut self, sort_specs: Vec<(usize, usize, bool, bool)>) -> bool {
         if self.buffer.is_empty() {
             return false;
         }
+        self.last_sort_specs = Some(sort_specs.clone());
 
         // Save state before sorting
         self.save_state();
@@ -751,7 +2351,7 @@ replace_text: None,
 
         for (idx, line) in self.buffer.iter().enumerate() {
             let mut sort_keys = Vec::new();
-            for &(start_col, end_col, _) in &sort_specs {
+            for &(start_col, end_col, _, _) in &sort_specs {
                 let key = self.extract_sort_key(line, start_col, end_col);
                 sort_keys.push(key);
             }
@@ -759,23 +2359,7 @@ replace_text: None,
         }
 
         // Sort using the sort specifications
-        indexed_lines.sort_by(|a, b| {
-            for (i, &(_, _, asc)) in sort_specs.iter().enumerate() {
-                let key_a = &a.2[i];
-                let key_b = &b.2[i];
-                
-                let cmp = if asc {
-                    key_a.cmp(key_b)
-                } else {
-                    key_b.cmp(key_a)
-                };
-                
-                if cmp != std::cmp::Ordering::Equal {
-                    return cmp;
-                }
-            }
-            std::cmp::Ordering::Equal
-        });
+        indexed_lines.sort_by(|a, b| compare_sort_keys(&a.2, &b.2, &sort_specs));
 
         // Update buffer with sorted lines
         for (i, (_, line, _)) in indexed_lines.into_iter().enumerate() {
@@ -786,8 +2370,9 @@ replace_text: None,
         true
     }
 
-    pub fn sort_block(&mut self, sort_specs: Vec<(usize, usize, bool)>) -> bool {
+    pub fn sort_block(&mut self, sort_specs: Vec<(usize, usize, bool, bool)>) -> bool {
         if let (Some(start), Some(end)) = (self.selection_start, self.selection_end) {
+            self.last_sort_specs = Some(sort_specs.clone());
             // Save state before sorting
             self.save_state();
 
@@ -803,7 +2388,7 @@ replace_text: None,
                         if y < self.buffer.len() {
                             let line = &self.buffer[y];
                             let mut sort_keys = Vec::new();
-                            for &(start_col, end_col, _) in &sort_specs {
+                            for &(start_col, end_col, _, _) in &sort_specs {
                                 let key = self.extract_sort_key(line, start_col, end_col);
                                 sort_keys.push(key);
                             }
@@ -812,23 +2397,7 @@ replace_text: None,
                     }
 
                     // Sort the selected lines
-                    selected_lines.sort_by(|a, b| {
-                        for (i, &(_, _, asc)) in sort_specs.iter().enumerate() {
-                            let key_a = &a.2[i];
-                            let key_b = &b.2[i];
-                            
-                            let cmp = if asc {
-                                key_a.cmp(key_b)
-                            } else {
-                                key_b.cmp(key_a)
-                            };
-                            
-                            if cmp != std::cmp::Ordering::Equal {
-                                return cmp;
-                            }
-                        }
-                        std::cmp::Ordering::Equal
-                    });
+                    selected_lines.sort_by(|a, b| compare_sort_keys(&a.2, &b.2, &sort_specs));
 
                     // Update buffer with sorted lines
                     for (i, (_, line, _)) in selected_lines.into_iter().enumerate() {
@@ -849,10 +2418,10 @@ replace_text: None,
                             let block_text = self.extract_block_text(line, min_x, end_col);
                             
                             let mut sort_keys = Vec::new();
-                            for &(start_col, end_col, _) in &sort_specs {
+                            for &(start_col, end_col, _, _) in &sort_specs {
                                 // Adjust column positions relative to block start
-                                let adjusted_start = if start_col >= min_x { start_col - min_x } else { 0 };
-                                let adjusted_end = if end_col >= min_x { end_col - min_x } else { 0 };
+                                let adjusted_start = start_col.saturating_sub(min_x);
+                                let adjusted_end = end_col.saturating_sub(min_x);
                                 let key = self.extract_sort_key(&block_text, adjusted_start, adjusted_end);
                                 sort_keys.push(key);
                             }
@@ -861,23 +2430,7 @@ replace_text: None,
                     }
 
                     // Sort the block content
-                    block_content.sort_by(|a, b| {
-                        for (i, &(_, _, asc)) in sort_specs.iter().enumerate() {
-                            let key_a = &a.2[i];
-                            let key_b = &b.2[i];
-                            
-                            let cmp = if asc {
-                                key_a.cmp(key_b)
-                            } else {
-                                key_b.cmp(key_a)
-                            };
-                            
-                            if cmp != std::cmp::Ordering::Equal {
-                                return cmp;
-                            }
-                        }
-                        std::cmp::Ordering::Equal
-                    });
+                    block_content.sort_by(|a, b| compare_sort_keys(&a.2, &b.2, &sort_specs));
 
                     // Update buffer with sorted block content
                     for (i, (_, sorted_block, _)) in block_content.into_iter().enumerate() {
@@ -900,6 +2453,57 @@ replace_text: None,
         }
     }
 
+    /// Adds `delta` to the number in each row of the block selection and
+    /// writes it back right-aligned in the block's width, as one undo step.
+    /// Cells that are not a number are skipped. Returns how many cells
+    /// changed and how many results were too wide to fit, or None without
+    /// a block selection.
+    pub fn increment_block(&mut self, delta: i64) -> Option<(usize, usize)> {
+        let (start, end) = (self.selection_start?, self.selection_end?);
+        if self.selection_mode != SelectionMode::Block {
+            return None;
+        }
+        let min_y = start.0.min(end.0);
+        let max_y = start.0.max(end.0).min(self.buffer.len() - 1);
+        let min_x = start.1.min(end.1);
+        let end_col = start.1.max(end.1) + 1;
+        let width = end_col - min_x;
+
+        let mut lines = self.buffer[min_y..=max_y].to_vec();
+        let (mut changed, mut too_wide) = (0, 0);
+        for line in &mut lines {
+            let cell = self.extract_block_text(line, min_x, end_col);
+            let Some(value) = cell.trim().parse::<i64>().ok().and_then(|n| n.checked_add(delta)) else {
+                continue;
+            };
+            let text = format!("{:>width$}", value, width = width);
+            if text.len() > width {
+                too_wide += 1;
+                continue;
+            }
+            let start_byte = column_to_byte_index(line, min_x);
+            let end_byte = column_to_byte_index(line, end_col);
+            line.replace_range(start_byte..end_byte, &text);
+            changed += 1;
+        }
+        if changed > 0 && !self.replace_lines(min_y, max_y, lines) {
+            return Some((0, 0));
+        }
+        Some((changed, too_wide))
+    }
+
+    /// Writes sort specs the way they are typed: 1-based inclusive columns,
+    /// `d` marking a descending key, e.g. `1-10 15-20d`.
+    pub fn format_sort_specs(sort_specs: &[(usize, usize, bool, bool)]) -> String {
+        sort_specs
+            .iter()
+            .map(|&(start, end, asc, numeric)| {
+                format!("{}-{}{}{}", start + 1, end, if numeric { "n" } else { "" }, if asc { "" } else { "d" })
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
     fn extract_sort_key(&self, line: &str, start_col: usize, end_col: usize) -> String {
         let line_width = line.width();
         
@@ -969,6 +2573,7 @@ replace_text: None,
         }
 
         self.search_target = Some(target.to_string());
+        self.replace_text = None;
         self.search_scope = scope.clone();
         self.search_case_sensitive = case_sensitive;
         self.search_matches.clear();
@@ -982,14 +2587,6 @@ replace_text: None,
                     self.find_matches_in_line(line, line_idx);
                 }
             }
-            SearchScope::Line => {
-                for (line_idx, line) in self.buffer.iter().enumerate() {
-                    if let Some((start_col, end_col)) = self.find_first_match_in_line(line) {
-                        self.search_matches.push((line_idx, start_col, end_col));
-                        break; // Only first match per line
-                    }
-                }
-            }
             SearchScope::Block => {
                 if let (Some(start), Some(end)) = (self.selection_start, self.selection_end) {
                     let min_y = start.0.min(end.0);
@@ -1035,47 +2632,20 @@ replace_text: None,
         true
     }
 
+    /// Records every match in `line` as `(line_idx, start_col, end_col)`
+    /// display columns.
     fn find_matches_in_line(&mut self, line: &str, line_idx: usize) {
         self.matches_in_last_line = 0;
-        let search_line = if self.search_case_sensitive {
-            line.to_string()
-        } else {
-            line.to_lowercase()
-        };
-        let search_target = if self.search_case_sensitive {
-            self.search_target.as_ref().unwrap().clone()
-        } else {
-            self.search_target.as_ref().unwrap().to_lowercase()
-        };
-
-        let mut start = 0;
-        while let Some(pos) = search_line[start..].find(&search_target) {
-            let abs_start = start + pos;
-            let abs_end = abs_start + search_target.len();
-            self.search_matches.push((line_idx, abs_start, abs_end));
-            self.matches_in_last_line += 1;
-            start = abs_start + 1; // Move past current match to avoid infinite loop
+        let target = self.search_target.as_deref().unwrap_or("");
+        if target.is_empty() {
+            return;
         }
-    }
-
-    fn find_first_match_in_line(&self, line: &str) -> Option<(usize, usize)> {
-        let search_line = if self.search_case_sensitive {
-            line.to_string()
-        } else {
-            line.to_lowercase()
-        };
-        let search_target = if self.search_case_sensitive {
-            self.search_target.as_ref().unwrap().clone()
-        } else {
-            self.search_target.as_ref().unwrap().to_lowercase()
-        };
-
-        if let Some(pos) = search_line.find(&search_target) {
-            let start = pos;
-            let end = pos + search_target.len();
-            Some((start, end))
-        } else {
-            None
+        let mut from = 0;
+        while let Some((start, end)) = find_in_line(line, target, self.search_case_sensitive, from) {
+            self.search_matches.push((line_idx, line[..start].width(), line[..end].width()));
+            self.matches_in_last_line += 1;
+            // Move past the first character of the match to avoid an infinite loop
+            from = start + line[start..].chars().next().map_or(1, char::len_utf8);
         }
     }
 
@@ -1084,96 +2654,40 @@ replace_text: None,
             let (line_idx, start_col, _) = self.search_matches[match_index];
             self.cursor_y = line_idx;
             self.cursor_x = start_col;
+            self.edit_group = None;
             self.scroll();
         }
     }
 
-    pub fn get_current_match_highlight(&self) -> Option<(usize, usize, usize)> {
-        if !self.search_matches.is_empty() && self.current_match_index < self.search_matches.len() {
-            Some(self.search_matches[self.current_match_index])
-        } else {
-            None
-        }
-    }
-
-    pub fn clear_search(&mut self) {
-        self.search_target = None;
-        self.search_matches.clear();
-        self.current_match_index = 0;
-    }
-
-    pub fn replace(&mut self, find_text: &str, replace_text: &str, scope: SearchScope, replace_all: bool, case_sensitive: bool) -> bool {
-        if find_text.is_empty() {
-            return false;
+    /// Finds `find_text` within `scope`. With `replace_all` every match is
+    /// replaced at once, as one undo step; otherwise the cursor moves to the
+    /// first match and F1 replaces the matches one at a time with
+    /// `replace_next`. Returns the number of matches found.
+    pub fn replace(&mut self, find_text: &str, replace_text: &str, scope: SearchScope, replace_all: bool, case_sensitive: bool) -> usize {
+        if !self.find(find_text, scope.clone(), case_sensitive) {
+            return 0;
         }
+        let count = self.search_matches.len();
 
-        // Save state before replacing
-        self.save_state();
-
-        // Set up search for finding matches
-        self.search_target = Some(find_text.to_string());
-        self.search_scope = scope.clone();
-        self.search_case_sensitive = case_sensitive;
-        self.search_matches.clear();
-        self.current_match_index = 0;
-
-        // Find all matches based on scope
-        match scope {
-            SearchScope::All => {
-                let lines = self.buffer.clone();
-                for (line_idx, line) in lines.iter().enumerate() {
-                    self.find_matches_in_line(line, line_idx);
-                }
-            }
-            SearchScope::Line => {
-                for (line_idx, line) in self.buffer.iter().enumerate() {
-                    if let Some((start_col, end_col)) = self.find_first_match_in_line(line) {
-                        self.search_matches.push((line_idx, start_col, end_col));
-                    }
-                }
-            }
-            SearchScope::Block => {
-                if let (Some(start), Some(end)) = (self.selection_start, self.selection_end) {
-                    let min_y = start.0.min(end.0);
-                    let max_y = start.0.max(end.0);
-                    let min_x = start.1.min(end.1);
-                    let max_x = start.1.max(end.1);
-
-                    for line_idx in min_y..=max_y {
-                        if line_idx < self.buffer.len() {
-                            let line = &self.buffer[line_idx].clone();
-                            let block_text = self.extract_block_text(line, min_x, max_x + 1);
-                            self.find_matches_in_line(&block_text, line_idx);
-                            
-                            // Adjust match positions relative to block start
-                            for match_idx in (self.search_matches.len() - self.matches_in_last_line)..self.search_matches.len() {
-                                let (line, start, end) = self.search_matches[match_idx];
-                                self.search_matches[match_idx] = (line, start + min_x, end + min_x);
-                            }
-                        }
+        if replace_all {
+            self.save_state();
+            match scope {
+                SearchScope::All => self.replace_all_instances(find_text, replace_text, case_sensitive),
+                SearchScope::Block => {
+                    // Right to left, so earlier columns stay where they were
+                    for (line_idx, start_col, end_col) in std::mem::take(&mut self.search_matches).into_iter().rev() {
+                        self.perform_replace(line_idx, start_col, end_col, replace_text);
                     }
-                } else {
-                    return false; // No block selected
                 }
             }
-        }
-
-        if self.search_matches.is_empty() {
-            return false;
-        }
-
-        if replace_all {
-            // Replace all instances at once
-            self.replace_all_instances(find_text, replace_text, case_sensitive);
+            self.search_matches.clear();
+            self.replace_text = None;
         } else {
             // Set up for F1 navigation (replace one at a time)
             self.replace_text = Some(replace_text.to_string());
-            self.replace_all = false;
-            // Move to first match
-            self.move_to_match(0);
         }
 
-        true
+        count
     }
 
     pub fn replace_next(&mut self) -> bool {
@@ -1185,34 +2699,22 @@ replace_text: None,
             let (line_idx, start_col, end_col) = self.search_matches[self.current_match_index];
             
             // Perform replacement on current match
+            self.save_state();
             self.perform_replace(line_idx, start_col, end_col, &replace_text);
             
-            // Recalculate matches after replacement
-            let scope = self.search_scope.clone();
-            let _case_sensitive = self.search_case_sensitive;
-            let _find_text = self.search_target.as_ref().unwrap().clone();
-            
             // Clear and rebuild search matches
             self.search_matches.clear();
             self.current_match_index = 0;
             
-            match scope {
-            SearchScope::All => {
-                let lines = self.buffer.clone();
-                for (line_idx, line) in lines.iter().enumerate() {
-                    self.find_matches_in_line(line, line_idx);
-                }
-            }
-            SearchScope::Line => {
-                // Find first match on each line only
-                for (line_idx, line) in self.buffer.iter().enumerate() {
-                    if let Some((start_col, end_col)) = self.find_first_match_in_line(line) {
-                        self.search_matches.push((line_idx, start_col, end_col));
+            match self.search_scope {
+                SearchScope::All => {
+                    let lines = self.buffer.clone();
+                    for (line_idx, line) in lines.iter().enumerate() {
+                        self.find_matches_in_line(line, line_idx);
                     }
                 }
-            }
-            SearchScope::Block => {
-                if let (Some(start), Some(end)) = (self.selection_start, self.selection_end) {
+                SearchScope::Block => {
+                    if let (Some(start), Some(end)) = (self.selection_start, self.selection_end) {
                         let min_y = start.0.min(end.0);
                         let max_y = start.0.max(end.0);
                         let min_x = start.1.min(end.1);
@@ -1228,16 +2730,25 @@ replace_text: None,
                                 for match_idx in (self.search_matches.len() - self.matches_in_last_line)..self.search_matches.len() {
                                     let (line, start, end) = self.search_matches[match_idx];
                                     self.search_matches[match_idx] = (line, start + min_x, end + min_x);
-             }
-         }
+                                }
+                            }
                         }
                     }
                 }
             }
             
-            // Move to next available match
+            // Continue with the first match after the replaced text, wrapping
+            // to the top once the last one is done
+            let resume = (line_idx, start_col + replace_text.width());
             if !self.search_matches.is_empty() {
+                self.current_match_index = self
+                    .search_matches
+                    .iter()
+                    .position(|&(line, start, _)| (line, start) >= resume)
+                    .unwrap_or(0);
                 self.move_to_match(self.current_match_index);
+            } else {
+                self.replace_text = None;
             }
         }
 
@@ -1245,87 +2756,104 @@ replace_text: None,
     }
 
     fn replace_all_instances(&mut self, find_text: &str, replace_text: &str, case_sensitive: bool) {
-        let search_target = if case_sensitive {
-            find_text.to_string()
-        } else {
-            find_text.to_lowercase()
-        };
-
-        for line_idx in 0..self.buffer.len() {
-            let line = self.buffer[line_idx].clone();
-        let mut search_line = if case_sensitive {
-            line.to_string()
-        } else {
-            line.to_lowercase()
-        };
-
-            let mut result_line = line;
+        if find_text.is_empty() {
+            return;
+        }
+        for line in &mut self.buffer {
+            // Positions are bytes of the line as it is being rewritten
             let mut offset = 0;
-            
-            while let Some(pos) = search_line[offset..].find(&search_target) {
-                let abs_pos = offset + pos;
-                let end_pos = abs_pos + find_text.len();
-                
-                // Perform replacement
-                let start_byte = column_to_byte_index(&result_line, abs_pos);
-                let end_byte = column_to_byte_index(&result_line, end_pos);
-                result_line.replace_range(start_byte..end_byte, replace_text);
-                
-                // Update search line for next iteration
-                search_line = if case_sensitive {
-                    result_line.clone()
-                } else {
-                    result_line.to_lowercase()
-                };
-                
-                offset = abs_pos + replace_text.len();
+            while let Some((start, end)) = find_in_line(line, find_text, case_sensitive, offset) {
+                line.replace_range(start..end, replace_text);
+                offset = start + replace_text.len();
             }
-            
-            self.buffer[line_idx] = result_line;
         }
-        
+
         self.modified = true;
     }
 
+    /// Replaces the text between display columns `start_col` and `end_col`
+    /// of line `line_idx`.
     fn perform_replace(&mut self, line_idx: usize, start_col: usize, end_col: usize, replace_text: &str) {
         let line = &mut self.buffer[line_idx];
         let start_byte = column_to_byte_index(line, start_col);
         let end_byte = column_to_byte_index(line, end_col);
-        
-        // Handle text pulling/pushing based on length difference
-        let original_width = end_col - start_col;
-        let replace_width = replace_text.width();
-        
-        if replace_width < original_width {
-            // Replacement is shorter - pull text from right
-            line.replace_range(start_byte..end_byte, replace_text);
-            // Remove extra spaces
-            let _remaining_to_remove = original_width - replace_width;
-            let current_end_byte = column_to_byte_index(line, start_col + replace_width);
-            let remove_end_byte = column_to_byte_index(line, start_col + original_width);
-            if remove_end_byte > current_end_byte {
-                line.replace_range(current_end_byte..remove_end_byte, "");
-            }
-        } else if replace_width > original_width {
-            // Replacement is longer - push text to right
-            line.replace_range(start_byte..end_byte, replace_text);
-        } else {
-            // Same length - direct replacement
-            line.replace_range(start_byte..end_byte, replace_text);
-        }
-        
+        line.replace_range(start_byte..end_byte, replace_text);
         self.modified = true;
     }
 
-    pub fn start_diff_mode(&mut self, modified_buffer: Vec<String>) {
+    /// The buffer lines an AI request for `ai_scope` covers.
+    fn ai_target_lines(&self) -> Option<Range<usize>> {
+        match self.ai_scope {
+            AiScope::Buffer => None,
+            AiScope::Lines { start_y, end_y } | AiScope::Block { start_y, end_y, .. } => {
+                let end = (end_y + 1).min(self.buffer.len());
+                Some(start_y.min(end)..end)
+            }
+        }
+    }
+
+    /// Reviews an AI response as a diff against just the lines it was asked
+    /// to change, so accepting it cannot touch the rest of the buffer. With
+    /// `ai_diff_target_only` off the whole buffer is diffed instead.
+    pub fn start_ai_diff(&mut self, response: &str) {
+        let spliced = self.splice_ai_response(response);
+        match self.ai_target_lines().filter(|_| self.ai_diff_target_only) {
+            Some(target) => {
+                // Everything after the target is unchanged, so the new text
+                // ends as far from the end of the buffer as the old one did
+                let end = spliced.len() - (self.buffer.len() - target.end).min(spliced.len());
+                let replacement = spliced[target.start.min(end)..end].to_vec();
+                self.start_diff_mode(Some(target), replacement);
+            }
+            None => self.start_diff_mode(None, spliced),
+        }
+    }
+
+    /// Reviews the edits made since `disk`, the file as saved. Every hunk
+    /// starts out accepted, so leaving the review keeps the buffer as it
+    /// was and rejecting a hunk reverts that edit. Returns false, without
+    /// entering diff mode, when there is nothing to review.
+    pub fn start_disk_diff(&mut self, disk: Vec<String>) -> bool {
+        let edited = std::mem::replace(&mut self.buffer, disk);
+        self.start_diff_mode(None, edited);
+        let DiffMode::Active { hunks, modified_buffer, .. } = &mut self.diff_mode else {
+            return false;
+        };
+        if hunks.is_empty() {
+            self.buffer = std::mem::take(modified_buffer);
+            self.diff_mode = DiffMode::Inactive;
+            return false;
+        }
+        for hunk in hunks.iter_mut() {
+            hunk.accepted = true;
+        }
+        self.update_buffer_with_accepted_hunks();
+        true
+    }
+
+    /// Enters diff review of `modified_buffer` as the new text for `target`
+    /// (the whole buffer when `None`).
+    pub fn start_diff_mode(&mut self, target: Option<Range<usize>>, modified_buffer: Vec<String>) {
         let original_buffer = self.buffer.clone();
-        let hunks = self.compute_diff(&original_buffer, &modified_buffer);
-        
+        let hunks = match &target {
+            Some(target) => {
+                let mut hunks = self.compute_diff(&original_buffer[target.clone()], &modified_buffer);
+                for hunk in &mut hunks {
+                    hunk.old_start += target.start;
+                    hunk.new_start += target.start;
+                }
+                hunks
+            }
+            None => self.compute_diff(&original_buffer, &modified_buffer),
+        };
+
         self.diff_mode = DiffMode::Active {
             original_buffer,
+            target,
             modified_buffer,
             hunks,
             current_hunk: 0,
+            current_line: 0,
             accept_all: false,
         };
         
@@ -1335,15 +2863,6 @@ replace_text: None,
         }
     }
 
-    fn should_use_temp_file(&self, buffer_size: &[String]) -> bool {
-        // Use temp file for buffers larger than 1MB or 10000 lines
-        const SIZE_THRESHOLD: usize = 1024 * 1024; // 1MB
-        const LINE_THRESHOLD: usize = 10000;
-        
-        let total_size: usize = buffer_size.iter().map(|line| line.len()).sum();
-        total_size > SIZE_THRESHOLD || buffer_size.len() > LINE_THRESHOLD
-    }
-
     pub fn get_hunks(&self) -> &[Hunk] {
         static EMPTY_HUNKS: Vec<Hunk> = Vec::new();
         match &self.diff_mode {
@@ -1360,9 +2879,10 @@ replace_text: None,
     }
 
     pub fn show_hunk(&mut self, hunk_index: usize) {
-        if let DiffMode::Active { hunks, current_hunk, .. } = &mut self.diff_mode {
+        if let DiffMode::Active { hunks, current_hunk, current_line, .. } = &mut self.diff_mode {
             if hunk_index < hunks.len() {
                 *current_hunk = hunk_index;
+                *current_line = 0;
                 // Update buffer to show current state with this hunk applied
                 self.update_buffer_with_accepted_hunks();
             }
@@ -1392,10 +2912,94 @@ replace_text: None,
         }
     }
 
+    /// Moves the line cursor within the current hunk.
+    pub fn move_hunk_line(&mut self, delta: isize) {
+        if let DiffMode::Active { hunks, current_hunk, current_line, .. } = &mut self.diff_mode {
+            let last = hunks[*current_hunk].lines.len().saturating_sub(1);
+            *current_line = current_line.saturating_add_signed(delta).min(last);
+        }
+    }
+
+    /// Takes the change under the line cursor in or out of the current
+    /// hunk. A hunk not accepted yet becomes accepted with only that change;
+    /// one left with no changes becomes rejected. Returns false on a
+    /// context line.
+    pub fn toggle_hunk_line(&mut self) -> bool {
+        let DiffMode::Active { hunks, current_hunk, current_line, .. } = &mut self.diff_mode else {
+            return false;
+        };
+        let hunk = &mut hunks[*current_hunk];
+        let changes: Vec<usize> = hunk
+            .lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| !matches!(line, DiffLine::Context(_)))
+            .map(|(i, _)| i)
+            .collect();
+        if !changes.contains(current_line) {
+            return false;
+        }
+        if !hunk.accepted {
+            hunk.excluded = changes.iter().copied().filter(|i| i != current_line).collect();
+        } else {
+            // Spell out what the part left out, so the toggles have one
+            // place to live
+            for &i in &changes {
+                let left_out = matches!(
+                    (&hunk.lines[i], hunk.part),
+                    (DiffLine::Added(_), HunkPart::DeletionsOnly) | (DiffLine::Removed(_), HunkPart::AdditionsOnly)
+                );
+                if left_out {
+                    hunk.excluded.insert(i);
+                }
+            }
+            if !hunk.excluded.remove(current_line) {
+                hunk.excluded.insert(*current_line);
+            }
+        }
+        hunk.part = HunkPart::All;
+        hunk.reviewed = true;
+        hunk.accepted = hunk.excluded.len() < changes.len();
+        if !hunk.accepted {
+            hunk.excluded.clear();
+        }
+        self.update_buffer_with_accepted_hunks();
+        true
+    }
+
+    /// The first hunk neither accepted nor rejected yet.
+    pub fn first_pending_hunk(&self) -> Option<usize> {
+        self.get_hunks().iter().position(|h| !h.reviewed && !h.accepted)
+    }
+
+    /// How many hunks are accepted, rejected and still pending.
+    pub fn hunk_review_counts(&self) -> (usize, usize, usize) {
+        let hunks = self.get_hunks();
+        let accepted = hunks.iter().filter(|h| h.accepted).count();
+        let rejected = hunks.iter().filter(|h| h.reviewed && !h.accepted).count();
+        (accepted, rejected, hunks.len() - accepted - rejected)
+    }
+
     pub fn accept_current_hunk(&mut self) {
         if let DiffMode::Active { hunks, current_hunk, .. } = &mut self.diff_mode {
             if *current_hunk < hunks.len() {
                 hunks[*current_hunk].accepted = true;
+                hunks[*current_hunk].reviewed = true;
+                hunks[*current_hunk].part = HunkPart::All;
+                hunks[*current_hunk].excluded.clear();
+                self.update_buffer_with_accepted_hunks();
+            }
+        }
+    }
+
+    /// Accepts only the additions or only the deletions of the current hunk.
+    pub fn accept_current_hunk_part(&mut self, part: HunkPart) {
+        if let DiffMode::Active { hunks, current_hunk, .. } = &mut self.diff_mode {
+            if *current_hunk < hunks.len() {
+                hunks[*current_hunk].accepted = true;
+                hunks[*current_hunk].reviewed = true;
+                hunks[*current_hunk].part = part;
+                hunks[*current_hunk].excluded.clear();
                 self.update_buffer_with_accepted_hunks();
             }
         }
@@ -1405,6 +3009,8 @@ replace_text: None,
         if let DiffMode::Active { hunks, current_hunk, .. } = &mut self.diff_mode {
             if *current_hunk < hunks.len() {
                 hunks[*current_hunk].accepted = false;
+                hunks[*current_hunk].reviewed = true;
+                hunks[*current_hunk].excluded.clear();
                 self.update_buffer_with_accepted_hunks();
             }
         }
@@ -1414,6 +3020,9 @@ replace_text: None,
         if let DiffMode::Active { hunks, accept_all, .. } = &mut self.diff_mode {
             for hunk in hunks.iter_mut() {
                 hunk.accepted = true;
+                hunk.reviewed = true;
+                hunk.part = HunkPart::All;
+                hunk.excluded.clear();
             }
             *accept_all = true;
             self.update_buffer_with_accepted_hunks();
@@ -1433,6 +3042,8 @@ replace_text: None,
         if let DiffMode::Active { hunks, accept_all, .. } = &mut self.diff_mode {
             for hunk in hunks.iter_mut() {
                 hunk.accepted = false;
+                hunk.reviewed = true;
+                hunk.excluded.clear();
             }
             *accept_all = false;
             self.update_buffer_with_accepted_hunks();
@@ -1440,19 +3051,12 @@ replace_text: None,
     }
 
     pub fn apply_diff_changes(&mut self) -> bool {
-        if let DiffMode::Active { original_buffer, hunks, .. } = &self.diff_mode {
-            // Apply all accepted hunks to create final buffer
-            let mut result_buffer = original_buffer.clone();
-            let mut line_offset = 0isize;
-            
-            for hunk in hunks.iter().filter(|h| h.accepted) {
-                self.apply_hunk_to_buffer(&mut result_buffer, hunk, (hunk.old_start as isize + line_offset) as usize);
-                line_offset += hunk.new_lines as isize - hunk.old_lines as isize;
-            }
-            
+        if let Some(result_buffer) = self.accepted_buffer() {
             self.buffer = result_buffer;
             self.modified = true;
             self.diff_mode = DiffMode::Inactive;
+            // Rejected hunks can leave fewer lines than the cursor was on
+            self.cursor_y = self.cursor_y.min(self.buffer.len() - 1);
             true
         } else {
             false
@@ -1470,37 +3074,56 @@ replace_text: None,
     }
 
     fn update_buffer_with_accepted_hunks(&mut self) {
-        if let DiffMode::Active { original_buffer, hunks, .. } = &self.diff_mode {
-            let mut result_buffer = original_buffer.clone();
-            let mut line_offset = 0isize;
-            
-            for hunk in hunks.iter().filter(|h| h.accepted) {
-                self.apply_hunk_to_buffer(&mut result_buffer, hunk, (hunk.old_start as isize + line_offset) as usize);
-                line_offset += hunk.new_lines as isize - hunk.old_lines as isize;
-            }
-            
+        if let Some(result_buffer) = self.accepted_buffer() {
             self.buffer = result_buffer;
         }
     }
 
-    fn apply_hunk_to_buffer(&self, buffer: &mut Vec<String>, hunk: &Hunk, start_line: usize) {
-        // Remove old lines (only if they exist)
-        for _ in 0..hunk.old_lines {
-            if start_line < buffer.len() {
-                buffer.remove(start_line);
-            }
-        }
-        
-        // Insert new lines (ensure we don't go beyond buffer)
-        for (i, line) in hunk.lines.iter().enumerate() {
-            match line {
-                DiffLine::Added(content) | DiffLine::Context(content) => {
-                    let insert_pos = (start_line + i).min(buffer.len());
-                    buffer.insert(insert_pos, content.clone());
+    /// The original buffer with the accepted hunks applied. For a scoped
+    /// diff only the target lines are rebuilt and spliced back in place.
+    fn accepted_buffer(&self) -> Option<Vec<String>> {
+        let DiffMode::Active { original_buffer, target, hunks, .. } = &self.diff_mode else {
+            return None;
+        };
+        let target = target.clone().unwrap_or(0..original_buffer.len());
+        let mut region = original_buffer[target.clone()].to_vec();
+        let mut line_offset = 0isize;
+        for hunk in hunks.iter().filter(|h| h.accepted) {
+            let start = hunk.old_start - target.start;
+            let inserted = self.apply_hunk_to_buffer(&mut region, hunk, (start as isize + line_offset) as usize);
+            line_offset += inserted as isize - hunk.old_lines as isize;
+        }
+        let mut result_buffer = original_buffer[..target.start].to_vec();
+        result_buffer.extend(region);
+        result_buffer.extend_from_slice(&original_buffer[target.end..]);
+        if result_buffer.is_empty() {
+            result_buffer.push(String::new());
+        }
+        Some(result_buffer)
+    }
+
+    /// Replaces the hunk's original lines at `start_line` with the lines its
+    /// `part` and `excluded` keep, returning how many lines were put in.
+    fn apply_hunk_to_buffer(&self, buffer: &mut Vec<String>, hunk: &Hunk, start_line: usize) -> usize {
+        let replacement: Vec<String> = hunk
+            .lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| {
+                let excluded = hunk.excluded.contains(&i);
+                match line {
+                    DiffLine::Context(content) => Some(content.clone()),
+                    DiffLine::Added(content) if hunk.part != HunkPart::DeletionsOnly && !excluded => Some(content.clone()),
+                    DiffLine::Removed(content) if hunk.part == HunkPart::AdditionsOnly || excluded => Some(content.clone()),
+                    _ => None,
                 }
-                DiffLine::Removed(_) => {} // Skip removed lines
-            }
-        }
+            })
+            .collect();
+        let start = start_line.min(buffer.len());
+        let end = (start + hunk.old_lines).min(buffer.len());
+        let inserted = replacement.len();
+        buffer.splice(start..end, replacement);
+        inserted
     }
 
     fn compute_diff(&self, original: &[String], modified: &[String]) -> Vec<Hunk> {
@@ -1520,9 +3143,11 @@ replace_text: None,
                         old_start: hunk_start_original,
                         old_lines: i - hunk_start_original,
                         new_start: hunk_start_modified,
-                        new_lines: j - hunk_start_modified,
                         lines: current_hunk_lines.clone(),
                         accepted: false,
+                        reviewed: false,
+                        part: HunkPart::All,
+                        excluded: HashSet::new(),
                     });
                     current_hunk_lines.clear();
                     in_hunk = false;
@@ -1555,9 +3180,11 @@ replace_text: None,
                 old_start: hunk_start_original,
                 old_lines: i - hunk_start_original,
                 new_start: hunk_start_modified,
-                new_lines: j - hunk_start_modified,
                 lines: current_hunk_lines,
                 accepted: false,
+                reviewed: false,
+                part: HunkPart::All,
+                excluded: HashSet::new(),
             });
         }
         
@@ -1587,3 +3214,722 @@ replace_text: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> EditorConfig {
+        toml::from_str("theme = \"base16-ocean.dark\"\ntab_width = 4\n[syntax_map]\n").unwrap()
+    }
+
+    fn editor(text: &str) -> Editor {
+        Editor::new(text, &config())
+    }
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_string).collect()
+    }
+
+    fn select(e: &mut Editor, mode: SelectionMode, start: (usize, usize), end: (usize, usize)) {
+        e.selection_mode = mode;
+        e.selection_start = Some(start);
+        e.selection_end = Some(end);
+    }
+
+    #[test]
+    fn selection_text_of_line_and_block_selections() {
+        let mut e = editor("one\ntwo\nthree");
+        assert_eq!(e.selection_text(), None);
+        select(&mut e, SelectionMode::Line, (2, 3), (1, 0));
+        assert_eq!(e.selection_text(), Some((AiScope::Lines { start_y: 1, end_y: 2 }, "two\nthree".to_string())));
+        // Rows shorter than the block are padded to its width
+        select(&mut e, SelectionMode::Block, (2, 3), (0, 1));
+        assert_eq!(
+            e.selection_text(),
+            Some((AiScope::Block { start_y: 0, end_y: 2, start_x: 1, end_x: 4 }, "ne \nwo \nhre".to_string()))
+        );
+    }
+
+    #[test]
+    fn splice_ai_response_replaces_just_the_scope() {
+        let mut e = editor("a\nb\nc\nd");
+        e.ai_scope = AiScope::Lines { start_y: 1, end_y: 2 };
+        assert_eq!(e.splice_ai_response("X\nY\nZ\n"), lines("a\nX\nY\nZ\nd"));
+        e.ai_scope = AiScope::Buffer;
+        assert_eq!(e.splice_ai_response("new"), lines("new"));
+        assert_eq!(e.splice_ai_response(""), vec![""]);
+    }
+
+    #[test]
+    fn splice_ai_response_into_a_block() {
+        let mut e = editor("ab12cd\nab34cd\nab");
+        e.ai_scope = AiScope::Block { start_y: 0, end_y: 2, start_x: 2, end_x: 4 };
+        assert_eq!(e.splice_ai_response("éé\n漢"), lines("abéécd\nab漢cd\nab"));
+        assert_eq!(e.splice_ai_response("1\n2\n3\n4"), lines("ab1cd\nab2cd\nab3\n4"));
+    }
+
+    #[test]
+    fn open_line_below_carries_the_indent() {
+        let mut e = editor("fn main() {\n    let x = 1;\n}");
+        e.cursor_y = 1;
+        e.cursor_x = 7;
+        e.open_line_below();
+        assert_eq!(e.buffer, vec!["fn main() {", "    let x = 1;", "    ", "}"]);
+        assert_eq!((e.cursor_y, e.cursor_x), (2, 4));
+        assert!(e.modified);
+        e.undo();
+        assert_eq!(e.buffer, vec!["fn main() {", "    let x = 1;", "}"]);
+    }
+
+    #[test]
+    fn open_line_above_carries_the_indent() {
+        let mut e = editor("\tif x {\n\t\ty();\n\t}");
+        e.cursor_y = 1;
+        e.open_line_above();
+        assert_eq!(e.buffer, vec!["\tif x {", "\t\t", "\t\ty();", "\t}"]);
+        assert_eq!(e.cursor_y, 1);
+        assert_eq!(e.cursor_x, "\t\t".width());
+    }
+
+    #[test]
+    fn open_line_on_an_empty_buffer() {
+        let mut e = editor("");
+        e.open_line_below();
+        assert_eq!(e.buffer, vec!["", ""]);
+        assert_eq!((e.cursor_y, e.cursor_x), (1, 0));
+        e.cursor_y = 0;
+        e.open_line_above();
+        assert_eq!(e.buffer, vec!["", "", ""]);
+        assert_eq!((e.cursor_y, e.cursor_x), (0, 0));
+    }
+
+    #[test]
+    fn select_paragraph_selects_the_middle_of_three() {
+        let mut e = editor("one\ntwo\n\nthree\nfour\nfive\n\nsix");
+        e.cursor_y = 4;
+        assert!(e.select_paragraph());
+        assert!(e.selection_mode == SelectionMode::Line);
+        assert_eq!(e.selection_start.map(|(y, _)| y), Some(3));
+        assert_eq!(e.selection_end.map(|(y, _)| y), Some(5));
+        assert_eq!(e.selection_text().map(|(_, text)| text), Some("three\nfour\nfive".to_string()));
+    }
+
+    #[test]
+    fn select_paragraph_at_the_buffer_edges() {
+        let mut e = editor("one\n\ntwo");
+        e.cursor_y = 2;
+        assert!(e.select_paragraph());
+        assert_eq!((e.selection_start.unwrap().0, e.selection_end.unwrap().0), (2, 2));
+        e.deselect();
+        e.cursor_y = 1;
+        assert!(!e.select_paragraph());
+        assert_eq!(e.selection_start, None);
+        assert!(!editor("").select_paragraph());
+    }
+
+    fn newline_between(open: char, close: char, auto_pairs: bool) -> Editor {
+        let mut e = editor(&format!("  call{}{};", open, close));
+        e.auto_pairs = auto_pairs;
+        e.tab_width = 4;
+        e.cursor_x = 7;
+        e.insert_newline();
+        e
+    }
+
+    #[test]
+    fn enter_splits_empty_bracket_pairs() {
+        for (open, close) in [('{', '}'), ('[', ']'), ('(', ')')] {
+            let e = newline_between(open, close, true);
+            assert_eq!(e.buffer, vec![format!("  call{}", open), "      ".to_string(), format!("  {};", close)]);
+            assert_eq!((e.cursor_y, e.cursor_x), (1, 6));
+        }
+    }
+
+    #[test]
+    fn enter_between_brackets_without_auto_pairs_just_breaks_the_line() {
+        let e = newline_between('{', '}', false);
+        assert_eq!(e.buffer, vec!["  call{", "};"]);
+        assert_eq!((e.cursor_y, e.cursor_x), (1, 0));
+    }
+
+    #[test]
+    fn enter_leaves_mismatched_brackets_alone() {
+        let e = newline_between('(', ']', true);
+        assert_eq!(e.buffer, vec!["  call(", "];"]);
+    }
+
+    #[test]
+    fn accepting_only_additions_keeps_the_removed_lines() {
+        let mut e = editor("a\nb\nc\nd");
+        e.start_diff_mode(None, lines("a\nB\nc\nd\ne"));
+        assert_eq!(e.get_hunks().len(), 2);
+        e.accept_current_hunk_part(HunkPart::AdditionsOnly);
+        assert_eq!(e.buffer, lines("a\nb\nB\nc\nd"));
+        assert!(e.apply_diff_changes());
+        assert_eq!(e.buffer, lines("a\nb\nB\nc\nd"));
+    }
+
+    #[test]
+    fn accepting_only_deletions_leaves_out_the_added_lines() {
+        let mut e = editor("a\nb\nc");
+        e.start_diff_mode(None, lines("a\nB\nc"));
+        e.accept_current_hunk_part(HunkPart::DeletionsOnly);
+        assert!(e.apply_diff_changes());
+        assert_eq!(e.buffer, lines("a\nc"));
+    }
+
+    #[test]
+    fn accepting_only_deletions_of_a_pure_addition_changes_nothing() {
+        let mut e = editor("a");
+        e.start_diff_mode(None, lines("a\nb"));
+        e.accept_current_hunk_part(HunkPart::DeletionsOnly);
+        assert!(e.apply_diff_changes());
+        assert_eq!(e.buffer, lines("a"));
+    }
+
+    fn type_text(e: &mut Editor, text: &str) {
+        for c in text.chars() {
+            e.type_char(c);
+        }
+    }
+
+    fn undo_steps(e: &mut Editor) -> Vec<String> {
+        let mut steps = Vec::new();
+        while e.undo() {
+            steps.push(e.buffer.join("\n"));
+        }
+        steps
+    }
+
+    #[test]
+    fn char_granularity_undoes_each_keystroke() {
+        let mut e = editor("");
+        type_text(&mut e, "ab c");
+        assert_eq!(undo_steps(&mut e), vec!["ab ", "ab", "a", ""]);
+    }
+
+    #[test]
+    fn word_granularity_undoes_a_typed_word_at_once() {
+        let mut e = editor("");
+        e.undo_granularity = UndoGranularity::Word;
+        type_text(&mut e, "ab cd");
+        assert_eq!(undo_steps(&mut e), vec!["ab ", "ab", ""]);
+    }
+
+    #[test]
+    fn line_granularity_undoes_everything_typed_on_the_line() {
+        let mut e = editor("");
+        e.undo_granularity = UndoGranularity::Line;
+        type_text(&mut e, "ab cd");
+        e.backspace();
+        e.insert_newline();
+        type_text(&mut e, "x");
+        // Enter is typed on the first line, so it belongs to that line's step
+        assert_eq!(undo_steps(&mut e), vec!["ab c\n", ""]);
+    }
+
+    #[test]
+    fn moving_away_and_back_starts_a_new_undo_step() {
+        for granularity in [UndoGranularity::Word, UndoGranularity::Line] {
+            let mut e = editor("\nnext");
+            e.undo_granularity = granularity;
+            type_text(&mut e, "ab");
+            e.move_cursor(0, 1);
+            e.move_cursor(0, -1);
+            e.cursor_x = 2;
+            type_text(&mut e, "c");
+            assert_eq!(undo_steps(&mut e), vec!["ab\nnext", "\nnext"]);
+        }
+    }
+
+    const NESTED: &str = "fn f() {\n    if x { y(); }\n    loop {\n        z();\n    }\n}";
+
+    #[test]
+    fn enclosing_braces_finds_the_innermost_block() {
+        let mut e = editor(NESTED);
+        (e.cursor_y, e.cursor_x) = (3, 8);
+        assert_eq!(e.enclosing_braces(), Some(((2, 9), (4, 4))));
+        // Between the inner blocks only the outer one encloses the cursor
+        (e.cursor_y, e.cursor_x) = (1, 17);
+        assert_eq!(e.enclosing_braces(), Some(((0, 7), (5, 0))));
+        (e.cursor_y, e.cursor_x) = (1, 10);
+        assert_eq!(e.enclosing_braces(), Some(((1, 9), (1, 16))));
+    }
+
+    #[test]
+    fn enclosing_braces_counts_a_brace_under_the_cursor_as_inside() {
+        let mut e = editor(NESTED);
+        (e.cursor_y, e.cursor_x) = (2, 9);
+        assert_eq!(e.enclosing_braces(), Some(((2, 9), (4, 4))));
+        (e.cursor_y, e.cursor_x) = (4, 4);
+        assert_eq!(e.enclosing_braces(), Some(((2, 9), (4, 4))));
+        (e.cursor_y, e.cursor_x) = (0, 0);
+        assert_eq!(e.enclosing_braces(), None);
+    }
+
+    #[test]
+    fn enclosing_braces_reports_display_columns() {
+        let mut e = editor("let 名前 = { été };");
+        e.cursor_x = 13;
+        assert_eq!(e.enclosing_braces(), Some(((0, 11), (0, 17))));
+        assert_eq!(editor("").enclosing_braces(), None);
+    }
+
+    #[test]
+    fn paste_replaces_a_line_selection() {
+        let mut e = editor("a\nb\nc\nd");
+        e.clipboard = lines("x\ny\nz");
+        select(&mut e, SelectionMode::Line, (2, 0), (1, 0));
+        assert!(e.paste());
+        assert_eq!(e.buffer, lines("a\nx\ny\nz\nd"));
+        assert_eq!((e.cursor_y, e.cursor_x), (1, 0));
+        assert_eq!(e.selection_start, None);
+        e.undo();
+        assert_eq!(e.buffer, lines("a\nb\nc\nd"));
+    }
+
+    #[test]
+    fn paste_replaces_a_block_selection() {
+        let mut e = editor("abcdef\nabcdef\nab");
+        e.block_paste = BlockPaste::Replace;
+        e.clipboard = lines("X\nYY\nZZZ\nW");
+        select(&mut e, SelectionMode::Block, (0, 1), (1, 2));
+        assert!(e.paste());
+        assert_eq!(e.buffer, lines("aXdef\naYYdef\naZZZb\n W"));
+        assert_eq!((e.cursor_y, e.cursor_x), (0, 1));
+    }
+
+    #[test]
+    fn paste_overwrites_a_block_selection() {
+        let mut e = editor("abcdef\nabcdef\nab");
+        e.block_paste = BlockPaste::Overwrite;
+        e.clipboard = lines("X\nYYYY");
+        select(&mut e, SelectionMode::Block, (0, 1), (2, 3));
+        assert!(e.paste());
+        assert_eq!(e.buffer, lines("aX  ef\naYYYef\na   "));
+        e.undo();
+        assert_eq!(e.buffer, lines("abcdef\nabcdef\nab"));
+    }
+
+    #[test]
+    fn paste_into_a_read_only_buffer_or_from_an_empty_clipboard() {
+        let mut e = editor("text");
+        assert!(!e.paste());
+        e.clipboard = lines("x");
+        e.read_only = true;
+        assert!(!e.paste());
+        assert_eq!(e.buffer, lines("text"));
+    }
+
+    #[test]
+    fn number_lines_pads_to_the_widest_number_and_round_trips() {
+        let text = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj";
+        let mut e = editor(text);
+        e.cursor_y = 9;
+        e.cursor_x = 1;
+        assert_eq!(e.number_lines(None, ": "), 10);
+        assert_eq!(e.buffer[0], " 1: a");
+        assert_eq!(e.buffer[9], "10: j");
+        assert_eq!(e.cursor_x, 5);
+        assert_eq!(e.unnumber_lines(": "), 10);
+        assert_eq!(e.buffer, lines(text));
+        assert_eq!(e.cursor_x, 1);
+    }
+
+    #[test]
+    fn number_lines_in_a_selection_keeps_buffer_numbers() {
+        let mut e = editor("a\nb\nc\nd");
+        select(&mut e, SelectionMode::Line, (1, 0), (2, 0));
+        assert_eq!(e.number_lines(Some(3), " "), 2);
+        assert_eq!(e.buffer, lines("a\n  2 b\n  3 c\nd"));
+        e.undo();
+        assert_eq!(e.buffer, lines("a\nb\nc\nd"));
+    }
+
+    #[test]
+    fn unnumber_lines_leaves_unnumbered_lines_alone() {
+        let mut e = editor("1 one\ntwo\n 3 three\n4x");
+        assert_eq!(e.unnumber_lines(" "), 2);
+        assert_eq!(e.buffer, lines("one\ntwo\nthree\n4x"));
+        let mut e = editor("plain\ntext");
+        assert_eq!(e.unnumber_lines(" "), 0);
+        assert!(!e.modified);
+    }
+
+    #[test]
+    fn replace_next_walks_the_matches_in_order() {
+        let mut e = editor("cat cat\ncat");
+        assert_eq!(e.replace("cat", "dog", SearchScope::All, false, true), 3);
+        assert!(e.replace_next());
+        assert_eq!(e.buffer, lines("dog cat\ncat"));
+        assert_eq!((e.cursor_y, e.cursor_x), (0, 4));
+        assert!(e.replace_next());
+        assert_eq!(e.buffer, lines("dog dog\ncat"));
+        assert_eq!((e.cursor_y, e.cursor_x), (1, 0));
+        assert!(e.replace_next());
+        assert_eq!(e.buffer, lines("dog dog\ndog"));
+        assert_eq!(e.replace_text, None);
+        assert!(!e.replace_next());
+        assert_eq!(undo_steps(&mut e), vec!["dog dog\ncat", "dog cat\ncat", "cat cat\ncat"]);
+    }
+
+    #[test]
+    fn replace_next_skips_the_text_it_just_inserted() {
+        let mut e = editor("a a");
+        e.replace("a", "aa", SearchScope::All, false, true);
+        assert!(e.replace_next());
+        assert_eq!(e.buffer, lines("aa a"));
+        assert_eq!(e.cursor_x, 3);
+        assert!(e.replace_next());
+        assert_eq!(e.buffer, lines("aa aa"));
+    }
+
+    #[test]
+    fn replace_next_with_multibyte_text() {
+        let mut e = editor("naïve café");
+        e.replace("café", "thé", SearchScope::All, false, true);
+        assert_eq!(e.cursor_x, 6);
+        assert!(e.replace_next());
+        assert_eq!(e.buffer, lines("naïve thé"));
+        assert_eq!(e.replace_text, None);
+
+        let mut e = editor("漢字 漢字");
+        e.replace("字", "x", SearchScope::All, false, true);
+        assert_eq!(e.cursor_x, 2);
+        assert!(e.replace_next());
+        assert_eq!(e.buffer, lines("漢x 漢字"));
+        assert_eq!(e.cursor_x, 6);
+        assert!(e.replace_next());
+        assert_eq!(e.buffer, lines("漢x 漢x"));
+    }
+
+    #[test]
+    fn replace_next_in_an_empty_buffer() {
+        let mut e = editor("");
+        assert_eq!(e.replace("x", "y", SearchScope::All, false, true), 0);
+        assert!(!e.replace_next());
+        assert_eq!(e.buffer, vec![""]);
+        assert!(!e.modified);
+    }
+
+    fn transposed(text: &str, cursor_x: usize) -> Option<(String, usize)> {
+        let mut e = editor(text);
+        e.cursor_x = cursor_x;
+        e.transpose_words().then(|| (e.buffer[0].clone(), e.cursor_x))
+    }
+
+    #[test]
+    fn transpose_words_inside_and_between_words() {
+        assert_eq!(transposed("one two three", 1), Some(("two one three".to_string(), 7)));
+        assert_eq!(transposed("one two three", 3), Some(("two one three".to_string(), 7)));
+        assert_eq!(transposed("one two three", 4), Some(("two one three".to_string(), 7)));
+        assert_eq!(transposed("one two three", 6), Some(("one three two".to_string(), 13)));
+    }
+
+    #[test]
+    fn transpose_words_across_punctuation() {
+        assert_eq!(transposed("foo, bar", 1), Some(("bar, foo".to_string(), 8)));
+        assert_eq!(transposed("a.b", 1), Some(("b.a".to_string(), 3)));
+        assert_eq!(transposed("x = (left + right);", 7), Some(("x = (right + left);".to_string(), 17)));
+    }
+
+    #[test]
+    fn transpose_words_with_multibyte_text() {
+        assert_eq!(transposed("café naïve", 2), Some(("naïve café".to_string(), 10)));
+        assert_eq!(transposed("漢字 かな", 2), Some(("かな 漢字".to_string(), 9)));
+    }
+
+    #[test]
+    fn transpose_words_needs_two_words() {
+        assert_eq!(transposed("", 0), None);
+        assert_eq!(transposed("one two", 7), None);
+        assert_eq!(transposed("one two", 0), None);
+        assert_eq!(transposed("lonely", 3), None);
+        assert_eq!(transposed("one two three", 9), None);
+    }
+
+    fn ai_diff_hunks(target_only: bool) -> (Editor, Vec<(usize, usize)>) {
+        let mut e = editor("}\n}\nfn a() {\n}\n}");
+        e.ai_diff_target_only = target_only;
+        e.ai_scope = AiScope::Lines { start_y: 2, end_y: 3 };
+        e.start_ai_diff("fn a() {\n    b();\n}\n}");
+        let hunks = e.get_hunks().iter().map(|h| (h.old_start, h.old_start + h.old_lines)).collect();
+        (e, hunks)
+    }
+
+    #[test]
+    fn ai_diff_of_a_selection_only_has_hunks_in_the_selection() {
+        let (mut e, hunks) = ai_diff_hunks(true);
+        assert!(!hunks.is_empty());
+        assert!(hunks.iter().all(|&(start, end)| 2 <= start && end <= 4), "{:?}", hunks);
+        e.accept_all_hunks();
+        assert!(e.apply_diff_changes());
+        assert_eq!(e.buffer, lines("}\n}\nfn a() {\n    b();\n}\n}\n}"));
+    }
+
+    #[test]
+    fn ai_diff_of_the_whole_buffer_when_the_setting_is_off() {
+        let mut config = config();
+        config.ai_diff_target_only = Some(false);
+        let mut e = editor("");
+        e.apply_config(&config);
+        assert!(!e.ai_diff_target_only);
+
+        let (mut e, hunks) = ai_diff_hunks(false);
+        assert!(hunks.iter().any(|&(start, _)| start >= 4), "{:?}", hunks);
+        e.accept_all_hunks();
+        assert!(e.apply_diff_changes());
+        assert_eq!(e.buffer, lines("}\n}\nfn a() {\n    b();\n}\n}\n}"));
+    }
+
+    fn scoped_ai_diff(text: &str, start_y: usize, end_y: usize, response: &str) -> Editor {
+        let mut e = editor(text);
+        e.ai_scope = AiScope::Lines { start_y, end_y };
+        e.start_ai_diff(response);
+        e
+    }
+
+    #[test]
+    fn accepting_one_hunk_of_a_scoped_ai_diff() {
+        let mut e = scoped_ai_diff("head\na\nb\nc\nd\ne\ntail", 1, 5, "A\nb\nc\nd\nE");
+        assert_eq!(e.get_hunks().len(), 2);
+        assert!(e.next_hunk());
+        e.accept_current_hunk();
+        assert!(e.apply_diff_changes());
+        assert_eq!(e.buffer, lines("head\na\nb\nc\nd\nE\ntail"));
+    }
+
+    #[test]
+    fn rejecting_a_scoped_ai_diff_keeps_the_buffer() {
+        let mut e = scoped_ai_diff("head\na\ntail", 1, 1, "A\nB");
+        e.reject_all_hunks();
+        assert!(e.apply_diff_changes());
+        assert_eq!(e.buffer, lines("head\na\ntail"));
+    }
+
+    #[test]
+    fn an_empty_ai_response_removes_just_the_target_lines() {
+        let mut e = scoped_ai_diff("head\na\nb\ntail", 1, 2, "");
+        e.accept_all_hunks();
+        assert!(e.apply_diff_changes());
+        assert_eq!(e.buffer, lines("head\ntail"));
+
+        let mut e = scoped_ai_diff("a\nb", 0, 1, "");
+        e.accept_all_hunks();
+        assert!(e.apply_diff_changes());
+        assert_eq!(e.buffer, vec![""]);
+    }
+
+    fn undo_depth(e: &Editor) -> (usize, usize) {
+        (e.undo_index, e.undo_history.len())
+    }
+
+    #[test]
+    fn backspace_and_delete_with_nothing_to_delete_record_no_undo_step() {
+        let mut e = editor("");
+        e.backspace();
+        e.delete_char();
+        assert_eq!(undo_depth(&e), (0, 1));
+        assert!(!e.modified);
+
+        let mut e = editor("abc\ndef");
+        e.backspace();
+        e.cursor_y = 1;
+        e.cursor_x = 3;
+        e.delete_char();
+        assert_eq!(undo_depth(&e), (0, 1));
+        assert_eq!(e.buffer, lines("abc\ndef"));
+        assert!(!e.undo());
+    }
+
+    #[test]
+    fn backspace_and_delete_in_virtual_space_record_no_undo_step() {
+        let mut e = editor("ab\ncd");
+        e.virtual_cursor = true;
+        e.cursor_x = 4;
+        e.delete_char();
+        assert_eq!(undo_depth(&e), (0, 1));
+        e.backspace();
+        assert_eq!(e.cursor_x, 3);
+        assert_eq!(undo_depth(&e), (0, 1));
+        assert_eq!(e.buffer, lines("ab\ncd"));
+
+        // Without virtual space, Delete at the end of a line still joins
+        e.virtual_cursor = false;
+        e.cursor_x = 2;
+        e.delete_char();
+        assert_eq!(e.buffer, lines("abcd"));
+        assert!(e.undo());
+        assert_eq!(e.buffer, lines("ab\ncd"));
+    }
+
+    #[test]
+    fn backspace_over_multibyte_text_records_each_deletion() {
+        let mut e = editor("漢字é");
+        e.cursor_x = 5;
+        e.delete_char();
+        assert_eq!(undo_depth(&e), (0, 1));
+        e.backspace();
+        assert_eq!((e.buffer[0].as_str(), e.cursor_x), ("漢字", 4));
+        e.backspace();
+        assert_eq!((e.buffer[0].as_str(), e.cursor_x), ("漢", 2));
+        assert_eq!(undo_steps(&mut e), vec!["漢字", "漢字é"]);
+    }
+
+    fn numbered(count: usize, height: usize) -> Editor {
+        let text: Vec<String> = (0..count).map(|i| i.to_string()).collect();
+        let mut e = editor(&text.join("\n"));
+        e.editor_visible_height = height;
+        e.editor_visible_width = 80;
+        e.scroll_off = 0;
+        e
+    }
+
+    #[test]
+    fn page_down_keeps_the_screen_row_and_clamps_at_the_end() {
+        let mut e = numbered(30, 10);
+        e.cursor_y = 2;
+        e.page_down();
+        assert_eq!((e.scroll_y, e.cursor_y), (9, 11));
+        e.page_down();
+        assert_eq!((e.scroll_y, e.cursor_y), (18, 20));
+        e.page_down();
+        assert_eq!((e.scroll_y, e.cursor_y), (20, 29));
+        e.page_down();
+        assert_eq!((e.scroll_y, e.cursor_y), (20, 29));
+    }
+
+    #[test]
+    fn page_up_clamps_at_the_top() {
+        let mut e = numbered(30, 10);
+        e.cursor_y = 25;
+        e.scroll();
+        assert_eq!(e.scroll_y, 16);
+        e.page_up();
+        assert_eq!((e.scroll_y, e.cursor_y), (7, 16));
+        e.page_up();
+        assert_eq!((e.scroll_y, e.cursor_y), (0, 7));
+        e.page_up();
+        assert_eq!((e.scroll_y, e.cursor_y), (0, 0));
+    }
+
+    #[test]
+    fn paging_a_short_buffer_or_empty_view() {
+        let mut e = numbered(3, 10);
+        e.page_down();
+        assert_eq!((e.scroll_y, e.cursor_y), (0, 2));
+        let mut e = numbered(3, 0);
+        e.page_down();
+        assert_eq!(e.cursor_y, 0);
+    }
+
+    #[test]
+    fn paging_pulls_the_cursor_back_to_the_line_end() {
+        let mut e = editor("long line here\nab\nlonger line");
+        e.editor_visible_height = 2;
+        e.editor_visible_width = 80;
+        e.scroll_off = 0;
+        e.cursor_x = 9;
+        e.virtual_cursor = false;
+        e.page_down();
+        assert_eq!((e.cursor_y, e.cursor_x), (1, 2));
+        e.virtual_cursor = true;
+        e.cursor_x = 9;
+        e.page_down();
+        assert_eq!((e.cursor_y, e.cursor_x), (2, 9));
+    }
+
+    #[test]
+    fn scroll_keeps_scrolloff_lines_around_the_cursor() {
+        let mut e = numbered(30, 10);
+        e.scroll_off = 3;
+        e.cursor_y = 7;
+        e.scroll();
+        assert_eq!(e.scroll_y, 1);
+        e.cursor_y = 29;
+        e.scroll();
+        assert_eq!(e.scroll_y, 20);
+        e.cursor_y = 22;
+        e.scroll();
+        assert_eq!(e.scroll_y, 19);
+        e.cursor_y = 0;
+        e.scroll();
+        assert_eq!(e.scroll_y, 0);
+    }
+
+    #[test]
+    fn scrolloff_is_capped_at_half_the_view() {
+        let mut e = numbered(30, 4);
+        e.scroll_off = 10;
+        e.cursor_y = 10;
+        e.scroll();
+        // A margin of 1 either side of the cursor fits in four rows
+        assert_eq!(e.scroll_y, 8);
+    }
+
+    #[test]
+    fn sidescrolloff_keeps_columns_beside_the_cursor() {
+        let mut e = editor(&"x".repeat(100));
+        e.editor_visible_height = 10;
+        e.editor_visible_width = 20;
+        e.side_scroll_off = 5;
+        e.cursor_x = 18;
+        e.scroll();
+        assert_eq!(e.scroll_x, 4);
+        e.cursor_x = 6;
+        e.scroll();
+        assert_eq!(e.scroll_x, 1);
+    }
+
+    #[test]
+    fn replace_all_instances_with_multibyte_text() {
+        let mut e = editor("café au café\n漢字漢字 字");
+        e.replace_all_instances("é", "e", true);
+        assert_eq!(e.buffer, lines("cafe au cafe\n漢字漢字 字"));
+        e.replace_all_instances("字", "ji", true);
+        assert_eq!(e.buffer, lines("cafe au cafe\n漢ji漢ji ji"));
+        e.replace_all_instances("e", "é", true);
+        assert_eq!(e.buffer, lines("café au café\n漢ji漢ji ji"));
+        assert!(e.modified);
+    }
+
+    #[test]
+    fn replace_all_instances_ignoring_case_and_containing_the_target() {
+        let mut e = editor("ÉCOLE école Ecole");
+        e.replace_all_instances("école", "school", false);
+        assert_eq!(e.buffer, lines("school school Ecole"));
+
+        let mut e = editor("a ab");
+        e.replace_all_instances("a", "aa", true);
+        assert_eq!(e.buffer, lines("aa aab"));
+        e.replace_all_instances("", "x", true);
+        assert_eq!(e.buffer, lines("aa aab"));
+    }
+
+    #[test]
+    fn replace_all_is_one_undo_step() {
+        let mut e = editor("ü ü\n\n字ü");
+        assert_eq!(e.replace("ü", "ue", SearchScope::All, true, true), 3);
+        assert_eq!(e.buffer, lines("ue ue\n\n字ue"));
+        assert_eq!(undo_steps(&mut e), vec!["ü ü\n\n字ü"]);
+    }
+
+    #[test]
+    fn find_in_line_starts_at_the_given_byte() {
+        assert_eq!(find_in_line("abab", "ab", true, 0), Some((0, 2)));
+        assert_eq!(find_in_line("abab", "ab", true, 1), Some((2, 4)));
+        assert_eq!(find_in_line("abab", "ab", true, 3), None);
+        assert_eq!(find_in_line("café café", "é", true, 5), Some((9, 11)));
+        assert_eq!(find_in_line("abab", "AB", true, 0), None);
+    }
+
+    #[test]
+    fn find_in_line_ignoring_case_returns_ranges_in_the_original_line() {
+        assert_eq!(find_in_line("Größe GRÖẞE", "größe", false, 1), Some((8, 16)));
+        // The Kelvin sign is three bytes but lowercases to a one-byte 'k'
+        assert_eq!(find_in_line("5 \u{212A}m", "km", false, 0), Some((2, 6)));
+        assert_eq!(find_in_line("漢字", "字", false, 0), Some((3, 6)));
+        assert_eq!(find_in_line("abc", "abcd", false, 0), None);
+    }
+}
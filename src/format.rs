@@ -0,0 +1,694 @@
+// Format-aware text transforms (JSON, XML) used by the formatting commands.
+// Each transform takes the text to rewrite and either returns the new text or
+// a message describing where the input could not be understood.
+
+use unicode_width::UnicodeWidthStr;
+
+/// Rewrites relaxed JSON (trailing commas, single-quoted strings, comments)
+/// towards strict JSON. Comments are kept in the output but ignored when the
+/// result is validated.
+pub fn normalize_json(text: &str, strip_commas: bool, double_quotes: bool) -> Result<String, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    // Same as `out` but with comments blanked out, for validation
+    let mut check = String::with_capacity(text.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '"' || c == '\'' {
+            let end = string_end(&chars, i)
+                .ok_or_else(|| format!("Unterminated string starting at {}", position(&chars, i)))?;
+            let literal: String = if c == '\'' && double_quotes {
+                requote(&chars[i + 1..end])
+            } else {
+                chars[i..=end].iter().collect()
+            };
+            out.push_str(&literal);
+            check.push_str(&literal);
+            i = end + 1;
+        } else if let Some(end) = comment_end(&chars, i) {
+            let comment: String = chars[i..end].iter().collect();
+            out.push_str(&comment);
+            check.extend(comment.chars().filter(|&ch| ch == '\n'));
+            i = end;
+        } else if c == ',' && strip_commas && closes_after(&chars, i + 1) {
+            i += 1;
+        } else {
+            out.push(c);
+            check.push(c);
+            i += 1;
+        }
+    }
+
+    serde_json::from_str::<serde_json::Value>(&check)
+        .map_err(|e| format!("JSON error: {}", e))?;
+    Ok(out)
+}
+
+/// Index of the quote closing the string that opens at `start`.
+fn string_end(chars: &[char], start: usize) -> Option<usize> {
+    let quote = chars[start];
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            c if c == quote => return Some(i),
+            '\n' => return None,
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// If a `//` or `/* */` comment starts at `start`, the index just past it.
+fn comment_end(chars: &[char], start: usize) -> Option<usize> {
+    if chars[start] != '/' {
+        return None;
+    }
+    match chars.get(start + 1) {
+        Some('/') => Some(
+            (start..chars.len())
+                .find(|&i| chars[i] == '\n')
+                .unwrap_or(chars.len()),
+        ),
+        Some('*') => Some(
+            (start + 2..chars.len())
+                .find(|&i| chars[i] == '*' && chars.get(i + 1) == Some(&'/'))
+                .map(|i| i + 2)
+                .unwrap_or(chars.len()),
+        ),
+        _ => None,
+    }
+}
+
+/// True when the next significant character from `from` closes an object or
+/// array, i.e. a comma just before it is a trailing comma.
+fn closes_after(chars: &[char], from: usize) -> bool {
+    let mut i = from;
+    while i < chars.len() {
+        if chars[i].is_whitespace() {
+            i += 1;
+        } else if let Some(end) = comment_end(chars, i) {
+            i = end;
+        } else {
+            return chars[i] == '}' || chars[i] == ']';
+        }
+    }
+    false
+}
+
+/// Turns the body of a single-quoted string into a double-quoted literal.
+fn requote(body: &[char]) -> String {
+    let mut result = String::from("\"");
+    let mut i = 0;
+    while i < body.len() {
+        match body[i] {
+            '\\' if body.get(i + 1) == Some(&'\'') => {
+                result.push('\'');
+                i += 2;
+            }
+            '\\' => {
+                result.push('\\');
+                if let Some(&next) = body.get(i + 1) {
+                    result.push(next);
+                }
+                i += 2;
+            }
+            '"' => {
+                result.push_str("\\\"");
+                i += 1;
+            }
+            c => {
+                result.push(c);
+                i += 1;
+            }
+        }
+    }
+    result.push('"');
+    result
+}
+
+fn position(chars: &[char], index: usize) -> String {
+    let line = chars[..index].iter().filter(|&&c| c == '\n').count() + 1;
+    let column = index - chars[..index].iter().rposition(|&c| c == '\n').map(|p| p + 1).unwrap_or(0) + 1;
+    format!("line {} column {}", line, column)
+}
+
+/// Re-wraps `lines` so no line is wider than `width` columns, keeping the
+/// first line's indentation. With a `comment_prefix` (`//`, `#`) and every
+/// non-blank line starting with it, the prefix is treated as part of the
+/// indentation: the comment text is wrapped and each line re-prefixed.
+/// Blank lines (and empty comment lines) separate paragraphs and are kept.
+pub fn reflow(lines: &[String], width: usize, comment_prefix: Option<&str>) -> Vec<String> {
+    let first = match lines.iter().find(|l| !l.trim().is_empty()) {
+        Some(first) => first,
+        None => return lines.to_vec(),
+    };
+    let indent: String = first.chars().take_while(|c| c.is_whitespace()).collect();
+    // Take in doc-comment variants such as `///` and `//!`
+    let marker = comment_prefix.and_then(|p| {
+        let rest = first.trim_start().strip_prefix(p)?;
+        let extra: String = rest.chars().take_while(|c| !c.is_alphanumeric() && !c.is_whitespace()).collect();
+        Some(format!("{}{}", p, extra))
+    });
+    let comment = marker
+        .as_deref()
+        .filter(|p| lines.iter().all(|l| l.trim().is_empty() || l.trim_start().starts_with(p)));
+    let lead = match comment {
+        Some(p) => format!("{}{} ", indent, p),
+        None => indent.clone(),
+    };
+    // The text of each line without its indentation and comment marker
+    let body = |line: &str| -> String {
+        let text = line.trim_start();
+        match comment {
+            Some(p) => text.strip_prefix(p).unwrap_or(text).trim().to_string(),
+            None => text.trim_end().to_string(),
+        }
+    };
+
+    let mut result = Vec::new();
+    let mut current = String::new();
+    let flush = |current: &mut String, result: &mut Vec<String>| {
+        if !current.is_empty() {
+            result.push(format!("{}{}", lead, current));
+            current.clear();
+        }
+    };
+    for line in lines {
+        let text = body(line);
+        if text.is_empty() {
+            flush(&mut current, &mut result);
+            result.push(match comment {
+                Some(p) if !line.trim().is_empty() => format!("{}{}", indent, p),
+                _ => String::new(),
+            });
+            continue;
+        }
+        for word in text.split_whitespace() {
+            if !current.is_empty() && lead.width() + current.width() + 1 + word.width() > width {
+                flush(&mut current, &mut result);
+            }
+            if !current.is_empty() {
+                current.push(' ');
+            }
+            current.push_str(word);
+        }
+    }
+    flush(&mut current, &mut result);
+    result
+}
+
+/// Byte position and text of the first assignment-like operator in `line`
+/// outside string literals: `:=`, `=` (with a compound prefix such as `+=`,
+/// but not `==`, `<=`, `=>`), or a single `:`. With `only`, just that
+/// operator counts.
+fn find_assign_operator(line: &str, only: Option<&str>) -> Option<(usize, String)> {
+    let bytes = line.as_bytes();
+    let mut quote = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let next = bytes.get(i + 1).copied();
+        let prev = if i > 0 { Some(bytes[i - 1]) } else { None };
+        let found = match (quote, c) {
+            (Some(q), b'\\') if q != b'`' => {
+                i += 2;
+                continue;
+            }
+            (Some(q), c) => {
+                if c == q {
+                    quote = None;
+                }
+                None
+            }
+            (None, b'"') | (None, b'\'') | (None, b'`') => {
+                quote = Some(c);
+                None
+            }
+            (None, b':') if next == Some(b'=') => Some((i, ":=".to_string())),
+            (None, b':') if next != Some(b':') && prev != Some(b':') => Some((i, ":".to_string())),
+            (None, b'=') if next != Some(b'=') && next != Some(b'>') => match prev {
+                Some(b'=') | Some(b'!') | Some(b'<') | Some(b'>') | Some(b':') => None,
+                Some(p) if b"+-*/%|&^".contains(&p) => Some((i - 1, format!("{}=", p as char))),
+                _ => Some((i, "=".to_string())),
+            },
+            _ => None,
+        };
+        if let Some((at, op)) = found {
+            let kind = if op.ends_with('=') && op != ":=" { "=" } else { op.as_str() };
+            if only.is_none_or(|only| only == kind) {
+                return Some((at, op));
+            }
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Lines up the assignment operators of `lines`. The operator kind is `:=`
+/// if any line has one, else `=`, else `:`; lines without it are left
+/// alone. `=` and `:=` get one space on each side, while `:`
+/// stays on its name and the values after it are aligned. Returns the new
+/// lines and how many were aligned.
+pub fn align_assignments(lines: &[String]) -> (Vec<String>, usize) {
+    let kind = match [":=", "=", ":"]
+        .into_iter()
+        .find(|kind| lines.iter().any(|l| find_assign_operator(l, Some(kind)).is_some()))
+    {
+        Some(kind) => kind.to_string(),
+        None => return (lines.to_vec(), 0),
+    };
+    let parts: Vec<Option<(&str, String, &str)>> = lines
+        .iter()
+        .map(|line| {
+            find_assign_operator(line, Some(&kind))
+                .map(|(at, op)| (line[..at].trim_end(), op.clone(), line[at + op.len()..].trim_start()))
+        })
+        .collect();
+    let left_width = parts.iter().flatten().map(|(left, _, _)| left.width()).max().unwrap_or(0);
+    let op_width = parts.iter().flatten().map(|(_, op, _)| op.len()).max().unwrap_or(0);
+
+    let mut count = 0;
+    let aligned = lines
+        .iter()
+        .zip(&parts)
+        .map(|(line, part)| match part {
+            Some((left, op, right)) => {
+                count += 1;
+                let pad = " ".repeat(left_width - left.width());
+                let aligned = if kind == ":" {
+                    format!("{}:{} {}", left, pad, right)
+                } else {
+                    format!("{}{} {:>w$} {}", left, pad, op, right, w = op_width)
+                };
+                aligned.trim_end().to_string()
+            }
+            None => line.clone(),
+        })
+        .collect();
+    (aligned, count)
+}
+
+/// Collapses runs of identical lines into one, prefixed with the run length
+/// like `uniq -c`: right-aligned to `width` (default: the widest count) and
+/// followed by `separator`.
+pub fn count_duplicates(lines: &[String], width: Option<usize>, separator: &str) -> Vec<String> {
+    let mut runs: Vec<(usize, &String)> = Vec::new();
+    for line in lines {
+        match runs.last_mut() {
+            Some((count, last)) if *last == line => *count += 1,
+            _ => runs.push((1, line)),
+        }
+    }
+    let width = width.unwrap_or_else(|| runs.iter().map(|(count, _)| count.to_string().len()).max().unwrap_or(1));
+    runs.iter()
+        .map(|(count, line)| format!("{:>width$}{}{}", count, separator, line, width = width))
+        .collect()
+}
+
+/// Capitalizes the first letter of every word and lowercases the rest. An
+/// apostrophe inside a word does not start a new one ("don't" -> "Don't").
+pub fn title_case(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut in_word = false;
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            if in_word {
+                out.extend(c.to_lowercase());
+            } else {
+                out.extend(c.to_uppercase());
+            }
+            in_word = true;
+        } else {
+            out.push(c);
+            in_word = in_word && c == '\'';
+        }
+    }
+    out
+}
+
+/// Rotates ASCII letters 13 places; anything else is left as it is.
+pub fn rot13(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            'a'..='z' => ((c as u8 - b'a' + 13) % 26 + b'a') as char,
+            'A'..='Z' => ((c as u8 - b'A' + 13) % 26 + b'A') as char,
+            _ => c,
+        })
+        .collect()
+}
+
+/// A classic hex dump of `bytes`, 16 to a row: the offset, the bytes in
+/// hex in two groups of eight, then the printable ASCII ones between bars
+/// with `.` for the rest.
+pub fn hex_dump(bytes: &[u8]) -> Vec<String> {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(row, chunk)| {
+            let mut hex = String::new();
+            for i in 0..16 {
+                if i == 8 {
+                    hex.push(' ');
+                }
+                match chunk.get(i) {
+                    Some(byte) => hex.push_str(&format!("{:02x} ", byte)),
+                    None => hex.push_str("   "),
+                }
+            }
+            let ascii: String = chunk
+                .iter()
+                .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                .collect();
+            format!("{:08x}  {} |{}|", row * 16, hex, ascii)
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+enum XmlToken {
+    Open(String, String),
+    Close(String, String),
+    SelfClose(String),
+    Other(String),
+    Text(String),
+}
+
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track", "wbr",
+];
+
+/// Re-indents XML/HTML with one element per line, keeping text-only
+/// elements such as `<b>bold</b>` on a single line.
+pub fn format_xml(text: &str, indent_width: usize) -> Result<String, String> {
+    let tokens = tokenize_xml(text)?;
+    let indent = |depth: usize| " ".repeat(depth * indent_width);
+    let mut lines = Vec::new();
+    let mut depth = 0;
+    let mut i = 0;
+
+    while i < tokens.len() {
+        match &tokens[i] {
+            XmlToken::Open(name, raw) => {
+                // Keep `<a>text</a>` and `<a></a>` together
+                match (tokens.get(i + 1), tokens.get(i + 2)) {
+                    (Some(XmlToken::Close(close, end)), _) if close == name => {
+                        lines.push(format!("{}{}{}", indent(depth), raw, end));
+                        i += 2;
+                        continue;
+                    }
+                    (Some(XmlToken::Text(body)), Some(XmlToken::Close(close, end))) if close == name => {
+                        lines.push(format!("{}{}{}{}", indent(depth), raw, collapse_text(body), end));
+                        i += 3;
+                        continue;
+                    }
+                    _ => {}
+                }
+                lines.push(format!("{}{}", indent(depth), raw));
+                depth += 1;
+            }
+            XmlToken::Close(_, raw) => {
+                depth = depth.saturating_sub(1);
+                lines.push(format!("{}{}", indent(depth), raw));
+            }
+            XmlToken::SelfClose(raw) | XmlToken::Other(raw) => {
+                lines.push(format!("{}{}", indent(depth), raw));
+            }
+            XmlToken::Text(body) => {
+                lines.push(format!("{}{}", indent(depth), collapse_text(body)));
+            }
+        }
+        i += 1;
+    }
+
+    Ok(lines.join("\n"))
+}
+
+/// Removes the whitespace between elements, producing a single line.
+pub fn minify_xml(text: &str) -> Result<String, String> {
+    let tokens = tokenize_xml(text)?;
+    Ok(tokens
+        .iter()
+        .map(|token| match token {
+            XmlToken::Open(_, raw) | XmlToken::Close(_, raw) | XmlToken::SelfClose(raw) | XmlToken::Other(raw) => {
+                raw.clone()
+            }
+            XmlToken::Text(body) => collapse_text(body),
+        })
+        .collect())
+}
+
+/// Splits XML/HTML into tags and text, checking that elements are properly
+/// nested. Whitespace-only text between tags is dropped.
+fn tokenize_xml(text: &str) -> Result<Vec<XmlToken>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut open: Vec<(String, usize)> = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        if chars[i] != '<' {
+            let end = (i..chars.len()).find(|&j| chars[j] == '<').unwrap_or(chars.len());
+            let body: String = chars[i..end].iter().collect();
+            if !body.trim().is_empty() {
+                tokens.push(XmlToken::Text(body));
+            }
+            i = end;
+            continue;
+        }
+
+        let end = tag_end(&chars, i).ok_or_else(|| format!("Unterminated tag at {}", position(&chars, i)))?;
+        let raw: String = chars[i..end].iter().collect();
+        let raw = if raw.contains('\n') {
+            raw.split_whitespace().collect::<Vec<_>>().join(" ")
+        } else {
+            raw
+        };
+
+        if raw.starts_with("<!") || raw.starts_with("<?") {
+            tokens.push(XmlToken::Other(raw));
+        } else if let Some(rest) = raw.strip_prefix("</") {
+            let name = tag_name(rest);
+            match open.pop() {
+                Some((expected, _)) if expected.eq_ignore_ascii_case(&name) => {}
+                Some((expected, _)) => {
+                    return Err(format!(
+                        "Expected </{}> but found </{}> at {}",
+                        expected,
+                        name,
+                        position(&chars, i)
+                    ))
+                }
+                None => return Err(format!("Unexpected </{}> at {}", name, position(&chars, i))),
+            }
+            tokens.push(XmlToken::Close(name, raw));
+        } else {
+            let name = tag_name(&raw[1..]);
+            if raw.ends_with("/>") || VOID_ELEMENTS.contains(&name.to_ascii_lowercase().as_str()) {
+                tokens.push(XmlToken::SelfClose(raw));
+            } else if name.eq_ignore_ascii_case("script") || name.eq_ignore_ascii_case("style") {
+                // Raw text elements: copy the body verbatim up to the closing tag
+                let close = format!("</{}", name.to_ascii_lowercase());
+                let body_end = (end..chars.len())
+                    .find(|&j| chars[j..].iter().take(close.len()).collect::<String>().to_ascii_lowercase() == close)
+                    .ok_or_else(|| format!("Unclosed <{}> at {}", name, position(&chars, i)))?;
+                let close_end =
+                    tag_end(&chars, body_end).ok_or_else(|| format!("Unterminated tag at {}", position(&chars, body_end)))?;
+                let body: String = chars[end..body_end].iter().collect();
+                let close_raw: String = chars[body_end..close_end].iter().collect();
+                tokens.push(XmlToken::Open(name.clone(), raw));
+                if !body.trim().is_empty() {
+                    tokens.push(XmlToken::Text(body));
+                }
+                tokens.push(XmlToken::Close(name, close_raw));
+                i = close_end;
+                continue;
+            } else {
+                open.push((name.clone(), i));
+                tokens.push(XmlToken::Open(name, raw));
+            }
+        }
+        i = end;
+    }
+
+    if let Some((name, at)) = open.pop() {
+        return Err(format!("Unclosed <{}> at {}", name, position(&chars, at)));
+    }
+    Ok(tokens)
+}
+
+/// Index just past the tag, comment, or CDATA section starting at `start`.
+fn tag_end(chars: &[char], start: usize) -> Option<usize> {
+    let rest: String = chars[start..].iter().take(9).collect();
+    let terminator = if rest.starts_with("<!--") {
+        Some("-->")
+    } else if rest.starts_with("<![CDATA[") {
+        Some("]]>")
+    } else {
+        None
+    };
+    if let Some(terminator) = terminator {
+        let term: Vec<char> = terminator.chars().collect();
+        return (start + 4..=chars.len().saturating_sub(term.len()))
+            .find(|&j| chars[j..j + term.len()] == term[..])
+            .map(|j| j + term.len());
+    }
+
+    let mut quote = None;
+    for (j, &c) in chars.iter().enumerate().skip(start + 1) {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '>') => return Some(j + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn tag_name(after_bracket: &str) -> String {
+    after_bracket
+        .chars()
+        .take_while(|c| !c.is_whitespace() && *c != '/' && *c != '>')
+        .collect()
+}
+
+fn collapse_text(body: &str) -> String {
+    body.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_json_strips_trailing_commas() {
+        let text = "{\n  \"a\": [1, 2, 3,],\n  \"b\": {\"c\": true,},\n}";
+        assert_eq!(
+            normalize_json(text, true, false).unwrap(),
+            "{\n  \"a\": [1, 2, 3],\n  \"b\": {\"c\": true}\n}"
+        );
+    }
+
+    #[test]
+    fn normalize_json_keeps_commas_in_strings_and_before_comments() {
+        let text = "[\"a,]\", // last\n 2, /* done */ ]";
+        assert_eq!(normalize_json(text, true, false).unwrap(), "[\"a,]\", // last\n 2 /* done */ ]");
+    }
+
+    #[test]
+    fn normalize_json_requotes_single_quoted_strings() {
+        let text = "{'key': 'it\\'s \"x\"'}";
+        assert_eq!(normalize_json(text, false, true).unwrap(), "{\"key\": \"it's \\\"x\\\"\"}");
+    }
+
+    #[test]
+    fn normalize_json_reports_where_it_failed() {
+        assert_eq!(
+            normalize_json("{\n  \"a\": \"open\n}", true, true),
+            Err("Unterminated string starting at line 2 column 8".to_string())
+        );
+        assert!(normalize_json("{\"a\": 1,}", false, true).unwrap_err().starts_with("JSON error:"));
+    }
+
+    #[test]
+    fn format_xml_indents_a_compact_snippet() {
+        let text = "<?xml version=\"1.0\"?><root><item id=\"1\"><name>One</name></item><empty></empty></root>";
+        assert_eq!(
+            format_xml(text, 2).unwrap(),
+            "<?xml version=\"1.0\"?>\n<root>\n  <item id=\"1\">\n    <name>One</name>\n  </item>\n  <empty></empty>\n</root>"
+        );
+    }
+
+    #[test]
+    fn format_xml_keeps_self_closing_and_void_tags_at_one_level() {
+        let text = "<div><br><img src=\"a.png\"/><p>Hi <b>there</b></p></div>";
+        assert_eq!(
+            format_xml(text, 4).unwrap(),
+            "<div>\n    <br>\n    <img src=\"a.png\"/>\n    <p>\n        Hi\n        <b>there</b>\n    </p>\n</div>"
+        );
+    }
+
+    #[test]
+    fn minify_xml_joins_elements_onto_one_line() {
+        let text = "<a>\n  <b x=\"1\" />\n  <c>  some   text </c>\n</a>";
+        assert_eq!(minify_xml(text).unwrap(), "<a><b x=\"1\" /><c>some text</c></a>");
+    }
+
+    #[test]
+    fn format_xml_reports_mismatched_tags() {
+        assert_eq!(format_xml("<a>\n  <b></a>", 2), Err("Expected </b> but found </a> at line 2 column 6".to_string()));
+        assert_eq!(format_xml("<a><b/>", 2), Err("Unclosed <a> at line 1 column 1".to_string()));
+        assert_eq!(minify_xml("<a x=\"1\""), Err("Unterminated tag at line 1 column 1".to_string()));
+    }
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn reflow_rewraps_a_line_comment() {
+        let text = "    // The quick brown fox jumps over\n    // the lazy dog and keeps running.";
+        assert_eq!(
+            reflow(&lines(text), 30, Some("//")),
+            lines("    // The quick brown fox\n    // jumps over the lazy dog\n    // and keeps running.")
+        );
+    }
+
+    #[test]
+    fn reflow_keeps_doc_comment_markers_and_paragraphs() {
+        let text = "/// One two three four five six.\n///\n/// Seven eight.";
+        assert_eq!(
+            reflow(&lines(text), 16, Some("//")),
+            lines("/// One two\n/// three four\n/// five six.\n///\n/// Seven eight.")
+        );
+    }
+
+    #[test]
+    fn reflow_treats_mixed_lines_as_plain_text() {
+        let text = "// a comment\nlet x = 1;";
+        assert_eq!(reflow(&lines(text), 80, Some("//")), lines("// a comment let x = 1;"));
+        assert_eq!(reflow(&lines("  é ü 漢字 かな"), 9, None), lines("  é ü\n  漢字\n  かな"));
+        assert_eq!(reflow(&lines("\n  \n"), 10, Some("#")), lines("\n  \n"));
+    }
+
+    #[test]
+    fn align_assignments_lines_up_compound_operators() {
+        let text = "let a = 1;\nlet long_name += 2;\n// no operator\nx==y";
+        let (aligned, count) = align_assignments(&lines(text));
+        assert_eq!(aligned, lines("let a          = 1;\nlet long_name += 2;\n// no operator\nx==y"));
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn align_assignments_prefers_walrus_and_skips_strings() {
+        let text = "s := \"a = b\"\nlonger := 2\nplain = 3";
+        let (aligned, count) = align_assignments(&lines(text));
+        assert_eq!(aligned, lines("s      := \"a = b\"\nlonger := 2\nplain = 3"));
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn align_assignments_keeps_colons_on_their_keys() {
+        let text = "name: vedit\nvérsion:  1\n漢字: x";
+        let (aligned, count) = align_assignments(&lines(text));
+        assert_eq!(aligned, lines("name:    vedit\nvérsion: 1\n漢字:    x"));
+        assert_eq!(count, 3);
+        assert_eq!(align_assignments(&lines("a::b\nc")), (lines("a::b\nc"), 0));
+    }
+
+    #[test]
+    fn count_duplicates_collapses_adjacent_runs_only() {
+        let text = "a\na\nb\na\na\na\na\na\na\na\na\na\na";
+        assert_eq!(count_duplicates(&lines(text), None, " "), lines(" 2 a\n 1 b\n10 a"));
+        assert_eq!(count_duplicates(&lines("x\nx"), Some(4), ": "), lines("   2: x"));
+    }
+
+    #[test]
+    fn count_duplicates_keeps_blank_and_multibyte_lines() {
+        assert_eq!(count_duplicates(&lines("\n\nü\nü\nu"), None, "\t"), lines("2\t\n2\tü\n1\tu"));
+        assert!(count_duplicates(&[], None, " ").is_empty());
+    }
+}
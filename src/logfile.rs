@@ -0,0 +1,40 @@
+// Log files written by vedit (debug log, AI interactions), kept out of the
+// working directory and rotated by size.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Size limit used when the config does not set one.
+pub const DEFAULT_MAX_BYTES: u64 = 1024 * 1024;
+
+/// `~/.vedit/<name>`, where logs go unless configured otherwise.
+pub fn default_path(name: &str) -> PathBuf {
+    home::home_dir()
+        .unwrap_or_default()
+        .join(".vedit")
+        .join(name)
+}
+
+/// Turns a configured path into a real one, expanding a leading `~/`.
+pub fn resolve(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => home::home_dir().unwrap_or_default().join(rest),
+        None => PathBuf::from(path),
+    }
+}
+
+/// Opens `path` for appending, creating its directory. A file already
+/// larger than `max_bytes` is first renamed to `<path>.1`, replacing any
+/// older rotation.
+pub fn open(path: &Path, max_bytes: u64) -> io::Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    if fs::metadata(path).map(|m| m.len() > max_bytes).unwrap_or(false) {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+        fs::rename(path, rotated)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
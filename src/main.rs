@@ -1,39 +1,111 @@
 use clap::Parser;
 use config::EditorConfig;
-use std::collections::HashMap;
-use std::fs;
-use std::io::Write;
+use std::io::{IsTerminal, Read, Write};
 
 mod ai;
+mod commands;
 mod config;
 mod editor;
+mod format;
+mod logfile;
+mod spell;
+mod swap;
+mod symbols;
 mod syntax;
 mod ui;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// The file to edit
+    /// The file to edit; FILE:N opens it at line N
     filename: Option<String>,
 
-    /// Enable debug logging to "vedit.log"
+    /// Line to open the file at, written +N
+    #[arg(value_name = "+LINE")]
+    line: Option<String>,
+
+    /// Enable debug logging to "~/.vedit/vedit.log"
     #[arg(short, long)]
     debug: bool,
+
+    /// Highlight as this syntax instead of detecting it (e.g. "Rust")
+    #[arg(long, value_name = "NAME")]
+    syntax: Option<String>,
+}
+
+/// Everything piped into stdin, when no file was named and stdin is not a
+/// terminal (`cat foo | vedit`). The buffer then starts out unnamed.
+fn read_piped_stdin() -> Option<String> {
+    let mut stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        return None;
+    }
+    let mut contents = String::new();
+    stdin.read_to_string(&mut contents).ok()?;
+    Some(contents.replace("\r\n", "\n").replace('\r', "\n"))
+}
+
+/// The text of the file to edit; empty when it does not exist yet. Exits
+/// when it cannot be read as text, rather than opening an empty buffer that
+/// could be saved over it.
+fn read_file(path: &str) -> String {
+    match commands::read_text_file(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            eprintln!("Cannot open {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The `--syntax` name as the syntax set spells it; exits listing the valid
+/// names when it matches none.
+fn forced_syntax(name: &str) -> String {
+    let names = syntax::syntax_names();
+    match names.iter().find(|n| n.eq_ignore_ascii_case(name)) {
+        Some(found) => found.clone(),
+        None => {
+            eprintln!("Unknown syntax '{}'. Valid names are:\n  {}", name, names.join("\n  "));
+            std::process::exit(1);
+        }
+    }
 }
 
-fn detect_syntax(filename: &str, syntax_map: &HashMap<String, String>) -> Option<String> {
-    std::path::Path::new(filename)
-        .extension()
-        .and_then(|ext| ext.to_str())
-        .and_then(|ext| syntax_map.get(ext).cloned())
+/// Splits the start line off the command line: `+N` after the file name, or
+/// `FILE:N` as printed by `grep -n` (unless a file with that exact name
+/// exists).
+fn split_line_spec(filename: Option<String>, line: Option<&str>) -> (Option<String>, Option<usize>) {
+    if let Some(spec) = line {
+        let number = spec.strip_prefix('+').and_then(|n| n.parse().ok()).unwrap_or_else(|| {
+            eprintln!("Invalid line '{}', expected +N.", spec);
+            std::process::exit(1);
+        });
+        return (filename, Some(number));
+    }
+    if let Some(name) = &filename {
+        if !std::path::Path::new(name).exists() {
+            if let Some((path, number)) = name.rsplit_once(':') {
+                if let Ok(number) = number.parse() {
+                    return (Some(path.to_string()), Some(number));
+                }
+            }
+        }
+    }
+    (filename, None)
 }
 
 fn main() {
     let cli = Cli::parse();
+    let forced_syntax = cli.syntax.as_deref().map(forced_syntax);
+    let (filename, start_line) = split_line_spec(cli.filename, cli.line.as_deref());
+    // Unsaved work left behind by a session that did not exit cleanly
+    let recovery = filename.as_deref().and_then(swap::find);
 
     // Set up logging if debug flag is present
     if cli.debug {
-        let mut log_file = fs::File::create("vedit.log").expect("Failed to create log file");
+        let mut log_file = logfile::open(&logfile::default_path("vedit.log"), logfile::DEFAULT_MAX_BYTES)
+            .expect("Failed to create log file");
         writeln!(log_file, "Debug mode enabled.").unwrap();
 
         let config = EditorConfig::load().unwrap_or_else(|e| {
@@ -44,27 +116,41 @@ fn main() {
 
         let syntax_engine = syntax::SyntaxEngine::new(&config.theme);
         writeln!(log_file, "Syntax engine created for theme '{}'.", config.theme).unwrap();
+        if let Some(path) = &recovery {
+            writeln!(log_file, "Found recovery file: {}", path).unwrap();
+        }
 
-        let syntax_name = cli
-            .filename
-            .as_ref()
-            .and_then(|f| detect_syntax(f, &config.syntax_map))
-            .unwrap_or_else(|| "Plain Text".to_string());
-        writeln!(log_file, "Detected syntax: '{}'", syntax_name).unwrap();
-
-        let buffer = match &cli.filename {
+        let buffer = match &filename {
             Some(path) => {
                 writeln!(log_file, "Loading file: {}", path).unwrap();
-                let contents = fs::read_to_string(path).unwrap_or_default();
-                contents.replace("\r\n", "\n").replace('\r', "\n")
+                read_file(path)
+            }
+            None => match read_piped_stdin() {
+                Some(contents) => {
+                    writeln!(log_file, "No file specified, read {} bytes from stdin.", contents.len()).unwrap();
+                    contents
+                }
+                None => {
+                    writeln!(log_file, "No file specified, starting with empty buffer.").unwrap();
+                    String::new()
+                }
+            },
+        };
+
+        let first_line = buffer.lines().next().unwrap_or("");
+        let syntax_name = match forced_syntax {
+            Some(name) => {
+                writeln!(log_file, "Syntax forced: '{}'", name).unwrap();
+                name
             }
             None => {
-                writeln!(log_file, "No file specified, starting with empty buffer.").unwrap();
-                String::new()
+                let name = syntax::resolve_syntax(filename.as_deref(), first_line, &config);
+                writeln!(log_file, "Detected syntax: '{}'", name).unwrap();
+                name
             }
         };
 
-        ui::run_editor(buffer, config, syntax_engine, syntax_name, cli.filename);
+        ui::run_editor(buffer, config, syntax_engine, syntax_name, filename, start_line, recovery);
     } else {
         // Original logic without logging
         let config = EditorConfig::load().unwrap_or_else(|e| {
@@ -74,20 +160,15 @@ fn main() {
 
         let syntax_engine = syntax::SyntaxEngine::new(&config.theme);
 
-        let syntax_name = cli
-            .filename
-            .as_ref()
-            .and_then(|f| detect_syntax(f, &config.syntax_map))
-            .unwrap_or_else(|| "Plain Text".to_string());
-
-        let buffer = match &cli.filename {
-            Some(path) => {
-                let contents = fs::read_to_string(path).unwrap_or_default();
-                contents.replace("\r\n", "\n").replace('\r', "\n")
-            }
-            None => String::new(),
+        let buffer = match &filename {
+            Some(path) => read_file(path),
+            None => read_piped_stdin().unwrap_or_default(),
         };
 
-        ui::run_editor(buffer, config, syntax_engine, syntax_name, cli.filename);
+        let first_line = buffer.lines().next().unwrap_or("");
+        let syntax_name = forced_syntax
+            .unwrap_or_else(|| syntax::resolve_syntax(filename.as_deref(), first_line, &config));
+
+        ui::run_editor(buffer, config, syntax_engine, syntax_name, filename, start_line, recovery);
     }
 }
@@ -0,0 +1,104 @@
+// Spell checking for prose. Words are looked up in a plain word list, one
+// word per line like /usr/share/dict/words, and in the user's own list of
+// accepted words, ~/.vedit/words.
+
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use unicode_width::UnicodeWidthChar;
+
+/// Word list used when the config does not name one.
+pub const DEFAULT_DICTIONARY: &str = "/usr/share/dict/words";
+
+/// Syntaxes checked when the config does not list them.
+pub const DEFAULT_SYNTAXES: &[&str] = &["Markdown", "Plain Text"];
+
+pub struct SpellChecker {
+    words: HashSet<String>,
+    personal_path: PathBuf,
+}
+
+/// `~/.vedit/words`, where accepted words are kept.
+fn personal_path() -> PathBuf {
+    home::home_dir().unwrap_or_default().join(".vedit").join("words")
+}
+
+fn read_words(path: &Path) -> io::Result<Vec<String>> {
+    let contents = fs::read_to_string(path)?;
+    Ok(contents.lines().map(str::trim).filter(|w| !w.is_empty()).map(str::to_string).collect())
+}
+
+impl SpellChecker {
+    /// Loads the word list at `dictionary` and the accepted words, if any.
+    pub fn load(dictionary: &Path) -> io::Result<Self> {
+        let mut words: HashSet<String> = read_words(dictionary)?.into_iter().collect();
+        let personal_path = personal_path();
+        if let Ok(personal) = read_words(&personal_path) {
+            words.extend(personal);
+        }
+        Ok(SpellChecker { words, personal_path })
+    }
+
+    /// Known as written, or in lower case so that capitalised and
+    /// upper-case words are found too.
+    pub fn is_known(&self, word: &str) -> bool {
+        let word = word.strip_suffix("'s").unwrap_or(word);
+        self.words.contains(word) || self.words.contains(&word.to_lowercase())
+    }
+
+    /// Display column ranges of the words in `line` that are not known.
+    pub fn misspelled(&self, line: &str) -> Vec<(usize, usize)> {
+        words(line)
+            .into_iter()
+            .filter(|(_, _, word)| !self.is_known(word))
+            .map(|(start, end, _)| (start, end))
+            .collect()
+    }
+
+    /// Accepts `word` from now on and adds it to the personal word list.
+    pub fn accept(&mut self, word: &str) -> io::Result<()> {
+        if let Some(dir) = self.personal_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.personal_path)?;
+        writeln!(file, "{}", word)?;
+        self.words.insert(word.to_string());
+        Ok(())
+    }
+}
+
+/// The words of `line` worth checking, with their display columns. Runs of
+/// letters count, with apostrophes inside them; runs that touch digits or
+/// underscores, single letters and camelCase names are left alone as code.
+pub fn words(line: &str) -> Vec<(usize, usize, &str)> {
+    let mut words = Vec::new();
+    let mut column = 0;
+    let mut run: Option<(usize, usize)> = None;
+    let chars = line.char_indices().chain(std::iter::once((line.len(), ' ')));
+    for (byte, ch) in chars {
+        if ch.is_alphanumeric() || ch == '_' || ch == '\'' {
+            run.get_or_insert((byte, column));
+        } else if let Some((start, start_column)) = run.take() {
+            let text = &line[start..byte];
+            let word = text.trim_matches('\'');
+            let skipped = text.len() - text.trim_start_matches('\'').len();
+            let is_code = word.chars().any(|c| c.is_numeric() || c == '_')
+                || (word.chars().skip(1).any(char::is_uppercase) && word.chars().any(char::is_lowercase));
+            if word.chars().count() > 1 && !is_code {
+                let word_column = start_column + skipped;
+                words.push((word_column, word_column + word.chars().map(|c| c.width().unwrap_or(1)).sum::<usize>(), word));
+            }
+        }
+        column += ch.width().unwrap_or(1);
+    }
+    words
+}
+
+/// The checked word at display column `column`, if any.
+pub fn word_at(line: &str, column: usize) -> Option<&str> {
+    words(line)
+        .into_iter()
+        .find(|&(start, end, _)| start <= column && column <= end)
+        .map(|(_, _, word)| word)
+}
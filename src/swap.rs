@@ -0,0 +1,72 @@
+// Recovery files for unsaved work. While a document has unsaved changes its
+// text is written every few seconds to `.NAME.swp` beside the file, so it can
+// be recovered after a crash; saving the file or quitting removes it.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// How often unsaved changes are written when the config does not say.
+pub const DEFAULT_INTERVAL_MS: u64 = 5000;
+
+/// The recovery file for `path`: `.NAME.swp` in the same directory.
+pub fn swap_path(path: &str) -> String {
+    let path = Path::new(path);
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{}.swp", name)).to_string_lossy().into_owned()
+}
+
+/// The recovery file left behind for `path`, if there is one.
+pub fn find(path: &str) -> Option<String> {
+    let swap = swap_path(path);
+    Path::new(&swap).is_file().then_some(swap)
+}
+
+pub fn write(path: &str, buffer: &[String]) -> io::Result<()> {
+    fs::write(swap_path(path), buffer.join("\n"))
+}
+
+/// Reads back the lines saved for `path`. Split on every newline, so blank
+/// lines at the end come back too.
+pub fn read(path: &str) -> io::Result<Vec<String>> {
+    let contents = fs::read_to_string(swap_path(path))?;
+    Ok(contents.split('\n').map(|line| line.to_string()).collect())
+}
+
+/// Removes the recovery file for `path`, if there is one.
+pub fn remove(path: &str) {
+    let _ = fs::remove_file(swap_path(path));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str) -> String {
+        std::env::temp_dir().join(format!("vedit-{}-{}", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    fn lines(text: &str) -> Vec<String> {
+        text.split('\n').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn swap_path_hides_the_file_beside_the_original() {
+        assert_eq!(swap_path("src/main.rs"), "src/.main.rs.swp");
+        assert_eq!(swap_path("notes.txt"), ".notes.txt.swp");
+    }
+
+    #[test]
+    fn write_then_read_gives_back_the_same_lines() {
+        let path = temp_file("swap-round-trip.txt");
+        assert_eq!(find(&path), None);
+        for buffer in [lines("one\ntwo"), lines("trailing\n\n"), lines(""), lines("héllo\n漢字\ttab")] {
+            write(&path, &buffer).unwrap();
+            assert_eq!(find(&path), Some(swap_path(&path)));
+            assert_eq!(read(&path).unwrap(), buffer);
+        }
+        remove(&path);
+        assert_eq!(find(&path), None);
+        remove(&path);
+    }
+}
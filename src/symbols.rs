@@ -0,0 +1,100 @@
+// Lightweight symbol scanning for the `symbols` command. Each syntax has a
+// list of regexes whose first capture group is the symbol name; a line that
+// matches any of them becomes an entry in the symbol list.
+
+use regex::Regex;
+
+pub struct Symbol {
+    pub line: usize,
+    pub name: String,
+}
+
+/// Built-in patterns, used when the config has no entry for the syntax.
+pub fn default_patterns(syntax: &str) -> Vec<String> {
+    let patterns: &[&str] = match syntax {
+        "Rust" => &[
+            r#"^\s*(?:pub(?:\([^)]*\))?\s+)?(?:(?:async|const|unsafe|extern\s+"[^"]*")\s+)*fn\s+(\w+)"#,
+            r"^\s*(?:pub(?:\([^)]*\))?\s+)?(?:struct|enum|trait|union|type|mod)\s+(\w+)",
+            r"^\s*macro_rules!\s*(\w+)",
+        ],
+        "Python" => &[r"^\s*(?:async\s+)?def\s+(\w+)", r"^\s*class\s+(\w+)"],
+        "JavaScript" | "TypeScript" => &[
+            r"^\s*(?:export\s+)?(?:default\s+)?(?:async\s+)?function\*?\s+(\w+)",
+            r"^\s*(?:export\s+)?(?:default\s+)?class\s+(\w+)",
+        ],
+        "Go" => &[r"^func\s+(?:\([^)]*\)\s*)?(\w+)", r"^type\s+(\w+)"],
+        _ => &[],
+    };
+    patterns.iter().map(|p| p.to_string()).collect()
+}
+
+/// Returns the symbols defined in `buffer`, in line order.
+pub fn scan(buffer: &[String], patterns: &[String]) -> Result<Vec<Symbol>, String> {
+    let regexes = patterns
+        .iter()
+        .map(|p| Regex::new(p).map_err(|e| format!("Bad symbol pattern '{}': {}", p, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(buffer
+        .iter()
+        .enumerate()
+        .filter_map(|(line, text)| {
+            regexes.iter().find_map(|re| {
+                re.captures(text)
+                    .and_then(|caps| caps.get(1))
+                    .map(|name| Symbol { line, name: name.as_str().to_string() })
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = "\
+use std::fmt;
+
+pub struct Point {
+    x: i32,
+}
+
+pub(crate) enum Shape {}
+
+impl Point {
+    pub async fn new() -> Self {
+        todo!()
+    }
+}
+
+fn main() {
+    // a fn in a comment is not a definition
+    let f = |x| x;
+}";
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn scan_finds_rust_fns_and_structs() {
+        let symbols = scan(&lines(FIXTURE), &default_patterns("Rust")).unwrap();
+        let found: Vec<(usize, &str)> = symbols.iter().map(|s| (s.line, s.name.as_str())).collect();
+        assert_eq!(found, vec![(2, "Point"), (6, "Shape"), (9, "new"), (14, "main")]);
+    }
+
+    #[test]
+    fn scan_uses_the_given_patterns() {
+        let patterns = vec![r"^(\w+):".to_string()];
+        let symbols = scan(&lines("start:\n  mov a, b\nloop:"), &patterns).unwrap();
+        let names: Vec<&str> = symbols.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["start", "loop"]);
+        assert!(default_patterns("Plain Text").is_empty());
+    }
+
+    #[test]
+    fn scan_reports_bad_patterns() {
+        let err = scan(&lines("x"), &["(".to_string()]).err().unwrap();
+        assert!(err.starts_with("Bad symbol pattern '(':"));
+    }
+}
@@ -1,15 +1,184 @@
 // Declare modules first
 
 // Then bring items into scope
-//use crate::config::EditorConfig;
+use crate::config::EditorConfig;
 //use std::env;
 //use std::fs;
 
 use ratatui::style::{Color, Modifier, Style as RatatuiStyle};
 use ratatui::text::{Line, Span};
 use syntect::easy::HighlightLines;
-use syntect::highlighting::{FontStyle, Style, Theme, ThemeSet};
-use syntect::parsing::SyntaxSet;
+use syntect::highlighting::{FontStyle, HighlightIterator, HighlightState, Highlighter, Style, Theme, ThemeSet};
+use syntect::html::highlighted_html_for_string;
+use syntect::parsing::{ParseState, ScopeStack, SyntaxSet};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Looks up the syntax name for `filename` by its extension.
+pub fn detect_syntax(filename: &str, syntax_map: &HashMap<String, String>) -> Option<String> {
+    std::path::Path::new(filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| syntax_map.get(ext).cloned())
+}
+
+/// Syntax for a `#!` first line, looking the interpreter up in
+/// `shebang_map` before the built-in list. Version suffixes are ignored, so
+/// `python3.11` matches `python`.
+pub fn detect_shebang(first_line: &str, shebang_map: Option<&HashMap<String, String>>) -> Option<String> {
+    let mut words = first_line.strip_prefix("#!")?.split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|w| !w.starts_with('-') && !w.contains('='))?;
+    }
+    let base = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+
+    if let Some(map) = shebang_map {
+        if let Some(name) = map.get(interpreter).or_else(|| map.get(base)) {
+            return Some(name.clone());
+        }
+    }
+    let name = match base {
+        "sh" | "bash" | "zsh" | "ksh" | "dash" => "Bourne Again Shell (bash)",
+        "python" => "Python",
+        "node" | "nodejs" => "JavaScript",
+        "ruby" => "Ruby",
+        "perl" => "Perl",
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
+/// Line comment marker for a syntax, from `comment_prefixes` or the
+/// built-in list.
+pub fn line_comment_prefix(syntax_name: &str, config: &EditorConfig) -> Option<String> {
+    if let Some(prefix) = config.comment_prefixes.as_ref().and_then(|map| map.get(syntax_name)) {
+        return Some(prefix.clone());
+    }
+    let prefix = match syntax_name {
+        "Rust" | "C" | "C++" | "C#" | "Go" | "Java" | "JavaScript" | "TypeScript" | "Scala" | "D"
+        | "Objective-C" | "Objective-C++" => "//",
+        "Python" | "Ruby" | "Perl" | "Bourne Again Shell (bash)" | "Makefile" | "YAML" | "TOML" | "R" => "#",
+        "SQL" | "Lua" | "Haskell" => "--",
+        "Erlang" | "TeX" | "LaTeX" => "%",
+        "Lisp" | "Clojure" => ";",
+        _ => return None,
+    };
+    Some(prefix.to_string())
+}
+
+/// Syntax for a document: by extension, then by shebang, else plain text.
+pub fn resolve_syntax(filename: Option<&str>, first_line: &str, config: &EditorConfig) -> String {
+    filename
+        .and_then(|f| detect_syntax(f, &config.syntax_map))
+        .or_else(|| detect_shebang(first_line, config.shebang_map.as_ref()))
+        .unwrap_or_else(|| "Plain Text".to_string())
+}
+
+/// Prints `message` to stderr unless it was printed before. The asset
+/// folders are re-read at runtime (theme and syntax lists), when writing to
+/// the terminal would garble the screen.
+fn warn_once(message: String) {
+    static WARNED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    let mut warned = WARNED.lock().unwrap_or_else(|e| e.into_inner());
+    if !warned.contains(&message) {
+        eprintln!("{}", message);
+        warned.push(message);
+    }
+}
+
+/// Existing `assets/<kind>` folders: under the working directory, then next
+/// to the executable. Missing folders are fine; the built-ins are used.
+fn asset_dirs(kind: &str) -> Vec<PathBuf> {
+    let mut dirs = vec![Path::new("assets").join(kind)];
+    if let Some(exe_dir) = std::env::current_exe().ok().and_then(|exe| exe.parent().map(Path::to_path_buf)) {
+        dirs.push(exe_dir.join("assets").join(kind));
+    }
+    let mut seen = Vec::new();
+    for dir in dirs {
+        if let Ok(dir) = dir.canonicalize() {
+            if dir.is_dir() && !seen.contains(&dir) {
+                seen.push(dir);
+            }
+        }
+    }
+    seen
+}
+
+/// The built-in themes plus any found in the `assets/themes` folders. A
+/// folder that fails to load is reported and skipped.
+fn load_themes() -> ThemeSet {
+    let mut theme_set = ThemeSet::load_defaults();
+    for dir in asset_dirs("themes") {
+        if let Err(e) = theme_set.add_from_folder(&dir) {
+            warn_once(format!("Warning: could not load themes from {}: {}", dir.display(), e));
+        }
+    }
+    theme_set
+}
+
+pub fn theme_names() -> Vec<String> {
+    load_themes().themes.keys().cloned().collect()
+}
+
+/// The built-in syntaxes plus any found in the `assets/syntaxes` folders. A
+/// folder that fails to load is reported and skipped.
+fn load_syntaxes() -> SyntaxSet {
+    let mut syntax_set = SyntaxSet::load_defaults_newlines();
+    for dir in asset_dirs("syntaxes") {
+        let mut builder = syntax_set.clone().into_builder();
+        match builder.add_from_folder(&dir, true) {
+            Ok(()) => syntax_set = builder.build(),
+            Err(e) => warn_once(format!("Warning: could not load syntaxes from {}: {}", dir.display(), e)),
+        }
+    }
+    syntax_set
+}
+
+/// Names of the selectable syntaxes, sorted.
+pub fn syntax_names() -> Vec<String> {
+    let mut names: Vec<String> = load_syntaxes()
+        .syntaxes()
+        .iter()
+        .filter(|syntax| !syntax.hidden)
+        .map(|syntax| syntax.name.clone())
+        .collect();
+    names.sort_by_key(|name| name.to_lowercase());
+    names.dedup();
+    names
+}
+
+/// Highlighted lines kept between redraws. Entry `i` holds line `i` and the
+/// highlighter state after it, so it stays valid only while line `i` and
+/// every line above it are unchanged.
+#[derive(Default)]
+pub struct HighlightCache {
+    syntax_name: String,
+    entries: Vec<CachedLine>,
+}
+
+struct CachedLine {
+    hash: u64,
+    line: Line<'static>,
+    parse_state: ParseState,
+    highlight_state: HighlightState,
+}
+
+impl HighlightCache {
+    /// Drops every entry, e.g. after the theme changed.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+fn hash_line(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
 
 pub struct SyntaxEngine {
     pub syntax_set: SyntaxSet,
@@ -36,14 +205,9 @@ fn map_style(style: Style) -> RatatuiStyle {
 
 impl SyntaxEngine {
     pub fn new(theme_name: &str) -> Self {
-        let mut syntax_set_builder = SyntaxSet::load_defaults_newlines().into_builder();
-        syntax_set_builder
-            .add_from_folder("assets/syntaxes", true)
-            .ok(); // Ignore if directory doesn't exist
-        let syntax_set = syntax_set_builder.build();
+        let syntax_set = load_syntaxes();
 
-        let mut theme_set = ThemeSet::load_defaults();
-        theme_set.add_from_folder("assets/themes").ok(); // Ignore if directory doesn't exist
+        let theme_set = load_themes();
 
         let theme = theme_set.themes.get(theme_name).cloned().unwrap_or_else(|| {
             eprintln!("Theme '{}' not found, using default.", theme_name);
@@ -53,7 +217,87 @@ impl SyntaxEngine {
         SyntaxEngine { syntax_set, theme }
     }
 
-    pub fn highlight_line(&self, line: &str, syntax_name: &str) -> Line {
+    /// Highlights buffer lines `rows`, carrying the parser state from line to
+    /// line so constructs spanning lines (block comments, strings) are
+    /// coloured correctly. Results are reused from `cache` up to the first
+    /// line whose text changed.
+    pub fn highlight_rows(
+        &self,
+        cache: &mut HighlightCache,
+        buffer: &[String],
+        syntax_name: &str,
+        rows: &[usize],
+    ) -> Vec<Line<'static>> {
+        let last = match rows.iter().max() {
+            Some(&last) => last,
+            None => return Vec::new(),
+        };
+        if cache.syntax_name != syntax_name {
+            cache.syntax_name = syntax_name.to_string();
+            cache.entries.clear();
+        }
+        let valid = cache
+            .entries
+            .iter()
+            .zip(&buffer[..=last])
+            .take_while(|(entry, text)| entry.hash == hash_line(text))
+            .count();
+        if valid <= last {
+            cache.entries.truncate(valid);
+        }
+
+        let syntax = self
+            .syntax_set
+            .find_syntax_by_name(syntax_name)
+            .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text());
+        let highlighter = Highlighter::new(&self.theme);
+        let (mut parse_state, mut highlight_state) = match cache.entries.last() {
+            Some(entry) => (entry.parse_state.clone(), entry.highlight_state.clone()),
+            None => (ParseState::new(syntax), HighlightState::new(&highlighter, ScopeStack::new())),
+        };
+
+        for text in buffer.iter().take(last + 1).skip(cache.entries.len()) {
+            // The syntaxes are loaded for newline-terminated lines
+            let text_nl = format!("{}\n", text);
+            let ops = parse_state.parse_line(&text_nl, &self.syntax_set).unwrap_or_default();
+            let spans: Vec<Span<'static>> = HighlightIterator::new(&mut highlight_state, &ops, &text_nl, &highlighter)
+                .map(|(style, content)| (style, content.trim_end_matches('\n')))
+                .filter(|(_, content)| !content.is_empty())
+                .map(|(style, content)| Span::styled(content.to_string(), map_style(style)))
+                .collect();
+            cache.entries.push(CachedLine {
+                hash: hash_line(text),
+                line: Line::from(spans),
+                parse_state: parse_state.clone(),
+                highlight_state: highlight_state.clone(),
+            });
+        }
+
+        rows.iter().map(|&y| cache.entries[y].line.clone()).collect()
+    }
+
+    /// A standalone HTML page showing `text` highlighted with the current
+    /// theme, using inline styles so it survives pasting into mail and docs.
+    pub fn highlighted_html(&self, text: &str, syntax_name: &str, title: &str) -> Result<String, String> {
+        let syntax = self
+            .syntax_set
+            .find_syntax_by_name(syntax_name)
+            .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text());
+        // The syntaxes are loaded for newline-terminated lines
+        let mut text = text.to_string();
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        let body = highlighted_html_for_string(&text, &self.syntax_set, syntax, &self.theme)
+            .map_err(|e| e.to_string())?;
+        let title = title.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+        Ok(format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n{}</body>\n</html>\n",
+            title, body
+        ))
+    }
+
+    pub fn highlight_line(&self, line: &str, syntax_name: &str) -> Line<'_> {
         let syntax = self
             .syntax_set
             .find_syntax_by_name(syntax_name)
@@ -1,48 +1,103 @@
-use crate::ai;
-use crate::config::EditorConfig;
-use crate::editor::{AiStatus, Editor, Focus, PromptAction, PromptType, SelectionMode, DiffMode, DiffLine, SearchScope};
+use crate::ai::AiEvent;
+use crate::commands::{self, CommandOutcome};
+use crate::config::{ColorsConfig, EditorConfig};
+use crate::editor::{file_mtime, AiStatus, Editor, Focus, PromptAction, PromptType, SelectionMode, DiffMode, DiffLine, HunkPart, ViewAlign};
+use crate::swap;
 use crate::syntax::SyntaxEngine;
-use std::fs;
-use std::sync::mpsc;
-use std::thread;
+use std::sync::mpsc::TryRecvError;
 use std::time::Instant;
-use unicode_width::UnicodeWidthChar;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 use crossterm::{
     cursor::SetCursorStyle,
-    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseEventKind},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, Clear, ClearType},
 };
 use ratatui::{
-    backend::{CrosstermBackend},
-    layout::{Constraint, Direction, Layout},
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, Clear as ClearWidget, Paragraph},
-    Terminal,
+    buffer::Buffer,
+    widgets::{Block, Borders, Clear as ClearWidget, Paragraph, Scrollbar, ScrollbarOrientation, ScrollbarState, Widget},
+    Frame, Terminal,
 };
 use std::io::{stdout, Write};
 
-fn generate_ruler(width: u16) -> Line<'static> {
-    let mut spans = Vec::with_capacity(width as usize);
-    spans.push(Span::styled("|", Style::default().fg(Color::DarkGray)));
-    for i in 1..width {
-        let char = match i % 10 {
-            0 => ((i / 10) % 10).to_string(),
-            5 => "+".to_string(),
-            _ => ".".to_string(),
-        };
-        let style = match i % 10 {
-            0 => Style::default().fg(Color::White),
-            5 => Style::default().fg(Color::Gray),
-            _ => Style::default().fg(Color::DarkGray),
-        };
-        spans.push(Span::styled(char, style));
+/// One entry per open buffer, the active one reversed; `*` marks unsaved
+/// changes.
+fn tab_bar(editor: &Editor) -> Line<'static> {
+    let spans = editor
+        .tab_titles()
+        .into_iter()
+        .enumerate()
+        .map(|(i, (name, modified))| {
+            let label = format!(" {}{} ", name, if modified { "*" } else { "" });
+            if i == editor.active_tab {
+                Span::styled(label, Style::default().add_modifier(Modifier::REVERSED))
+            } else {
+                Span::styled(label, Style::default().fg(Color::DarkGray))
+            }
+        })
+        .collect::<Vec<_>>();
+    Line::from(spans)
+}
+
+/// Splits a rendered line into the screen rows soft wrap gives it, each
+/// continuation row led by `marker`.
+fn wrap_line<'a>(line: Line<'a>, starts: &[usize], marker: &Option<Span<'a>>) -> Vec<Line<'a>> {
+    let mut rows: Vec<Vec<Span<'a>>> = vec![Vec::new()];
+    let mut column = 0;
+    let mut next = 1;
+    for span in line.spans {
+        let mut text = String::new();
+        for ch in span.content.chars() {
+            if next < starts.len() && column >= starts[next] {
+                if !text.is_empty() {
+                    rows.last_mut().unwrap().push(Span::styled(std::mem::take(&mut text), span.style));
+                }
+                rows.push(marker.iter().cloned().collect());
+                next += 1;
+            }
+            text.push(ch);
+            column += ch.width().unwrap_or(0);
+        }
+        if !text.is_empty() {
+            rows.last_mut().unwrap().push(Span::styled(text, span.style));
+        }
+    }
+    rows.into_iter().map(Line::from).collect()
+}
+
+/// Ruler marks for `width` columns starting at buffer column
+/// `first_column` (0-based), after `indent` blank columns that cover the
+/// gutter. Marks count columns from 1 like the status bar: every tenth
+/// column ends with its number, every fifth has a `+`.
+fn generate_ruler(indent: u16, first_column: usize, width: u16) -> Line<'static> {
+    let columns = (first_column + 1..).take(width as usize);
+    let mut marks: Vec<(char, Style)> = columns
+        .clone()
+        .map(|column| match column % 10 {
+            _ if column == 1 => ('|', Style::default().fg(Color::DarkGray)),
+            5 => ('+', Style::default().fg(Color::Gray)),
+            _ => ('.', Style::default().fg(Color::DarkGray)),
+        })
+        .collect();
+    for (end, column) in columns.enumerate().filter(|(_, column)| column % 10 == 0) {
+        let label = column.to_string();
+        for (k, digit) in label.chars().rev().enumerate() {
+            if let Some(at) = end.checked_sub(k) {
+                marks[at] = (digit, Style::default().fg(Color::White));
+            }
+        }
     }
+    let mut spans = Vec::with_capacity(1 + marks.len());
+    spans.push(Span::raw(" ".repeat(indent as usize)));
+    spans.extend(marks.into_iter().map(|(mark, style)| Span::styled(mark.to_string(), style)));
     Line::from(spans)
 }
 
-fn apply_block_selection(line: Line, min_x: usize, max_x: usize) -> Line {
+fn apply_block_selection(line: Line, min_x: usize, max_x: usize, highlight: Style) -> Line {
     let mut new_spans = Vec::new();
     let mut current_col = 0;
     for span in line.spans {
@@ -61,9 +116,7 @@ fn apply_block_selection(line: Line, min_x: usize, max_x: usize) -> Line {
             if ch_end <= min_x || ch_start >= max_x {
                 new_spans.push(Span::styled(ch_text.to_string(), span.style));
             } else {
-                let mut style = span.style;
-                style = style.bg(Color::Green).fg(Color::White);
-                new_spans.push(Span::styled(ch_text.to_string(), style));
+                new_spans.push(Span::styled(ch_text.to_string(), span.style.patch(highlight)));
             }
         }
         current_col += span_col;
@@ -77,20 +130,281 @@ fn apply_block_selection(line: Line, min_x: usize, max_x: usize) -> Line {
         }
         let virtual_len = max_x - current_col;
         if virtual_len > 0 {
-            new_spans.push(Span::styled(" ".repeat(virtual_len), Style::default().bg(Color::Green).fg(Color::White)));
+            new_spans.push(Span::styled(" ".repeat(virtual_len), highlight));
         }
     }
 
     Line::from(new_spans)
 }
 
-fn render_diff_line<'a>(diff_line: DiffLine, syntax_engine: &'a SyntaxEngine, syntax_name: &'a str) -> Line<'a> {
+/// Draws the whitespace of `text`, already rendered as `line`, for `list`:
+/// tabs as `→`, trailing spaces as `·` and the line end as `¶`, all dimmed.
+fn show_whitespace<'a>(line: Line<'a>, text: &str) -> Line<'a> {
+    let glyph_style = Style::default().fg(Color::DarkGray);
+    let trailing_start = text.trim_end_matches([' ', '\t']).chars().count();
+    let mut new_spans = Vec::new();
+    let mut index = 0;
+    for span in line.spans {
+        let mut run = String::new();
+        for ch in span.content.chars() {
+            let glyph = match ch {
+                '\t' => Some('→'),
+                ' ' if index >= trailing_start => Some('·'),
+                _ => None,
+            };
+            index += 1;
+            match glyph {
+                Some(glyph) => {
+                    if !run.is_empty() {
+                        new_spans.push(Span::styled(std::mem::take(&mut run), span.style));
+                    }
+                    new_spans.push(Span::styled(glyph.to_string(), span.style.patch(glyph_style)));
+                }
+                None => run.push(ch),
+            }
+        }
+        if !run.is_empty() {
+            new_spans.push(Span::styled(run, span.style));
+        }
+    }
+    new_spans.push(Span::styled("¶", glyph_style));
+    Line::from(new_spans)
+}
+
+/// The background colors the view draws with: those set in `[colors]`,
+/// and the built-in ones for the rest.
+struct Palette {
+    selection_bg: Color,
+    block_bg: Color,
+    cursor_line_bg: Color,
+    color_column_bg: Color,
+    diff_context_bg: Color,
+    diff_added_bg: Color,
+    diff_removed_bg: Color,
+}
+
+impl Palette {
+    fn new(colors: Option<&ColorsConfig>) -> Self {
+        let pick = |color: Option<&String>, default: Color| color.and_then(|c| c.parse().ok()).unwrap_or(default);
+        Palette {
+            selection_bg: pick(colors.and_then(|c| c.selection_bg.as_ref()), Color::Blue),
+            block_bg: pick(colors.and_then(|c| c.block_bg.as_ref()), Color::Green),
+            cursor_line_bg: pick(colors.and_then(|c| c.cursor_line_bg.as_ref()), Color::Rgb(40, 40, 40)),
+            color_column_bg: pick(colors.and_then(|c| c.color_column_bg.as_ref()), Color::Rgb(48, 32, 32)),
+            diff_context_bg: pick(colors.and_then(|c| c.diff_context_bg.as_ref()), Color::Rgb(40, 40, 40)),
+            diff_added_bg: pick(colors.and_then(|c| c.diff_added_bg.as_ref()), Color::Rgb(0, 40, 0)),
+            diff_removed_bg: pick(colors.and_then(|c| c.diff_removed_bg.as_ref()), Color::Rgb(40, 0, 0)),
+        }
+    }
+}
+
+/// How many colors the terminal can show.
+#[derive(Clone, Copy, PartialEq)]
+enum ColorMode {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+}
+
+impl ColorMode {
+    /// `color_mode` from the config, or else a guess from `$COLORTERM` and
+    /// `$TERM`.
+    fn new(config: &EditorConfig) -> Self {
+        match config.color_mode.as_deref() {
+            Some("truecolor") => ColorMode::TrueColor,
+            Some("256") => ColorMode::Ansi256,
+            Some("16") => ColorMode::Ansi16,
+            _ => Self::detect(),
+        }
+    }
+
+    fn detect() -> Self {
+        if matches!(std::env::var("COLORTERM").as_deref(), Ok("truecolor") | Ok("24bit")) {
+            return ColorMode::TrueColor;
+        }
+        match std::env::var("TERM") {
+            Ok(term) if term.contains("256color") => ColorMode::Ansi256,
+            Ok(term) if !term.is_empty() => ColorMode::Ansi16,
+            _ => ColorMode::TrueColor,
+        }
+    }
+
+    /// The nearest color this mode can show.
+    fn adapt(self, color: Color) -> Color {
+        match (self, color) {
+            (ColorMode::Ansi256, Color::Rgb(r, g, b)) => Color::Indexed(nearest_256(r, g, b)),
+            (ColorMode::Ansi16, Color::Rgb(r, g, b)) => nearest_16(r, g, b),
+            (ColorMode::Ansi16, Color::Indexed(i)) if i >= 16 => {
+                let (r, g, b) = indexed_rgb(i);
+                nearest_16(r, g, b)
+            }
+            (ColorMode::Ansi16, Color::Indexed(i)) => ANSI_16[i as usize].0,
+            _ => color,
+        }
+    }
+}
+
+/// The 16 ANSI colors and the xterm values they usually show as.
+const ANSI_16: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (205, 0, 0)),
+    (Color::Green, (0, 205, 0)),
+    (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)),
+    (Color::Magenta, (205, 0, 205)),
+    (Color::Cyan, (0, 205, 205)),
+    (Color::Gray, (229, 229, 229)),
+    (Color::DarkGray, (127, 127, 127)),
+    (Color::LightRed, (255, 0, 0)),
+    (Color::LightGreen, (0, 255, 0)),
+    (Color::LightYellow, (255, 255, 0)),
+    (Color::LightBlue, (92, 92, 255)),
+    (Color::LightMagenta, (255, 0, 255)),
+    (Color::LightCyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+/// Channel values of the 6x6x6 cube in the 256-color palette.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+/// Goes by hue rather than plain distance, which would turn most pale
+/// theme colors into gray: washed-out colors become the nearest gray,
+/// the rest the ANSI color of their hue, light when bright.
+fn nearest_16(r: u8, g: u8, b: u8) -> Color {
+    let (max, min) = (r.max(g).max(b) as i32, r.min(g).min(b) as i32);
+    if (max - min) * 4 < max || max == 0 {
+        let average = (r as i32 + g as i32 + b as i32) / 3;
+        let grays = [(Color::Black, 0), (Color::DarkGray, 127), (Color::Gray, 229), (Color::White, 255)];
+        return grays.iter().min_by_key(|(_, level)| (level - average).abs()).unwrap().0;
+    }
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let hue = if max == r {
+        (60 * (g - b) / (max - min)).rem_euclid(360)
+    } else if max == g {
+        60 * (b - r) / (max - min) + 120
+    } else {
+        60 * (r - g) / (max - min) + 240
+    };
+    let hues = [
+        (Color::Red, Color::LightRed),
+        (Color::Yellow, Color::LightYellow),
+        (Color::Green, Color::LightGreen),
+        (Color::Cyan, Color::LightCyan),
+        (Color::Blue, Color::LightBlue),
+        (Color::Magenta, Color::LightMagenta),
+    ];
+    let (normal, light) = hues[((hue + 30) / 60 % 6) as usize];
+    if max >= 230 {
+        light
+    } else {
+        normal
+    }
+}
+
+/// The closest of the cube colors and grays (16-255) in the 256-color palette.
+fn nearest_256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |c: u8| CUBE_LEVELS.iter().enumerate().min_by_key(|(_, l)| (**l as i32 - c as i32).abs()).unwrap().0 as u8;
+    let cube = 16 + 36 * level(r) + 6 * level(g) + level(b);
+    let average = ((r as u32 + g as u32 + b as u32) / 3) as u8;
+    let gray = 232 + (average.saturating_sub(3) / 10).min(23);
+    if distance(indexed_rgb(gray), (r, g, b)) < distance(indexed_rgb(cube), (r, g, b)) {
+        gray
+    } else {
+        cube
+    }
+}
+
+/// The color an entry of the 256-color palette usually shows as.
+fn indexed_rgb(i: u8) -> (u8, u8, u8) {
+    match i {
+        0..=15 => ANSI_16[i as usize].1,
+        16..=231 => {
+            let i = i - 16;
+            (CUBE_LEVELS[(i / 36) as usize], CUBE_LEVELS[(i / 6 % 6) as usize], CUBE_LEVELS[(i % 6) as usize])
+        }
+        _ => {
+            let level = 8 + 10 * (i - 232);
+            (level, level, level)
+        }
+    }
+}
+
+/// Drawn over everything else, swaps each color on screen for the nearest
+/// one the terminal can show.
+struct Downsample(ColorMode);
+
+impl Widget for Downsample {
+    fn render(self, _area: Rect, buf: &mut Buffer) {
+        for cell in buf.content.iter_mut() {
+            cell.fg = self.0.adapt(cell.fg);
+            cell.bg = self.0.adapt(cell.bg);
+        }
+    }
+}
+
+/// Shades the cursor line across `width` columns. Anything that already
+/// has a background (a selection, a bracket match) keeps it.
+fn highlight_cursor_line(line: Line, width: usize, shade: Color) -> Line {
+    let mut new_spans: Vec<Span> = line
+        .spans
+        .into_iter()
+        .map(|mut span| {
+            if span.style.bg.is_none() {
+                span.style = span.style.bg(shade);
+            }
+            span
+        })
+        .collect();
+    let line_width: usize = new_spans.iter().map(|span| span.width()).sum();
+    if line_width < width {
+        new_spans.push(Span::styled(" ".repeat(width - line_width), Style::default().bg(shade)));
+    }
+    Line::from(new_spans)
+}
+
+/// Shades display column `column` (0-based) of the line, padding short lines
+/// out to it. Cells that already have a background keep it.
+fn highlight_column(line: Line, column: usize, shade: Color) -> Line {
+    let mut new_spans = Vec::new();
+    let mut current_col = 0;
+    for span in line.spans {
+        let span_end = current_col + span.width();
+        if current_col > column || span_end <= column || span.style.bg.is_some() {
+            current_col = span_end;
+            new_spans.push(span);
+            continue;
+        }
+        for ch in span.content.chars() {
+            let style = if current_col <= column && column < current_col + ch.width().unwrap_or(1) {
+                span.style.bg(shade)
+            } else {
+                span.style
+            };
+            new_spans.push(Span::styled(ch.to_string(), style));
+            current_col += ch.width().unwrap_or(1);
+        }
+    }
+    if current_col <= column {
+        if current_col < column {
+            new_spans.push(Span::raw(" ".repeat(column - current_col)));
+        }
+        new_spans.push(Span::styled(" ", Style::default().bg(shade)));
+    }
+    Line::from(new_spans)
+}
+
+fn render_diff_line<'a>(diff_line: DiffLine, syntax_engine: &'a SyntaxEngine, syntax_name: &'a str, palette: &Palette) -> Line<'a> {
     match diff_line {
         DiffLine::Context(content) => {
             let highlighted = syntax_engine.highlight_line(&content, syntax_name);
             // Subtle gray background for context
             let new_spans: Vec<Span> = highlighted.spans.into_iter().map(|mut span| {
-                span.style = span.style.bg(Color::Rgb(40, 40, 40));
+                span.style = span.style.bg(palette.diff_context_bg);
                 span
             }).collect();
             Line::from(new_spans)
@@ -99,7 +413,7 @@ fn render_diff_line<'a>(diff_line: DiffLine, syntax_engine: &'a SyntaxEngine, sy
             let highlighted = syntax_engine.highlight_line(&content, syntax_name);
             // Green background for added lines
             let new_spans: Vec<Span> = highlighted.spans.into_iter().map(|mut span| {
-                span.style = span.style.bg(Color::Rgb(0, 40, 0)).fg(Color::Rgb(150, 255, 150));
+                span.style = span.style.bg(palette.diff_added_bg).fg(Color::Rgb(150, 255, 150));
                 span
             }).collect();
             Line::from(new_spans)
@@ -108,7 +422,7 @@ fn render_diff_line<'a>(diff_line: DiffLine, syntax_engine: &'a SyntaxEngine, sy
             let highlighted = syntax_engine.highlight_line(&content, syntax_name);
             // Red background for removed lines
             let new_spans: Vec<Span> = highlighted.spans.into_iter().map(|mut span| {
-                span.style = span.style.bg(Color::Rgb(40, 0, 0)).fg(Color::Rgb(255, 150, 150));
+                span.style = span.style.bg(palette.diff_removed_bg).fg(Color::Rgb(255, 150, 150));
                 span
             }).collect();
             Line::from(new_spans)
@@ -118,8 +432,9 @@ fn render_diff_line<'a>(diff_line: DiffLine, syntax_engine: &'a SyntaxEngine, sy
 
 fn render_diff_status(editor: &Editor) -> Line<'static> {
     match &editor.diff_mode {
-        DiffMode::Active { current_hunk, .. } => {
+        DiffMode::Active { hunks, current_hunk, .. } => {
             let (total_hunks, added, removed) = editor.get_diff_stats();
+            let (accepted, rejected, pending) = editor.hunk_review_counts();
             let hunk_num = current_hunk + 1;
             
             let status = if editor.all_hunks_accepted() {
@@ -129,95 +444,552 @@ fn render_diff_status(editor: &Editor) -> Line<'static> {
                 )
             } else {
                 format!(
-                    "Hunk {}/{} (+{} -{})   [a]ccept  [r]eject  [n]ext  [p]rev  [A]ccept all  [R]eject all  [q]uit",
-                    hunk_num, total_hunks, added, removed
+                    "Hunk {}/{} (+{} -{}) {} accepted, {} rejected, {} pending   [a]ccept  [r]eject  [+]adds only  [-]dels only  [n]ext  [p]rev  [g/G]first/last  [u]nreviewed  [j/k]line  [space]toggle line  [A]ccept all  [R]eject all  [q]uit",
+                    hunk_num, total_hunks, added, removed, accepted, rejected, pending
                 )
             };
             
-            Line::from(vec![
-                Span::styled(
-                    status,
-                    Style::default()
-                        .fg(Color::Rgb(200, 200, 200))
-                        .bg(Color::Rgb(30, 30, 30))
-                )
-            ])
+            // One tick per hunk: accepted, rejected or still pending
+            let mut spans: Vec<Span> = hunks
+                .iter()
+                .enumerate()
+                .map(|(i, hunk)| {
+                    let color = match (hunk.reviewed, hunk.accepted, hunk.part) {
+                        (_, true, HunkPart::All) if hunk.excluded.is_empty() => Color::Green,
+                        (_, true, _) => Color::Yellow,
+                        (true, false, _) => Color::Red,
+                        (false, false, _) => Color::Gray,
+                    };
+                    let mut style = Style::default().fg(color).bg(Color::Rgb(30, 30, 30));
+                    if i == *current_hunk {
+                        style = style.add_modifier(Modifier::REVERSED);
+                    }
+                    Span::styled("■", style)
+                })
+                .collect();
+            spans.push(Span::styled(
+                format!(" {}", status),
+                Style::default()
+                    .fg(Color::Rgb(200, 200, 200))
+                    .bg(Color::Rgb(30, 30, 30))
+            ));
+            Line::from(spans)
         }
         _ => Line::from(vec![]),
     }
 }
 
-fn save_file(editor: &mut Editor, filename: &Option<String>) -> Result<(), Box<dyn std::error::Error>> {
-    if let Some(path) = filename {
-        let content = editor.buffer.join("\n");
-        std::fs::write(path, &content)?;
-        editor.save_state(); // Save state for undo tracking
-        editor.mark_as_saved(); // Mark as saved to clear modified flag
-        Ok(())
+/// `exporthtml <path>`: writes the selection, or the whole buffer, as
+/// highlighted HTML. Handled here rather than in `commands` because it
+/// needs the syntax engine.
+fn export_html(editor: &Editor, syntax_engine: &SyntaxEngine, path: &str) -> CommandOutcome {
+    if path.is_empty() {
+        return CommandOutcome::Message("Usage: exporthtml <path>".to_string());
+    }
+    let text = match editor.selection_text() {
+        Some((_, text)) => text,
+        None => editor.buffer.join("\n"),
+    };
+    let title = editor.filename.as_deref().unwrap_or("untitled");
+    match syntax_engine.highlighted_html(&text, &editor.syntax_name, title) {
+        Ok(html) => match std::fs::write(path, html) {
+            Ok(()) => CommandOutcome::Message(format!("Exported {} lines to {}.", text.lines().count(), path)),
+            Err(e) => CommandOutcome::Message(format!("Could not write {}: {}", path, e)),
+        },
+        Err(e) => CommandOutcome::Message(format!("Export failed: {}", e)),
+    }
+}
+
+/// Draws the unfocused half of a split view into `area`: highlighted text
+/// and line numbers, but no cursor or selection.
+fn draw_split_pane<B: Backend>(f: &mut Frame<B>, editor: &mut Editor, syntax_engine: &SyntaxEngine, area: Rect) {
+    // Taken out while drawing so the rest of the editor stays borrowable
+    let Some(mut pane) = editor.split.take() else { return };
+    let (buffer, syntax_name, filename) = match editor.tabs.get(pane.tab) {
+        Some(Some(doc)) => (&doc.buffer, &doc.syntax_name, &doc.filename),
+        _ => (&editor.buffer, &editor.syntax_name, &editor.filename),
+    };
+    let (numbers_area, text_area) = if editor.show_gutter() {
+        let lnum_width = (buffer.len() as f64).log10() as u16 + 2;
+        let chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Length(lnum_width), Constraint::Min(0)])
+            .split(area);
+        (Some(chunks[0]), chunks[1])
     } else {
-        Err("No filename specified".into())
+        (None, area)
+    };
+    // The buffer may have shrunk since this pane last had the focus
+    pane.scroll_y = pane.scroll_y.min(buffer.len() - 1);
+    let height = text_area.height.saturating_sub(2) as usize;
+    let rows: Vec<usize> = (pane.scroll_y..buffer.len()).take(height).collect();
+    let lines = syntax_engine.highlight_rows(&mut pane.highlight_cache, buffer, syntax_name, &rows);
+    let title = filename.as_deref().unwrap_or("[New File]").to_string();
+    let paragraph = Paragraph::new(lines)
+        .block(
+            Block::default()
+                .title(title)
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::DarkGray)),
+        )
+        .style(Style::default().fg(Color::White))
+        .scroll((0, pane.scroll_x as u16));
+    if let Some(numbers_area) = numbers_area {
+        let width = numbers_area.width as usize - 1;
+        let mut number_lines: Vec<Line> = vec![Line::from(vec![])]; // Empty line for border alignment
+        number_lines.extend(rows.iter().map(|&i| {
+            Line::from(format!("{:>width$} ", editor.line_number_label(i, pane.cursor_y)))
+        }));
+        f.render_widget(
+            Paragraph::new(number_lines).style(Style::default().fg(Color::Gray)),
+            numbers_area,
+        );
     }
+    f.render_widget(ClearWidget, text_area);
+    f.render_widget(paragraph, text_area);
+    editor.split = Some(pane);
 }
 
-fn load_prompt_file(prompt_name: &str) -> Result<(String, String), Box<dyn std::error::Error>> {
-    let prompt_path = format!("prompts/{}.prompt", prompt_name);
-    let content = fs::read_to_string(&prompt_path)?;
-    
-    // Parse the prompt file to extract system and user sections
-    let mut system_prompt = String::new();
-    let mut user_prompt = String::new();
-    let mut current_section = String::new();
-    
-    for line in content.lines() {
-        if line.trim() == "[system]" {
-            current_section = "system".to_string();
-            continue;
-        } else if line.trim() == "[user]" {
-            current_section = "user".to_string();
-            continue;
+/// Turns the result of a command line entry into prompt/quit state.
+fn apply_outcome(editor: &mut Editor, outcome: CommandOutcome) {
+    match outcome {
+        CommandOutcome::Continue => {}
+        CommandOutcome::Message(msg) => {
+            editor.show_message(msg);
+        }
+        CommandOutcome::Confirm(msg, action) => {
+            editor.prompt = Some((msg, PromptType::Confirm, Some(action)));
         }
-        
-        match current_section.as_str() {
-            "system" => {
-                if !system_prompt.is_empty() {
-                    system_prompt.push('\n');
+        CommandOutcome::Quit => editor.quit = true,
+    }
+}
+
+/// Handles one key press. Returns false when the editor should close.
+// Key handlers keep their `if` inside the arm so that an unmet condition
+// swallows the key instead of falling through to the typing arm.
+#[allow(clippy::collapsible_match)]
+fn handle_key(editor: &mut Editor, config: &mut EditorConfig, syntax_engine: &mut SyntaxEngine, key: KeyEvent) -> bool {
+    // Handle diff mode keybindings
+    if let DiffMode::Active { .. } = &editor.diff_mode {
+        match key.code {
+            KeyCode::Char('a') => { editor.accept_current_hunk(); editor.next_hunk(); }
+            KeyCode::Char('A') => { editor.accept_all_hunks(); }
+            KeyCode::Char('r') => { editor.reject_current_hunk(); editor.next_hunk(); }
+            KeyCode::Char('R') => { editor.reject_all_hunks(); }
+            KeyCode::Char('+') => { editor.accept_current_hunk_part(HunkPart::AdditionsOnly); editor.next_hunk(); }
+            KeyCode::Char('-') => { editor.accept_current_hunk_part(HunkPart::DeletionsOnly); editor.next_hunk(); }
+            KeyCode::Char('n') | KeyCode::Char('N') => {
+                if !editor.next_hunk() {
+                    if editor.diff_wrap {
+                        editor.show_hunk(0);
+                        editor.show_message("Wrapped to the first hunk.".to_string());
+                    } else {
+                        editor.show_message("No more hunks. Press 'q' to apply changes or 'q' again to cancel.".to_string());
+                    }
                 }
-                system_prompt.push_str(line);
             }
-            "user" => {
-                if !user_prompt.is_empty() {
-                    user_prompt.push('\n');
+            KeyCode::Char('p') | KeyCode::Char('P') => {
+                if !editor.prev_hunk() && editor.diff_wrap {
+                    editor.show_hunk(editor.get_hunks().len().saturating_sub(1));
+                    editor.show_message("Wrapped to the last hunk.".to_string());
+                }
+            }
+            KeyCode::Down | KeyCode::Char('j') => { editor.move_hunk_line(1); }
+            KeyCode::Up | KeyCode::Char('k') => { editor.move_hunk_line(-1); }
+            KeyCode::Char(' ') => {
+                if !editor.toggle_hunk_line() {
+                    editor.show_message("Not a changed line.".to_string());
+                }
+            }
+            KeyCode::Char('g') => { editor.show_hunk(0); }
+            KeyCode::Char('G') => { editor.show_hunk(editor.get_hunks().len().saturating_sub(1)); }
+            KeyCode::Char('u') => match editor.first_pending_hunk() {
+                Some(index) => editor.show_hunk(index),
+                None => editor.show_message("Every hunk has been reviewed.".to_string()),
+            },
+            KeyCode::Char('q') => {
+                if editor.apply_diff_changes() {
+                    editor.show_message("Changes applied successfully.".to_string());
+                } else {
+                    editor.cancel_diff_mode();
+                    editor.show_message("Changes cancelled.".to_string());
+                }
+            }
+            _ => {} // Ignore other keys in diff mode
+        }
+        return true; // Skip focus-based handling when in diff mode
+    } else if let Some((_, prompt_type, action)) = &editor.prompt.clone() {
+
+        match prompt_type {
+            PromptType::Confirm => {
+                match key.code {
+                    KeyCode::Char('y') => {
+                        match action {
+                            Some(PromptAction::Save) => {
+                                editor.prompt = None;
+                                let outcome = commands::save(editor);
+                                apply_outcome(editor, outcome);
+                            }
+                            Some(PromptAction::Quit) => {
+                                return false;
+                            }
+                            Some(PromptAction::SaveAndQuit) => {
+                                match commands::save_all(editor) {
+                                    Ok(warnings) if warnings.is_empty() => return false,
+                                    // Stay open so a failed backup is not missed
+                                    Ok(warnings) => {
+                                        editor.show_message(format!("All saved. {}", warnings.join(" ")));
+                                    }
+                                    Err(e) => {
+                                        editor.show_message(format!("Save failed: {}", e));
+                                    }
+                                }
+                            }
+                            Some(PromptAction::Extract { path, copy }) => {
+                                editor.prompt = None;
+                                let outcome = commands::extract_selection(editor, config, path, *copy);
+                                apply_outcome(editor, outcome);
+                            }
+                            Some(PromptAction::WriteTo { path }) => {
+                                editor.prompt = None;
+                                let outcome = commands::write_selection(editor, path);
+                                apply_outcome(editor, outcome);
+                            }
+                            Some(PromptAction::Recover) => {
+                                editor.prompt = None;
+                                let outcome = commands::recover_swap(editor);
+                                apply_outcome(editor, outcome);
+                            }
+                            Some(PromptAction::CloseTab) => {
+                                editor.prompt = None;
+                                let outcome = commands::close_tab(editor, config);
+                                apply_outcome(editor, outcome);
+                            }
+                            None => {}
+                        }
+                    }
+                    KeyCode::Char('n') => {
+                        editor.prompt = None;
+                        editor.command_buffer.clear();
+                        editor.command_cursor = 0;
+                    }
+                    _ => {}
+                }
+            }
+            PromptType::Message(_) => {
+                editor.prompt = None;
+                editor.command_buffer.clear();
+                editor.command_cursor = 0;
+            }
+            PromptType::Fill => {
+                // Ignore other keys in fill mode
+                if let KeyCode::Char(c) = key.code {
+                    editor.fill_selection(c);
+                    editor.prompt = None;
+                    editor.command_buffer.clear();
+                    editor.command_cursor = 0;
+                }
+            }
+        }
+    } else {
+        match editor.focus {
+            Focus::Editor => {
+                if key.modifiers.contains(KeyModifiers::CONTROL) {
+                    match key.code {
+                        KeyCode::Up => editor.move_cursor(0, -1),
+                        KeyCode::Down => editor.move_cursor(0, 1),
+                        KeyCode::Left => editor.move_cursor(-1, 0),
+                        KeyCode::Right => editor.move_cursor(1, 0),
+                        KeyCode::Char('l') => editor.select_line(),
+                        KeyCode::Char('b') => editor.select_block(),
+                        KeyCode::Char('f') => {
+                            if editor.selection_start.is_some() {
+                                editor.prompt = Some(("Enter character to fill selection:".to_string(), PromptType::Fill, None));
+                            }
+                        }
+                        KeyCode::Char('u') => {
+                            editor.selection_start = None;
+                            editor.selection_end = None;
+                        }
+                        KeyCode::Char('o') => editor.open_line_below(),
+                        KeyCode::Char('c') => {
+                            editor.copy_selection();
+                        }
+                        KeyCode::Char('x') => {
+                            editor.cut_selection();
+                        }
+                        KeyCode::Char('v') => {
+                            editor.paste();
+                        }
+                        KeyCode::Char('w') => {
+                            let outcome = commands::switch_pane(editor, config);
+                            apply_outcome(editor, outcome);
+                        }
+                        // Terminals report Ctrl+] as Ctrl+5
+                        KeyCode::Char(']') | KeyCode::Char('5') => {
+                            editor.jump_to_matching_bracket();
+                        }
+                        KeyCode::Char(c) => editor.type_char(c),
+                        KeyCode::Tab | KeyCode::PageDown => {
+                            let outcome = commands::cycle_tab(editor, config, 1);
+                            apply_outcome(editor, outcome);
+                        }
+                        KeyCode::BackTab | KeyCode::PageUp => {
+                            let outcome = commands::cycle_tab(editor, config, -1);
+                            apply_outcome(editor, outcome);
+                        }
+                        KeyCode::Enter => editor.insert_newline(),
+                        KeyCode::Delete => editor.delete_char(),
+                        KeyCode::Insert => editor.toggle_overwrite(),
+                        KeyCode::Backspace => editor.backspace(),
+                        _ => {} // Ignore other keys in editor mode
+                    }
+                } else if key.modifiers.contains(KeyModifiers::ALT) {
+                    match key.code {
+                        KeyCode::Char('t') => {
+                            editor.transpose_words();
+                        }
+                        KeyCode::Char('l') => {
+                            editor.align_view(ViewAlign::Center);
+                        }
+                        KeyCode::Char('g') => {
+                            let message = editor.accept_word(None);
+                            editor.show_message(message);
+                        }
+                        KeyCode::Down => {
+                            editor.add_cursor(1);
+                        }
+                        KeyCode::Up => {
+                            editor.add_cursor(-1);
+                        }
+                        _ => {}
+                    }
+                } else if key.modifiers.contains(KeyModifiers::SHIFT) {
+                    match key.code {
+                        KeyCode::F(7) => {
+                            if editor.selection_start.is_some() {
+                                editor.move_block_left();
+                            }
+                        }
+                        KeyCode::F(8) => {
+                            if editor.selection_start.is_some() {
+                                editor.move_block_right();
+                            }
+                        }
+                        KeyCode::Char(c) => editor.type_char(c),
+                        _ => {}
+                    }
+                } else {
+                    match key.code {
+                        KeyCode::Up => editor.move_cursor(0, -1),
+                        KeyCode::Down => editor.move_cursor(0, 1),
+                        KeyCode::Left => editor.move_cursor(-1, 0),
+                        KeyCode::Right => editor.move_cursor(1, 0),
+                        KeyCode::Char(c) => editor.type_char(c),
+                        KeyCode::Tab => {
+                            let spaces = editor.tab_width - (editor.cursor_x % editor.tab_width);
+                            for _ in 0..spaces {
+                                editor.type_char(' ');
+                            }
+                        }
+                        KeyCode::Enter => {
+                            if !commands::follow_view_target(editor) {
+                                editor.insert_newline();
+                            }
+                        }
+                        KeyCode::Delete => editor.delete_char(),
+                        KeyCode::Insert => editor.toggle_overwrite(),
+                        KeyCode::Backspace => editor.backspace(),
+                        KeyCode::Home => editor.focus = Focus::CommandLine,
+                        KeyCode::Esc => editor.extra_cursors.clear(),
+                        KeyCode::PageUp => editor.page_up(),
+                        KeyCode::PageDown => editor.page_down(),
+                        KeyCode::F(1) => {
+                            if editor.replace_text.is_some() && !editor.read_only {
+                                editor.replace_next();
+                                let message = if editor.replace_text.is_some() { "Replaced; moved to next match." } else { "Replaced the last match." };
+                                editor.show_message(message.to_string());
+                            } else if editor.find_next() {
+                                editor.show_message("Moved to next match.".to_string());
+                            } else {
+                                editor.show_message("No more matches or no search active.".to_string());
+                            }
+                        }
+                        _ => {} // Ignore other keys in editor mode
+                    }
+                }
+            }
+            Focus::CommandLine => {
+                if key.code != KeyCode::Tab {
+                    editor.command_completion = None;
+                }
+                let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+                if editor.history_search.is_some() {
+                    match key.code {
+                        KeyCode::Char('r') if ctrl => editor.history_search_older(),
+                        KeyCode::Char(c) if !ctrl => editor.history_search_edit(Some(c)),
+                        KeyCode::Backspace => editor.history_search_edit(None),
+                        KeyCode::Esc => editor.history_search_cancel(),
+                        _ => editor.history_search_accept(),
+                    }
+                    // Enter only takes the match; other keys go on to act on it
+                    if editor.history_search.is_some() || matches!(key.code, KeyCode::Esc | KeyCode::Enter) {
+                        return true;
+                    }
+                }
+                if ctrl && key.code == KeyCode::Char('r') {
+                    editor.history_search_older();
+                    return true;
+                }
+                match key.code {
+                    KeyCode::Tab => {
+                        let outcome = commands::complete_command(editor);
+                        apply_outcome(editor, outcome);
+                    }
+                    KeyCode::Char(c) => {
+                        editor.command_insert_char(c);
+                    }
+                    KeyCode::Backspace => {
+                        editor.command_backspace();
+                    }
+                    KeyCode::Left => {
+                        editor.command_move_left();
+                    }
+                    KeyCode::Right => {
+                        editor.command_move_right();
+                    }
+                    KeyCode::Delete => {
+                        editor.command_delete();
+                    }
+                    KeyCode::Insert => {
+                        editor.toggle_overwrite();
+                    }
+                    KeyCode::Up => {
+                        editor.history_up();
+                    }
+                    KeyCode::Down => {
+                        editor.history_down();
+                    }
+                    KeyCode::F(1) => {
+                        if editor.replace_text.is_some() && !editor.read_only {
+                            editor.replace_next();
+                            let message = if editor.replace_text.is_some() { "Replaced; moved to next match." } else { "Replaced the last match." };
+                            editor.show_message(message.to_string());
+                        } else if editor.find_next() {
+                            editor.show_message("Moved to next match.".to_string());
+                        } else {
+                            editor.show_message("No more matches or no search/replace active.".to_string());
+                        }
+                    }
+                    KeyCode::Home => editor.focus = Focus::Editor,
+                    KeyCode::Enter => {
+                        let cmd = editor.command_buffer.trim().to_string();
+                        if !cmd.is_empty() {
+                            editor.add_to_history(cmd.clone());
+                            let theme = config.theme.clone();
+                            let outcome = match cmd.strip_prefix("exporthtml") {
+                                Some(path) if path.is_empty() || path.starts_with(' ') => {
+                                    export_html(editor, syntax_engine, path.trim())
+                                }
+                                _ => commands::execute_command(editor, config, &cmd),
+                            };
+                            if config.theme != theme {
+                                *syntax_engine = SyntaxEngine::new(&config.theme);
+                                editor.highlight_cache.clear();
+                            }
+                            apply_outcome(editor, outcome);
+                        }
+                        editor.command_buffer.clear();
+                        editor.command_cursor = 0;
+                    }
+                    _ => {} // Ignore other keys in command line mode
                 }
-                user_prompt.push_str(line);
             }
-            _ => {}
         }
     }
-    
-    if system_prompt.is_empty() {
-        return Err("No [system] section found in prompt file".into());
+    true
+}
+
+/// What leads the command line: the prompt marker, or the query while
+/// Ctrl+R searches the history.
+fn command_line_marker(editor: &Editor) -> String {
+    match &editor.history_search {
+        Some((query, Some(_))) => format!("search '{}':", query),
+        Some((query, None)) => format!("failed search '{}':", query),
+        None => ">".to_string(),
+    }
+}
+
+/// Feeds the last macro through `handle_key` `count` times, as one undo
+/// step. Stops early once a cursor key no longer moves the cursor, so a
+/// large count runs to the end of the buffer. Returns false when a replayed
+/// key closed the editor.
+fn replay_macro(editor: &mut Editor, config: &mut EditorConfig, syntax_engine: &mut SyntaxEngine, count: usize) -> bool {
+    let keys = editor.last_macro.clone();
+    let tab = editor.active_tab;
+    let start = editor.begin_undo_group();
+    'replay: for _ in 0..count {
+        for &key in &keys {
+            let moves = editor.focus == Focus::Editor
+                && editor.prompt.is_none()
+                && matches!(
+                    key.code,
+                    KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right | KeyCode::PageUp | KeyCode::PageDown
+                );
+            let before = (editor.cursor_y, editor.cursor_x);
+            if !handle_key(editor, config, syntax_engine, key) {
+                return false;
+            }
+            if moves && (editor.cursor_y, editor.cursor_x) == before {
+                break 'replay;
+            }
+        }
+    }
+    // Undo history belongs to the tab it was recorded in
+    if editor.active_tab == tab {
+        editor.end_undo_group(start);
     }
-    
-    Ok((system_prompt, user_prompt))
+    // A replay inside the macro would never end
+    editor.macro_replay = None;
+    true
 }
 
 pub fn run_editor(
     buffer: String,
-    config: EditorConfig,
-    syntax_engine: SyntaxEngine,
+    mut config: EditorConfig,
+    mut syntax_engine: SyntaxEngine,
     syntax_name: String,
     filename: Option<String>,
+    start_line: Option<usize>,
+    recovery: Option<String>,
 ) {
     let mut editor = Editor::new(&buffer, &config);
-    editor.filename = filename.clone();
+    editor.disk_mtime = filename.as_deref().and_then(file_mtime);
+    editor.filename = filename;
+    editor.syntax_name = syntax_name;
+    editor.tab_width = config.tab_width_for(&editor.syntax_name);
+    if let Some(line) = start_line {
+        editor.cursor_y = line.saturating_sub(1).min(editor.buffer.len() - 1);
+        // The editor area is only measured when drawn; centre the line using
+        // the terminal height less the bars and borders
+        let rows = crossterm::terminal::size().map(|(_, rows)| rows as usize).unwrap_or(24);
+        editor.scroll_y = editor.cursor_y.saturating_sub(rows.saturating_sub(5) / 2);
+    }
+    if let Some(swap) = recovery {
+        apply_outcome(&mut editor, commands::recovery_prompt(&swap));
+    }
+    let swap_interval = config.swap_interval_ms.unwrap_or(swap::DEFAULT_INTERVAL_MS);
+    let mut last_swap = Instant::now();
     if let Err(e) = enable_raw_mode() {
         eprintln!("Failed to enable raw mode: {}", e);
         return;
     }
-    execute!(stdout(), Clear(ClearType::All), SetCursorStyle::SteadyBlock).unwrap();
+    execute!(stdout(), Clear(ClearType::All), SetCursorStyle::SteadyBlock, EnableMouseCapture).unwrap();
 
     let backend = CrosstermBackend::new(stdout());
     let mut terminal = Terminal::new(backend).unwrap();
+    // Set by a resize so the next frame re-scrolls to the new text area
+    let mut resized = false;
 
     loop {
         // Set cursor style based on overwrite mode and selection
@@ -229,28 +1001,52 @@ pub fn run_editor(
             SetCursorStyle::SteadyBar
         };
         execute!(stdout(), cursor_style).unwrap();
+        editor.sync_folds();
 
         // Draw the UI
+        let palette = Palette::new(config.colors.as_ref());
+        let color_mode = ColorMode::new(&config);
         terminal
             .draw(|f| {
+                // The tab bar only takes a row once there is more than one buffer
+                let area = if editor.tabs.len() > 1 {
+                    let rows = Layout::default()
+                        .direction(Direction::Vertical)
+                        .constraints([Constraint::Length(1), Constraint::Min(0)])
+                        .split(f.size());
+                    f.render_widget(Paragraph::new(tab_bar(&editor)), rows[0]);
+                    rows[1]
+                } else {
+                    f.size()
+                };
                 let chunks = Layout::default()
                     .direction(Direction::Vertical)
                     .constraints([
                         Constraint::Length(1), // Status Bar
                         Constraint::Length(1), // Command Line
-                        Constraint::Length(1), // Ruler
+                        Constraint::Length(if editor.show_ruler { 1 } else { 0 }), // Ruler
                         Constraint::Min(0),    // Editor
                     ])
-                    .split(f.size());
+                    .split(area);
 
-                let editor_chunk = chunks[3];
+                // With a split view the unfocused pane takes half the width
+                let (editor_chunk, pane_chunk) = match &editor.split {
+                    Some(pane) => {
+                        let halves = Layout::default()
+                            .direction(Direction::Horizontal)
+                            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+                            .split(chunks[3]);
+                        if pane.on_left { (halves[1], Some(halves[0])) } else { (halves[0], Some(halves[1])) }
+                    }
+                    None => (chunks[3], None),
+                };
                 let num_lines = editor.buffer.len();
-                let lnum_width = if editor.show_line_numbers && num_lines > 0 {
+                let lnum_width = if editor.show_gutter() && num_lines > 0 {
                     ((num_lines as f64).log10() as usize + 1) + 1 // +1 for space
                 } else {
                     0
                 };
-                let (numbers_chunk, text_chunk) = if editor.show_line_numbers {
+                let (numbers_chunk, text_chunk) = if editor.show_gutter() {
                     let chunks = Layout::default()
                         .direction(Direction::Horizontal)
                         .constraints([Constraint::Length(lnum_width as u16), Constraint::Min(0)])
@@ -261,6 +1057,11 @@ pub fn run_editor(
                 };
                 editor.editor_visible_height = text_chunk.height as usize - 2; // Subtract 2 for borders
                 editor.editor_visible_width = text_chunk.width as usize - 2; // Subtract 2 for borders
+                if resized {
+                    editor.scroll();
+                }
+                let rows = editor.visible_rows();
+                let screen_rows = editor.screen_rows();
 
                 // 1. Status Bar
                 let dir = std::env::current_dir()
@@ -271,13 +1072,31 @@ pub fn run_editor(
                     Style::default().fg(Color::White).bg(Color::Blue),
                 );
 
-                let file_display = filename.as_deref().unwrap_or("[New File]");
+                let file_display = editor.filename.as_deref().unwrap_or("[New File]");
                 let file_comp = Span::styled(
                     format!(" [File: {}] ", file_display),
                     Style::default().fg(Color::White).bg(Color::Rgb(0, 128, 128)), // Teal
                 );
+                let flags: Vec<&str> = [
+                    (editor.modified, "[+]"),
+                    (editor.read_only, "[RO]"),
+                    (editor.recording.is_some(), "[REC]"),
+                ]
+                .iter()
+                .filter(|(on, _)| *on)
+                .map(|(_, flag)| *flag)
+                .collect();
+                let state_text = if flags.is_empty() { String::new() } else { format!(" {} ", flags.join(" ")) };
+                let state_comp = Span::styled(
+                    state_text,
+                    Style::default().fg(Color::Black).bg(Color::Yellow).add_modifier(Modifier::BOLD),
+                );
+                let extra_cursors = match editor.extra_cursors.len() {
+                    0 => String::new(),
+                    count => format!(" +{}", count),
+                };
                 let cursor_comp = Span::styled(
-                    format!(" [L:{} C:{}] ", editor.cursor_y + 1, editor.cursor_x + 1),
+                    format!(" [L:{} C:{}{}] ", editor.cursor_y + 1, editor.cursor_x + 1, extra_cursors),
                     Style::default().fg(Color::White).bg(Color::Rgb(128, 0, 128)), // Purple
                 );
                   let size_comp = Span::styled(
@@ -317,14 +1136,22 @@ pub fn run_editor(
 
                     let ai_status_comp = match &editor.ai_status {
                         AiStatus::Idle => Span::raw(""),
-                        AiStatus::InProgress { start_time, spinner_state } => {
+                        AiStatus::InProgress { start_time, spinner_state, received } => {
                             let spinner = ['|', '/', '-', '\\'];
                             let spinner_char = spinner[*spinner_state % spinner.len()];
                             let elapsed = start_time.elapsed().as_secs();
-                            Span::styled(
-                                format!(" [{} AI Running... {}s] ", spinner_char, elapsed),
-                                Style::default().fg(Color::White).bg(Color::Cyan),
-                            )
+                            // Show the tail of a streaming response as it arrives
+                            let tail: String = {
+                                let flat = received.replace('\n', " ");
+                                let chars: Vec<char> = flat.chars().collect();
+                                chars[chars.len().saturating_sub(40)..].iter().collect()
+                            };
+                            let text = if tail.is_empty() {
+                                format!(" [{} AI Running... {}s] ", spinner_char, elapsed)
+                            } else {
+                                format!(" [{} AI {}s] {} ", spinner_char, elapsed, tail)
+                            };
+                            Span::styled(text, Style::default().fg(Color::White).bg(Color::Cyan))
                         }
                         AiStatus::Success { message, .. } => Span::styled(
                             format!(" [AI: {}] ", message),
@@ -336,19 +1163,44 @@ pub fn run_editor(
                         ),
                     };
 
+                   // Selection size, inclusive of the column under the far corner
+                   let selection_comp = match (editor.selection_start, editor.selection_end) {
+                       (Some(start), Some(end)) => {
+                           let lines = start.0.max(end.0) - start.0.min(end.0) + 1;
+                           // Shown in the colour the selection itself is drawn in
+                           let (text, bg) = match editor.selection_mode {
+                               SelectionMode::Block => (
+                                   format!(" [SEL {}x{}] ", lines, start.1.max(end.1) - start.1.min(end.1) + 1),
+                                   palette.block_bg,
+                               ),
+                               _ if lines == 1 => (" [SEL 1 line] ".to_string(), palette.selection_bg),
+                               _ => (format!(" [SEL {} lines] ", lines), palette.selection_bg),
+                           };
+                           Some(Span::styled(text, Style::default().fg(Color::White).bg(bg)))
+                       }
+                       _ => None,
+                   };
+
                    let mut status_items = vec![
                        dir_comp,
                        separator.clone(),
                        file_comp,
+                       state_comp,
                        separator.clone(),
                        cursor_comp,
+                   ];
+                   if let Some(selection_comp) = selection_comp {
+                       status_items.push(separator.clone());
+                       status_items.push(selection_comp);
+                   }
+                   status_items.extend([
                        separator.clone(),
                        size_comp,
                        separator.clone(),
                        width_comp,
                        separator.clone(),
                        model_comp,
-                   ];
+                   ]);
 
                    if !matches!(editor.ai_status, AiStatus::Idle) {
                         status_items.push(separator.clone());
@@ -368,7 +1220,7 @@ pub fn run_editor(
                 } else {
                     Line::from(vec![
                         Span::styled(
-                            ">",
+                            command_line_marker(&editor),
                             Style::default()
                                 .fg(Color::Green)
                                 .add_modifier(Modifier::BOLD),
@@ -380,15 +1232,18 @@ pub fn run_editor(
                 let command_line = Paragraph::new(command_line_content).block(Block::default());
                 f.render_widget(command_line, chunks[1]);
 
-                // 3. Ruler
-                let ruler_line = generate_ruler(chunks[2].width);
-                let ruler = Paragraph::new(ruler_line)
-                    .style(Style::default().bg(Color::DarkGray))
-                    .block(Block::default());
-                f.render_widget(ruler, chunks[2]);
+                // 3. Ruler, lined up with the text inside the border
+                if editor.show_ruler {
+                    let first_column = if editor.soft_wrap { 0 } else { editor.scroll_x };
+                    let ruler_line = generate_ruler(text_chunk.x + 1 - chunks[2].x, first_column, text_chunk.width.saturating_sub(2));
+                    let ruler = Paragraph::new(ruler_line)
+                        .style(Style::default().bg(Color::DarkGray))
+                        .block(Block::default());
+                    f.render_widget(ruler, chunks[2]);
+                }
 
 // 4. Editor View
-                let lines: Vec<Line> = if let DiffMode::Active { hunks, current_hunk, .. } = &editor.diff_mode {
+                let lines: Vec<Line> = if let DiffMode::Active { hunks, current_hunk, current_line, .. } = &editor.diff_mode {
                     // Show diff view
                     let mut diff_lines = Vec::new();
                     let current_hunk_obj = &hunks[*current_hunk];
@@ -401,14 +1256,24 @@ pub fn run_editor(
                     for i in start_context..current_hunk_obj.old_start {
                         if i < editor.buffer.len() {
                             let context_line = DiffLine::Context(editor.buffer[i].clone());
-                            let rendered = render_diff_line(context_line, &syntax_engine, &syntax_name);
+                            let mut rendered = render_diff_line(context_line, &syntax_engine, &editor.syntax_name, &palette);
+                            rendered.spans.insert(0, Span::raw("  "));
                             diff_lines.push(rendered);
                         }
                     }
                     
-                    // Show hunk itself
-                    for diff_line in &current_hunk_obj.lines {
-                        let rendered = render_diff_line(diff_line.clone(), &syntax_engine, &syntax_name);
+                    // Show hunk itself, the line cursor marked and changes
+                    // left out of an accepted hunk dimmed
+                    for (i, diff_line) in current_hunk_obj.lines.iter().enumerate() {
+                        let mut rendered = render_diff_line(diff_line.clone(), &syntax_engine, &editor.syntax_name, &palette);
+                        if current_hunk_obj.accepted && current_hunk_obj.excluded.contains(&i) {
+                            let left_out = Style::default().fg(Color::DarkGray).add_modifier(Modifier::CROSSED_OUT);
+                            for span in &mut rendered.spans {
+                                span.style = span.style.patch(left_out);
+                            }
+                        }
+                        let marker = if i == *current_line { "▶ " } else { "  " };
+                        rendered.spans.insert(0, Span::styled(marker, Style::default().fg(Color::Yellow)));
                         diff_lines.push(rendered);
                     }
                     
@@ -420,7 +1285,8 @@ pub fn run_editor(
                     for i in hunk_end..end_context {
                         if i < editor.buffer.len() {
                             let context_line = DiffLine::Context(editor.buffer[i].clone());
-                            let rendered = render_diff_line(context_line, &syntax_engine, &syntax_name);
+                            let mut rendered = render_diff_line(context_line, &syntax_engine, &editor.syntax_name, &palette);
+                            rendered.spans.insert(0, Span::raw("  "));
                             diff_lines.push(rendered);
                         }
                     }
@@ -428,14 +1294,28 @@ pub fn run_editor(
                     diff_lines
                 } else {
                     // Normal editor view
-                    editor
-                        .buffer
-                        .iter()
-                        .enumerate()
-                        .skip(editor.scroll_y)
-                        .take(editor.scroll_y + editor.editor_visible_height)
-                        .map(|(y, line)| {
-                            let mut highlighted = syntax_engine.highlight_line(line, &syntax_name);
+                    let bracket_pair = editor
+                        .matching_bracket()
+                        .map(|partner| [(editor.cursor_y, editor.cursor_x), partner]);
+                    let spell = editor.spell_enabled() && editor.spell_checker().is_ok();
+                    let highlighted_rows = syntax_engine.highlight_rows(
+                        &mut editor.highlight_cache,
+                        &editor.buffer,
+                        &editor.syntax_name,
+                        &rows,
+                    );
+                    rows.iter()
+                        .zip(highlighted_rows)
+                        .map(|(&y, mut highlighted)| {
+                            if editor.show_whitespace {
+                                highlighted = show_whitespace(highlighted, &editor.buffer[y]);
+                            }
+                            if spell {
+                                let misspelled = Style::default().fg(Color::LightRed).add_modifier(Modifier::UNDERLINED);
+                                for (start, end) in editor.misspelled(y) {
+                                    highlighted = apply_block_selection(highlighted, start, end, misspelled);
+                                }
+                            }
                             // Check if line is selected
                             if let (Some(start), Some(end)) = (editor.selection_start, editor.selection_end) {
                                 let min_y = start.0.min(end.0);
@@ -444,12 +1324,12 @@ pub fn run_editor(
                                 let max_x = start.1.max(end.1);
                                 if y >= min_y && y <= max_y {
                                     if editor.selection_mode == SelectionMode::Block {
-                                        highlighted = apply_block_selection(highlighted, min_x, max_x);
+                                        highlighted = apply_block_selection(highlighted, min_x, max_x, Style::default().bg(palette.block_bg).fg(Color::White));
                                     } else {
                                         // For line, highlight whole line
                                         let new_spans: Vec<Span> = highlighted.spans.into_iter().map(|span| {
                                             let mut style = span.style;
-                                            style = style.bg(Color::Blue).fg(Color::White);
+                                            style = style.bg(palette.selection_bg).fg(Color::White);
                                             Span { content: span.content, style }
                                         }).collect();
                                         let mut highlighted_line = Line::from(new_spans);
@@ -457,17 +1337,65 @@ pub fn run_editor(
                                         let current_width = highlighted_line.width();
                                         if current_width < max_x {
                                             let pad_len = max_x - current_width;
-                                            highlighted_line.spans.push(Span::styled(" ".repeat(pad_len), Style::default().bg(Color::Blue).fg(Color::White)));
+                                            highlighted_line.spans.push(Span::styled(" ".repeat(pad_len), Style::default().bg(palette.selection_bg).fg(Color::White)));
                                         }
                                         highlighted = highlighted_line;
                                     }
                                 }
                             }
+                            if let Some((open, close)) = editor.scope_braces {
+                                let brace_style = Style::default().bg(Color::Yellow).fg(Color::Black);
+                                for (brace_y, brace_x) in [open, close] {
+                                    if brace_y == y {
+                                        highlighted = apply_block_selection(highlighted, brace_x, brace_x + 1, brace_style);
+                                    }
+                                }
+                            }
+                            for &(bracket_y, bracket_x) in bracket_pair.iter().flatten() {
+                                if bracket_y == y {
+                                    let bracket_style = Style::default().bg(Color::DarkGray).add_modifier(Modifier::BOLD);
+                                    highlighted = apply_block_selection(highlighted, bracket_x, bracket_x + 1, bracket_style);
+                                }
+                            }
+                            for &(_, cursor_x) in editor.extra_cursors.iter().filter(|(cursor_y, _)| *cursor_y == y) {
+                                let caret = Style::default().add_modifier(Modifier::REVERSED);
+                                highlighted = apply_block_selection(highlighted, cursor_x, cursor_x + 1, caret);
+                            }
+                            if let Some(&(_, end)) = editor.folds.iter().find(|(start, _)| *start == y) {
+                                highlighted.spans.push(Span::styled(
+                                    format!(" ... {} lines folded", end - y),
+                                    Style::default().fg(Color::DarkGray),
+                                ));
+                            }
+                            if let Some(column) = editor.color_column.filter(|_| !editor.soft_wrap) {
+                                highlighted = highlight_column(highlighted, column - 1, palette.color_column_bg);
+                            }
+                            if editor.cursor_line && y == editor.cursor_y {
+                                // Far enough to fill the last row whatever the
+                                // scroll or wrap; the view clips the rest
+                                let width = editor.scroll_x.max(highlighted.width()) + editor.editor_visible_width;
+                                highlighted = highlight_cursor_line(highlighted, width, palette.cursor_line_bg);
+                            }
                             highlighted
                         })
                         .collect()
                 };
 
+                let lines = if editor.soft_wrap && matches!(editor.diff_mode, DiffMode::Inactive) {
+                    let marker = editor.show_wrap_marker.then(|| {
+                        let color = editor.wrap_marker_color.parse::<Color>().unwrap_or(Color::DarkGray);
+                        Span::styled(editor.wrap_marker.clone(), Style::default().fg(color))
+                    });
+                    let mut wrapped: Vec<Line> = rows
+                        .iter()
+                        .zip(lines)
+                        .flat_map(|(&y, line)| wrap_line(line, &editor.wrap_starts(y), &marker))
+                        .collect();
+                    wrapped.truncate(screen_rows.len());
+                    wrapped
+                } else {
+                    lines
+                };
                 let paragraph = Paragraph::new(lines)
                     .block(Block::default().title("vedit").borders(Borders::ALL))
                     .style(Style::default().fg(Color::White))
@@ -475,11 +1403,17 @@ pub fn run_editor(
                 if let Some(numbers_chunk) = numbers_chunk {
                     let mut number_lines: Vec<Line> = vec![Line::from(vec![])]; // Empty line for border alignment
                     number_lines.extend(
-                        (editor.scroll_y..(editor.scroll_y + editor.editor_visible_height).min(editor.buffer.len()))
-                            .map(|i| {
-                                let num = (i + 1).to_string();
+                        screen_rows.iter()
+                            .map(|&(i, row)| {
+                                // Soft-wrapped continuation rows get no number
+                                let num = if row == 0 { editor.line_number_label(i, editor.cursor_y) } else { String::new() };
                                 let padded = format!("{:>width$} ", num, width = lnum_width - 1);
-                                Line::from(vec![Span::styled(padded, Style::default().fg(Color::Gray))])
+                                // Mark the lines of the block found by `scope`
+                                let color = match editor.scope_braces {
+                                    Some(((start, _), (end, _))) if (start..=end).contains(&i) => Color::Yellow,
+                                    _ => Color::Gray,
+                                };
+                                Line::from(vec![Span::styled(padded, Style::default().fg(color))])
                             })
                     );
                     let numbers_paragraph = Paragraph::new(number_lines)
@@ -490,14 +1424,49 @@ pub fn run_editor(
 
                 f.render_widget(ClearWidget, text_chunk);
                 f.render_widget(paragraph, text_chunk);
+                // Scrollbar over the right border, once the buffer outgrows the view
+                let max_scroll = num_lines.saturating_sub(editor.editor_visible_height);
+                if max_scroll > 0 && matches!(editor.diff_mode, DiffMode::Inactive) {
+                    let track = Rect {
+                        y: text_chunk.y + 1,
+                        height: text_chunk.height.saturating_sub(2),
+                        ..text_chunk
+                    };
+                    // The thumb reaches the bottom when the last line is in view;
+                    // huge buffers are scaled down to the widget's u16 range
+                    let position = editor.scroll_y.min(max_scroll) * num_lines / max_scroll;
+                    let scale = num_lines / u16::MAX as usize + 1;
+                    let mut state = ScrollbarState::default()
+                        .content_length((num_lines / scale) as u16)
+                        .viewport_content_length((editor.editor_visible_height / scale).max(1) as u16)
+                        .position((position / scale) as u16);
+                    let scrollbar = Scrollbar::new(ScrollbarOrientation::VerticalRight)
+                        .begin_symbol(None)
+                        .end_symbol(None)
+                        .track_symbol(Some("│"))
+                        .thumb_symbol("█");
+                    f.render_stateful_widget(scrollbar, track, &mut state);
+                }
+                if let Some(pane_chunk) = pane_chunk {
+                    draw_split_pane(f, &mut editor, &syntax_engine, pane_chunk);
+                }
 
                 // Set cursor position based on focus
                 match editor.focus {
                     Focus::Editor => {
-                        f.set_cursor(
-                            text_chunk.x + 1 + (editor.cursor_x - editor.scroll_x) as u16,
-                            text_chunk.y + 1 + (editor.cursor_y - editor.scroll_y) as u16,
-                        );
+                        // No cursor while the wheel has scrolled it out of view
+                        let (row, column) = editor.cursor_segment();
+                        if let Some(cursor_row) = screen_rows.iter().position(|&r| r == (editor.cursor_y, row)) {
+                            let column = if editor.soft_wrap {
+                                column.min(editor.editor_visible_width.saturating_sub(1))
+                            } else {
+                                column - editor.scroll_x
+                            };
+                            f.set_cursor(
+                                text_chunk.x + 1 + column as u16,
+                                text_chunk.y + 1 + cursor_row as u16,
+                            );
+                        }
                     }
                      Focus::CommandLine => {
                          if let Some((msg, _, _)) = &editor.prompt {
@@ -507,14 +1476,18 @@ pub fn run_editor(
                              );
 } else {
                               f.set_cursor(
-                                  chunks[1].x + 2 + editor.command_cursor as u16,
+                                  chunks[1].x + command_line_marker(&editor).width() as u16 + 1 + editor.command_cursor as u16,
                                   chunks[1].y,
                               );
                           }
                      }
                 }
+                if color_mode != ColorMode::TrueColor {
+                    f.render_widget(Downsample(color_mode), f.size());
+                }
             })
             .unwrap();
+        resized = false;
 
         stdout().flush().unwrap();
 
@@ -527,14 +1500,48 @@ pub fn run_editor(
                 editor.ai_status = AiStatus::Idle;
             }
         }
+        // Dismiss informational messages once they have been shown long enough
+        if let Some((_, PromptType::Message(shown), _)) = &editor.prompt {
+            let timeout = config.message_timeout_ms.unwrap_or(2000);
+            if timeout > 0 && shown.elapsed().as_millis() >= timeout as u128 {
+                editor.prompt = None;
+                editor.command_buffer.clear();
+                editor.command_cursor = 0;
+            }
+        }
+
+        // Keep the recovery files of unsaved documents current
+        if swap_interval > 0 && last_swap.elapsed().as_millis() >= swap_interval as u128 {
+            commands::write_swap_files(&editor);
+            last_swap = Instant::now();
+        }
 
         // Check for AI response
-        if let Some(receiver) = &editor.ai_response_receiver {
-            if let Ok(result) = receiver.try_recv() {
+        if let Some(receiver) = editor.ai_response_receiver.take() {
+            let mut finished = None;
+            loop {
+                match receiver.try_recv() {
+                    Ok(AiEvent::Chunk(chunk)) => {
+                        if let AiStatus::InProgress { ref mut received, .. } = editor.ai_status {
+                            received.push_str(&chunk);
+                        }
+                    }
+                    Ok(AiEvent::Done(result)) => {
+                        finished = Some(result);
+                        break;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        finished = Some(Err("AI request ended unexpectedly".to_string()));
+                        break;
+                    }
+                }
+            }
+            if let Some(result) = finished {
                 match result {
                     Ok(response) => {
-                        let modified_buffer: Vec<String> = response.lines().map(|s| s.to_string()).collect();
-                        editor.start_diff_mode(modified_buffer);
+                        editor.start_ai_diff(&response);
+                        editor.deselect();
                         editor.read_only = true;
                         editor.focus = Focus::CommandLine;
                         editor.ai_status = AiStatus::Success {
@@ -549,374 +1556,51 @@ pub fn run_editor(
                         };
                     }
                 }
-                editor.ai_response_receiver = None;
+            } else {
+                editor.ai_response_receiver = Some(receiver);
             }
         }
 
         // Update state based on events
         if event::poll(std::time::Duration::from_millis(200)).unwrap() {
-            if let Event::Key(key) = event::read().unwrap() {
+            let event = event::read().unwrap();
+            if let Event::Resize(..) = event {
+                // Redraw straight away; draw() picks up the new size
+                resized = true;
+            }
+            if let Event::Mouse(mouse) = event {
+                match mouse.kind {
+                    MouseEventKind::ScrollUp => editor.scroll_view(-3),
+                    MouseEventKind::ScrollDown => editor.scroll_view(3),
+                    _ => {}
+                }
+            }
+            if let Event::Key(key) = event {
                 if key.kind == KeyEventKind::Press {
-                    // Handle diff mode keybindings
-                    if let DiffMode::Active { .. } = &editor.diff_mode {
-                        match key.code {
-                            KeyCode::Char('a') => { editor.accept_current_hunk(); editor.next_hunk(); }
-                            KeyCode::Char('A') => { editor.accept_all_hunks(); }
-                            KeyCode::Char('r') => { editor.reject_current_hunk(); editor.next_hunk(); }
-                            KeyCode::Char('R') => { editor.reject_all_hunks(); }
-                            KeyCode::Char('n') => {
-                                if !editor.next_hunk() {
-                                    editor.prompt = Some(("No more hunks. Press 'q' to apply changes or 'q' again to cancel.".to_string(), PromptType::Message, None));
-                                }
-                            }
-                            KeyCode::Char('N') => {
-                                if !editor.next_hunk() {
-                                    editor.prompt = Some(("No more hunks. Press 'q' to apply changes or 'q' again to cancel.".to_string(), PromptType::Message, None));
-                                }
-                            }
-                            KeyCode::Char('p') => { editor.prev_hunk(); }
-                            KeyCode::Char('P') => { editor.prev_hunk(); }
-                            KeyCode::Char('q') => {
-                                if editor.apply_diff_changes() {
-                                    editor.prompt = Some(("Changes applied successfully.".to_string(), PromptType::Message, None));
-                                } else {
-                                    editor.cancel_diff_mode();
-                                    editor.prompt = Some(("Changes cancelled.".to_string(), PromptType::Message, None));
-                                }
-                            }
-                            _ => {} // Ignore other keys in diff mode
-                        }
-                        continue; // Skip focus-based handling when in diff mode
-                    } else if let Some((_, prompt_type, action)) = &editor.prompt.clone() {
-
-                        match prompt_type {
-                            PromptType::Confirm => {
-                                match key.code {
-                                    KeyCode::Char('y') => {
-                                        match action {
-                                            Some(PromptAction::Save) => {
-                                                let _ = save_file(&mut editor, &filename);
-                                            }
-                                            Some(PromptAction::Quit) => {
-                                                break;
-                                            }
-                                            Some(PromptAction::AcceptAi) => {
-                                                // Changes already applied, enable editing
-                                                editor.read_only = false;
-                                                editor.focus = Focus::Editor;
-                                            }
-                                            None => {}
-                                        }
-                                    }
-                                    KeyCode::Char('n') => {
-                                        if let Some(PromptAction::AcceptAi) = action {
-                                            // Restore original state
-                                            if let Some(buf) = editor.original_buffer.take() {
-                                                editor.buffer = buf;
-                                            }
-                                            editor.filename = editor.original_filename.take();
-                                            editor.cursor_y = editor.original_cursor_y;
-                                            editor.cursor_x = editor.original_cursor_x;
-                                            editor.scroll_y = editor.original_scroll_y;
-                                            editor.scroll_x = editor.original_scroll_x;
-                                            editor.modified = editor.original_modified;
-                                            editor.read_only = false;
-                                            editor.focus = Focus::Editor;
-                                            editor.prompt = Some(("AI changes rejected.".to_string(), PromptType::Message, None));
-                                        } else {
-                                            editor.prompt = None;
-                                            editor.command_buffer.clear();
-                                            editor.command_cursor = 0;
-                                        }
-                                    }
-                                    _ => {}
-                                }
-                            }
-                              PromptType::Message => {
-                                editor.prompt = None;
-                                editor.command_buffer.clear();
-                                            editor.command_cursor = 0;
+                    match key.code {
+                        KeyCode::F(3) if editor.prompt.is_none() => {
+                            let outcome = commands::toggle_recording(&mut editor);
+                            apply_outcome(&mut editor, outcome);
+                        }
+                        KeyCode::F(4) if editor.prompt.is_none() => {
+                            let outcome = commands::replay(&mut editor, 1);
+                            apply_outcome(&mut editor, outcome);
+                        }
+                        _ => {
+                            if let Some(keys) = &mut editor.recording {
+                                keys.push(key);
                             }
-                              PromptType::Fill => {
-                                match key.code {
-                                    KeyCode::Char(c) => {
-                                        editor.fill_selection(c);
-                                        editor.prompt = None;
-                                        editor.command_buffer.clear();
-                                            editor.command_cursor = 0;
-                                    }
-                                    _ => {} // Ignore other keys in fill mode
-                                }
+                            if !handle_key(&mut editor, &mut config, &mut syntax_engine, key) {
+                                break;
                             }
                         }
-                    } else {
-                        match editor.focus {
-                            Focus::Editor => {
-                                if key.modifiers.contains(KeyModifiers::CONTROL) {
-                                    match key.code {
-                                        KeyCode::Up => editor.move_cursor(0, -1),
-                                        KeyCode::Down => editor.move_cursor(0, 1),
-                                        KeyCode::Left => editor.move_cursor(-1, 0),
-                                        KeyCode::Right => editor.move_cursor(1, 0),
-                                        KeyCode::Char('l') => editor.select_line(),
-                                        KeyCode::Char('b') => editor.select_block(),
-                                        KeyCode::Char('f') => {
-                                            if editor.selection_start.is_some() {
-                                                editor.prompt = Some(("Enter character to fill selection:".to_string(), PromptType::Fill, None));
-                                            }
-                                        }
-                                        KeyCode::Char('u') => {
-                                            editor.selection_start = None;
-                                            editor.selection_end = None;
-                                        }
-                                        KeyCode::Char(c) => editor.type_char(c),
-                                        KeyCode::Tab => {
-                                            let spaces = config.tab_width - (editor.cursor_x % config.tab_width);
-                                            for _ in 0..spaces {
-                                                editor.type_char(' ');
-                                            }
-                                        }
-                                        KeyCode::Enter => editor.insert_newline(),
-                                        KeyCode::Delete => editor.delete_char(),
-                                        KeyCode::Insert => editor.toggle_overwrite(),
-                                        KeyCode::Backspace => editor.backspace(),
-                                        _ => {} // Ignore other keys in editor mode
-                                    }
-                                } else if key.modifiers.contains(KeyModifiers::SHIFT) {
-                                    match key.code {
-                                        KeyCode::F(7) => {
-                                            if editor.selection_start.is_some() {
-                                                editor.move_block_left();
-                                            }
-                                        }
-                                        KeyCode::F(8) => {
-                                            if editor.selection_start.is_some() {
-                                                editor.move_block_right();
-                                            }
-                                        }
-                                        KeyCode::Char(c) => editor.type_char(c),
-                                        _ => {}
-                                    }
-                                } else {
-                                    match key.code {
-                                        KeyCode::Up => editor.move_cursor(0, -1),
-                                        KeyCode::Down => editor.move_cursor(0, 1),
-                                        KeyCode::Left => editor.move_cursor(-1, 0),
-                                        KeyCode::Right => editor.move_cursor(1, 0),
-                                        KeyCode::Char(c) => editor.type_char(c),
-                                        KeyCode::Tab => {
-                                            let spaces = config.tab_width - (editor.cursor_x % config.tab_width);
-                                            for _ in 0..spaces {
-                                                editor.type_char(' ');
-                                            }
-                                        }
-                                        KeyCode::Enter => editor.insert_newline(),
-                                        KeyCode::Delete => editor.delete_char(),
-                                        KeyCode::Insert => editor.toggle_overwrite(),
-                                        KeyCode::Backspace => editor.backspace(),
-                                        KeyCode::Home => editor.focus = Focus::CommandLine,
-                                        KeyCode::PageUp => editor.page_up(),
-                                        KeyCode::PageDown => editor.page_down(),
-                                        KeyCode::F(1) => {
-                                            if editor.find_next() {
-                                                editor.prompt = Some(("Moved to next match.".to_string(), PromptType::Message, None));
-                                            } else {
-                                                editor.prompt = Some(("No more matches or no search active.".to_string(), PromptType::Message, None));
-                                            }
-                                        }
-                                        _ => {} // Ignore other keys in editor mode
-                                    }
-                                }
-                            }
-                            Focus::CommandLine => {
-                                match key.code {
-                                    KeyCode::Char(c) => {
-                                        editor.command_insert_char(c);
-                                    }
-                                    KeyCode::Backspace => {
-                                        editor.command_backspace();
-                                    }
-                                    KeyCode::Left => {
-                                        editor.command_move_left();
-                                    }
-                                    KeyCode::Right => {
-                                        editor.command_move_right();
-                                    }
-                                    KeyCode::Delete => {
-                                        editor.command_delete();
-                                    }
-                                    KeyCode::Insert => {
-                                        editor.toggle_overwrite();
-                                    }
-                                    KeyCode::Up => {
-                                        editor.history_up();
-                                    }
-                                    KeyCode::Down => {
-                                        editor.history_down();
-                                    }
-                                     KeyCode::F(1) => {
-                                         if editor.find_next() {
-                                             editor.prompt = Some(("Moved to next match.".to_string(), PromptType::Message, None));
-                                         } else {
-                                             editor.prompt = Some(("No more matches or no search/replace active.".to_string(), PromptType::Message, None));
-                                         }
-                                     }
-                                     KeyCode::Home => editor.focus = Focus::Editor,
-                                     KeyCode::Enter => {
-                                         let cmd = editor.command_buffer.trim().to_string();
-                                         if !cmd.is_empty() {
-                                             editor.add_to_history(cmd.clone());
-                                              if cmd == "q" || cmd == "quit" {
-                                                  if editor.read_only {
-                                                      // Restore original document
-                                                      if let Some(buf) = editor.original_buffer.take() {
-                                                          editor.buffer = buf;
-                                                      }
-                                                      editor.filename = editor.original_filename.take();
-                                                      editor.cursor_y = editor.original_cursor_y;
-                                                      editor.cursor_x = editor.original_cursor_x;
-                                                      editor.scroll_y = editor.original_scroll_y;
-                                                      editor.scroll_x = editor.original_scroll_x;
-                                                      editor.modified = editor.original_modified;
-                                                      editor.read_only = false;
-                                                      editor.focus = Focus::Editor;
-                                                      editor.prompt = Some(("Returned to document.".to_string(), PromptType::Message, None));
-                                                  } else if !editor.modified {
-                                                      editor.quit = true;
-                                                  } else {
-                                                       editor.prompt = Some(("Changes have been made. Abort? (y/n)".to_string(), PromptType::Confirm, Some(PromptAction::Quit)));
-                                                  }
-                                               }
-                                              else if cmd == "s" || cmd == "save" {
-                                                 match save_file(&mut editor, &filename) {
-                                                     Ok(()) => {
-                                                         editor.prompt = Some(("File saved.".to_string(), PromptType::Message, None));
-                                                     }
-                                                     Err(e) => {
-                                                         editor.prompt = Some((format!("Save failed: {}", e), PromptType::Message, None));
-                                                     }
-                                                 }
-} else if cmd == "undo" {
-                                                    if editor.undo() {
-                                                        editor.prompt = Some(("Undid last change.".to_string(), PromptType::Message, None));
-                                                    } else {
-                                                        editor.prompt = Some(("Nothing to undo.".to_string(), PromptType::Message, None));
-                                                    }
-                                                } else if cmd == "redo" {
-                                                    if editor.redo() {
-                                                        editor.prompt = Some(("Redid last change.".to_string(), PromptType::Message, None));
-                                                    } else {
-                                                        editor.prompt = Some(("Nothing to redo.".to_string(), PromptType::Message, None));
-                                                    }
-                                                } else if cmd == "lnum" {
-                                                  editor.show_line_numbers = !editor.show_line_numbers;
-                                                  editor.prompt = Some(("Line numbers toggled.".to_string(), PromptType::Message, None));
-                                                } else if cmd.starts_with("goto ") {
-                                                 let arg = &cmd[5..];
-                                                 if let Ok(line_num) = arg.trim().parse::<usize>() {
-                                                     if line_num >= 1 && line_num <= editor.buffer.len() {
-                                                         editor.cursor_y = line_num - 1;
-                                                         editor.cursor_x = 0;
-                                                         // Adjust scroll_y to make the line visible
-                                                         if editor.cursor_y < editor.scroll_y {
-                                                             editor.scroll_y = editor.cursor_y;
-                                                         } else if editor.cursor_y >= editor.scroll_y + editor.editor_visible_height {
-                                                             editor.scroll_y = editor.cursor_y - editor.editor_visible_height + 1;
-                                                         }
-                                                         editor.focus = Focus::Editor;
-                                                         editor.prompt = Some((format!("Jumped to line {}", line_num), PromptType::Message, None));
-                                                     } else {
-                                                         editor.prompt = Some(("Line number out of range.".to_string(), PromptType::Message, None));
-                                                     }
-                                                  } else {
-                                                      editor.prompt = Some(("Invalid line number.".to_string(), PromptType::Message, None));
-                                                  }
-                                              } else if let Some((search_text, case_sensitive)) = Editor::parse_find_command(&cmd) {
-                                                  if editor.find(&search_text, SearchScope::All, case_sensitive) {
-                                                      editor.focus = Focus::Editor;
-                                                      let case_text = if case_sensitive { "case-sensitive" } else { "case-insensitive" };
-                                                      editor.prompt = Some((format!("Found {} matches for '{}' ({})", 
-                                                          editor.search_matches.len(), search_text, case_text), 
-                                                          PromptType::Message, None));
-                                                  } else {
-                                                      editor.prompt = Some(("No matches found.".to_string(), PromptType::Message, None));
-                                                  }
-                                              } else if cmd == "help" {
-                                                  // Save current state
-                                                  editor.original_buffer = Some(editor.buffer.clone());
-                                                  editor.original_filename = editor.filename.clone();
-                                                  editor.original_cursor_y = editor.cursor_y;
-                                                  editor.original_cursor_x = editor.cursor_x;
-                                                  editor.original_scroll_y = editor.scroll_y;
-                                                  editor.original_scroll_x = editor.scroll_x;
-                                                  editor.original_modified = editor.modified;
-
-                                                  // Load help text
-                                                  match std::fs::read_to_string("help/help.txt") {
-                                                      Ok(content) => {
-                                                          editor.buffer = content.lines().map(|s| s.to_string()).collect();
-                                                          if editor.buffer.is_empty() {
-                                                              editor.buffer.push(String::new());
-                                                          }
-                                                          editor.cursor_y = 0;
-                                                          editor.cursor_x = 0;
-                                                          editor.scroll_y = 0;
-                                                          editor.scroll_x = 0;
-                                                          editor.modified = false;
-                                                          editor.read_only = true;
-                                                          editor.focus = Focus::Editor;
-                                                          editor.prompt = Some(("Help mode - use 'q' to return to document".to_string(), PromptType::Message, None));
-                                                      }
-                                                      Err(_) => {
-                                                          editor.prompt = Some(("Help file not found.".to_string(), PromptType::Message, None));
-                                                      }
-                                                   }
-} else if cmd.starts_with("prompt ") {
-    let prompt_arg = cmd[7..].trim();
-    if !prompt_arg.is_empty() {
-        let text = editor.buffer.join("\n");
-        let (tx, rx) = mpsc::channel();
-        editor.ai_response_receiver = Some(rx);
-        editor.ai_status = AiStatus::InProgress {
-            start_time: Instant::now(),
-            spinner_state: 0,
-        };
-
-        let thread_config = config.clone();
-        let thread_text = text.clone();
-        let prompt_arg = prompt_arg.to_string();
-
-        thread::spawn(move || {
-            let result = if prompt_arg.starts_with('"') && prompt_arg.ends_with('"') {
-                let user_prompt = &prompt_arg[1..prompt_arg.len() - 1];
-                ai::send_prompt_with_system(&thread_config, None, user_prompt, &thread_text)
-            } else {
-                match load_prompt_file(&prompt_arg) {
-                    Ok((system_prompt, user_prompt)) => {
-                        let final_user_prompt = user_prompt.replace("{{TEXT}}", &thread_text);
-                        ai::send_prompt_with_system(&thread_config, Some(&system_prompt), &final_user_prompt, "")
                     }
-                    Err(e) => Err(e.into()),
+                    if let Some(count) = editor.macro_replay.take() {
+                        if !replay_macro(&mut editor, &mut config, &mut syntax_engine, count) {
+                            break;
+                        }
+                    }
                 }
-            };
-            let _ = tx.send(result.map_err(|e| e.to_string()));
-        });
-    } else {
-        editor.prompt = Some(("Prompt command requires text or filename.".to_string(), PromptType::Message, None));
-    }
-} else {
-                                                   editor.prompt = Some((format!("Unknown command: {}", cmd), PromptType::Message, None));
-                                               }
-                                         }
-                                         editor.command_buffer.clear();
-                                            editor.command_cursor = 0;
-                                     }
-                                     _ => {} // Ignore other keys in command line mode
-                                }
-                 }
-}
-          }
-      }
 
          if editor.quit {
                break;
@@ -925,9 +1609,11 @@ pub fn run_editor(
      }
     }
 
+    commands::remove_swap_files(&editor);
     disable_raw_mode().unwrap();
     execute!(
         terminal.backend_mut(),
+        DisableMouseCapture,
         crossterm::cursor::Show,
         crossterm::terminal::Clear(ClearType::All)
     ).unwrap();